@@ -0,0 +1,141 @@
+// Built-in load test (`--bench N`)
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use ed25519_dalek::{Signer, SigningKey};
+
+use crate::config::Config;
+use crate::ledger::{Account, AccountStore, Ledger};
+use crate::server::{AccountLocks, Clock, SystemClock};
+use crate::validation::{handle_transaction, handle_transaction_staged, signing_bytes, Transaction};
+
+// How bench transfers share the ledger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Locking {
+    Ledger, // each transfer holds the ledger lock throughout
+    Accounts, // each transfer locks its own accounts and applies through handle_transaction_staged, like the server
+}
+
+// What a bench run measured
+#[derive(Debug, Clone)]
+pub struct BenchReport {
+    pub locking: Locking,
+    pub total: usize, // transfers attempted
+    pub ok: usize, // transfers handle_transaction accepted
+    pub senders: usize, // concurrent tasks, one per sender
+    pub elapsed: Duration,
+    pub throughput_per_sec: f64, // attempted transfers per second of wall time
+    pub p50: Duration, // latency percentiles of a single transfer, lock waits included
+    pub p90: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+// Built-in load test (`--bench N`): pushes N signed synthetic transfers from concurrent tasks, one per
+// sender, each paying a few receivers of its own, then reports throughput and latency percentiles.
+// Transactions are signed before the clock starts, so verifying them is measured but signing isn't.
+pub async fn run_bench(total: usize, config: Config, locking: Locking) -> BenchReport {
+    const SENDERS: usize = 64;
+
+    let senders = SENDERS.min(total.max(1));
+    let keys: Vec<SigningKey> = (0..senders).map(|i| SigningKey::from_bytes(&[i as u8 + 1; 32])).collect();
+    let mut accts = AccountStore::new();
+    for (i, key) in keys.iter().enumerate() {
+        let public_key = Some(hex::encode(key.verifying_key().to_bytes()));
+        accts.insert(format!("bench-sender-{}", i), Account { balance: u64::MAX / 2, public_key, ..Default::default() });
+    }
+    let ledger = Arc::new(Mutex::new(Ledger { accounts: accts, ..Default::default() }));
+    let locks = Arc::new(AccountLocks::default());
+    let config = Arc::new(config);
+
+    // Spread the transfers evenly, each sender walking its own nonces
+    let batches: Vec<Vec<Transaction>> = keys.iter().enumerate().map(|(i, key)| {
+        let count = total / senders + usize::from(i < total % senders);
+        (0..count).map(|nonce| {
+            let mut tx = Transaction {
+                sender: format!("bench-sender-{}", i),
+                receiver: format!("bench-receiver-{}-{}", i, nonce % 4),
+                amount: 1,
+                nonce: nonce as u32,
+                ..Default::default()
+            };
+            tx.signature = Some(hex::encode(key.sign(&signing_bytes(&tx)).to_bytes()));
+            tx
+        }).collect()
+    }).collect();
+
+    let started = Instant::now();
+    let tasks: Vec<_> = batches.into_iter().map(|txs| {
+        let ledger = ledger.clone();
+        let locks = locks.clone();
+        let config = config.clone();
+        tokio::spawn(async move {
+            let mut latencies = Vec::with_capacity(txs.len());
+            let mut ok = 0usize;
+            for tx in txs {
+                let tx_started = Instant::now();
+                let now = SystemClock.now_ms();
+                let result = match locking {
+                    Locking::Ledger => handle_transaction(&tx, &mut ledger.lock().unwrap(), &config, now),
+                    Locking::Accounts => {
+                        let _accounts = locks.lock([tx.sender.as_str(), tx.receiver.as_str()]);
+                        let locked = ledger.lock().unwrap();
+                        handle_transaction_staged(&tx, locked, || ledger.lock().unwrap(), &config, now).1
+                    }
+                };
+                latencies.push(tx_started.elapsed());
+                ok += usize::from(result.is_ok());
+                tokio::task::yield_now().await;
+            }
+            (latencies, ok)
+        })
+    }).collect();
+
+    let mut latencies = Vec::with_capacity(total);
+    let mut ok = 0;
+    for task in tasks {
+        let (task_latencies, task_ok) = task.await.unwrap();
+        latencies.extend(task_latencies);
+        ok += task_ok;
+    }
+    let elapsed = started.elapsed();
+    latencies.sort();
+
+    let percentile = |p: f64| {
+        latencies.get(((latencies.len() as f64 * p) as usize).min(latencies.len().saturating_sub(1)))
+            .copied()
+            .unwrap_or_default()
+    };
+    BenchReport {
+        locking,
+        total,
+        ok,
+        senders,
+        elapsed,
+        throughput_per_sec: total as f64 / elapsed.as_secs_f64(),
+        p50: percentile(0.50),
+        p90: percentile(0.90),
+        p99: percentile(0.99),
+        max: latencies.last().copied().unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigVars;
+
+    #[tokio::test]
+    async fn a_small_bench_reports_plausible_numbers() {
+        for locking in [Locking::Ledger, Locking::Accounts] {
+            let config = Config::from_vars(&ConfigVars::default()).unwrap();
+            let report = run_bench(128, config, locking).await;
+
+            assert_eq!((report.total, report.ok, report.senders), (128, 128, 64), "{:?}", report);
+            assert!(report.throughput_per_sec > 0.0 && report.throughput_per_sec.is_finite(), "{:?}", report);
+            assert!(report.p50 <= report.p90 && report.p90 <= report.p99 && report.p99 <= report.max, "{:?}", report);
+            assert!(report.max > Duration::ZERO && report.max <= report.elapsed, "{:?}", report);
+        }
+    }
+}
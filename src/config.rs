@@ -0,0 +1,636 @@
+// Settings, read once at startup from TXH_* variables and the optional config file
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+use crate::ledger::{check_genesis, AccountStore, GenesisAccount};
+use crate::validation::parse_public_key;
+
+// A replica serves reads only; writes belong to the primary
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Primary,
+    Replica,
+}
+
+impl std::str::FromStr for Role {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "primary" => Ok(Role::Primary),
+            "replica" => Ok(Role::Replica),
+            other => Err(format!("unknown role {}", other)),
+        }
+    }
+}
+
+// How timestamps in responses are written, see timestamp_format
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TimestampFormat {
+    #[default]
+    UnixMs,
+    Rfc3339,
+}
+
+impl std::str::FromStr for TimestampFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unix_ms" => Ok(TimestampFormat::UnixMs),
+            "rfc3339" => Ok(TimestampFormat::Rfc3339),
+            other => Err(format!("unknown timestamp format {}", other)),
+        }
+    }
+}
+
+// Tunables read from TXH_* environment variables at startup, and again on /admin/reload_config.
+// Every setting defaults to the original prototype behavior.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Config {
+    pub dedup_window_ms: u64, // identical transactions within this many ms are applied once (0 = off)
+    pub require_known_receiver: bool, // only auto-create receivers listed in known_receivers
+    pub known_receivers: Vec<String>, // ids that may be auto-created when require_known_receiver is on
+    pub allow_self_transfer: bool, // accept sender == receiver as a nonce bump that moves no funds
+    pub bump_receiver_nonce: bool, // receiving funds also increments the receiver's nonce
+    pub admin_token: Option<Secret>, // bearer token for admin endpoints; admin endpoints are disabled when unset
+    pub chain_id: Option<String>, // every transaction must carry this chain id, which its signature covers
+    pub sequencer_key: Option<String>, // hex ed25519 key of the trusted sequencer; set, every transaction needs its signed sequence
+    pub require_signatures: bool, // reject transactions from accounts without a registered public key
+    pub monotonic_timestamps: bool, // require a timestamp that never decreases per sender
+    pub max_future_skew_ms: u64, // reject timestamps more than this far ahead of the server clock (0 = off)
+    pub max_timestamp_age_ms: u64, // reject timestamps more than this far behind the server clock (0 = off)
+    pub amount_step: u64, // transfer amounts must be multiples of this (0 or 1 = any amount)
+    pub max_amount_supply_fraction: f64, // reject amounts above this fraction of the total supply (0 = off)
+    pub max_amount: u64, // reject amounts above this (0 = off)
+    pub account_creation_fee: u64, // extra charge to the sender when a transfer auto-creates the receiver
+    pub fee_flat: u64, // charged to the sender on every transfer, on top of the amount
+    pub fee_bps: u64, // plus this many basis points of the amount, rounded up
+    pub fee_min: u64, // floor for the basis-point part (only with fee_bps)
+    pub fee_collector: String, // account credited with collected fees
+    pub fee_burn_bps: u64, // share of every fee destroyed instead of credited to the collector, in basis points
+    pub require_fee_collector: bool, // with fees on, the fee collector must already exist instead of being auto-created
+    pub hex_nonces: bool, // nonces travel as "0x.." hex strings instead of JSON numbers
+    pub nonce_hints: bool, // nonce rejections carry the sender's current and expected nonce
+    pub nonce_queue_gap: u32, // hold transactions up to this many nonces ahead of the sender's (0 = reject them)
+    pub nonce_queue_ttl_secs: u64, // queued transactions are dropped after this long
+    pub string_amounts: bool, // transaction amounts and account balances travel as decimal strings
+    pub timestamp_format: TimestampFormat, // unix ms or RFC 3339 strings in history, statements and locks
+    pub flexible_amounts: bool, // amounts may arrive as numbers or decimal strings, whatever the output format
+    pub api_version: u8, // response envelope version when the request has no X-API-Version header
+    pub lock_ttl_secs: u64, // default lifetime of an account lock
+    pub max_lock_ttl_secs: u64, // longer requested lock lifetimes are cut down to this
+    pub max_outputs: usize, // most recipients a single distribution may pay
+    pub duplicate_receivers: DuplicateReceivers, // merge or reject a distribution that lists a recipient twice
+    pub batch_chunk_size: usize, // transactions a read-committed batch applies per hold of the ledger lock
+    pub velocity_limit: u64, // most a sender may send within the velocity window (0 = off)
+    pub velocity_window_secs: u64, // length of the sliding velocity window
+    pub min_account_age_secs: u64, // accounts can't send until they are this old (0 = off)
+    pub max_history: usize, // total history entries kept across all accounts, oldest evicted first (0 = unbounded)
+    pub inactive_ttl_secs: u64, // prune zero-balance accounts idle for longer than this (0 = keep forever)
+    pub janitor_interval_secs: u64, // how often the pruning task runs
+    pub max_inflight: usize, // cap on concurrent transaction requests (0 = unlimited)
+    pub max_event_subscribers: usize, // cap on open /events streams; more are answered with 503 (0 = unlimited)
+    pub shed_overload: bool, // beyond max_inflight, answer 503 right away instead of queueing
+    pub role: Role, // replicas reject every mutating endpoint with 403
+    pub primary_url: Option<String>, // http:// base URL a replica copies the ledger from
+    pub primary_token: Option<Secret>, // admin token of the primary, for its /admin/snapshot
+    pub sync_interval_secs: u64, // how often a replica pulls the primary's snapshot
+    pub log_bodies: bool, // log request/response bodies (sensitive fields redacted) at debug level; dev only
+    pub trace_sample_rate: f64, // fraction of requests run inside a tracing span (0.0 - 1.0)
+    pub signed_receipts: bool, // attach a server-signed receipt to successful transaction responses
+    pub server_key: Option<Secret>, // hex ed25519 seed for signing receipts; a fresh key is generated when unset
+    pub state_file: Option<String>, // JSON snapshot of the ledger, with a write-ahead log of later changes next to it
+    pub genesis_file: Option<String>, // JSON list of accounts to seed a fresh ledger with, instead of Alice and Bob
+    #[serde(skip)]
+    pub genesis: Option<Vec<GenesisAccount>>, // the same list given inline in the config file
+    pub tenants: Vec<String>, // ids of extra ledgers served next to the default one, picked per request
+    pub listen: String, // address the server binds to
+    pub snapshot_every: usize, // log records after which the next change rewrites the snapshot and empties the log
+    pub storage_retries: u32, // further attempts at a commit that failed with a transient error
+    pub storage_retry_backoff_ms: u64, // wait before the first retry, doubling for each one after it
+    pub maintenance_start_ms: u64, // unix ms a scheduled maintenance window opens
+    pub maintenance_end_ms: u64, // unix ms it closes again (0 = no window)
+    #[cfg(feature = "chaos")]
+    pub chaos_delay_ms: u64, // artificial delay added to every transaction request
+    #[cfg(feature = "chaos")]
+    pub chaos_error_rate: f64, // fraction of transaction requests answered with a 500 (0.0 - 1.0)
+    #[cfg(feature = "chaos")]
+    pub chaos_seed: u64, // seeds the choice of failed requests so a run can be repeated (0 = random)
+}
+
+impl Config {
+    // Reads the TXH_* settings from the process environment, falling back to TXH_CONFIG_FILE for unset ones
+    pub fn load() -> Result<Config, String> {
+        Config::from_vars(&ConfigVars::load()?)
+    }
+
+    // Every setting not in vars takes its default
+    pub fn from_vars(vars: &ConfigVars) -> Result<Config, String> {
+        let config = Config {
+            dedup_window_ms: vars.get_or("TXH_DEDUP_WINDOW_MS", 0)?,
+            require_known_receiver: vars.get_or("TXH_REQUIRE_KNOWN_RECEIVER", false)?,
+            known_receivers: vars.list("TXH_KNOWN_RECEIVERS"),
+            allow_self_transfer: vars.get_or("TXH_ALLOW_SELF_TRANSFER", false)?,
+            bump_receiver_nonce: vars.get_or("TXH_BUMP_RECEIVER_NONCE", false)?,
+            admin_token: vars.secret("TXH_ADMIN_TOKEN"),
+            chain_id: vars.optional("TXH_CHAIN_ID"),
+            sequencer_key: vars.optional("TXH_SEQUENCER_KEY"),
+            require_signatures: vars.get_or("TXH_REQUIRE_SIGNATURES", false)?,
+            monotonic_timestamps: vars.get_or("TXH_MONOTONIC_TIMESTAMPS", false)?,
+            max_future_skew_ms: vars.get_or("TXH_MAX_FUTURE_SKEW", 0)?,
+            max_timestamp_age_ms: vars.get_or("TXH_MAX_TIMESTAMP_AGE", 0)?,
+            amount_step: vars.get_or("TXH_AMOUNT_STEP", 0)?,
+            max_amount_supply_fraction: vars.get_or("TXH_MAX_AMOUNT_SUPPLY_FRACTION", 0.0)?,
+            max_amount: vars.get_or("TXH_MAX_AMOUNT", 0)?,
+            account_creation_fee: vars.get_or("TXH_ACCOUNT_CREATION_FEE", 0)?,
+            fee_flat: vars.get_or("TXH_FEE_FLAT", 0)?,
+            fee_bps: vars.get_or("TXH_FEE_BPS", 0)?,
+            fee_min: vars.get_or("TXH_FEE_MIN", 0)?,
+            fee_collector: vars.get_or("TXH_FEE_COLLECTOR", "fees".to_string())?,
+            fee_burn_bps: vars.get_or("TXH_FEE_BURN_BPS", 0)?,
+            require_fee_collector: vars.get_or("TXH_REQUIRE_FEE_COLLECTOR", false)?,
+            hex_nonces: vars.get_or("TXH_HEX_NONCES", false)?,
+            nonce_hints: vars.get_or("TXH_NONCE_HINTS", true)?,
+            nonce_queue_gap: vars.get_or("TXH_NONCE_QUEUE_GAP", 0)?,
+            nonce_queue_ttl_secs: vars.get_or("TXH_NONCE_QUEUE_TTL_SECS", 60)?,
+            string_amounts: vars.get_or("TXH_STRING_AMOUNTS", false)?,
+            flexible_amounts: vars.get_or("TXH_FLEXIBLE_AMOUNTS", false)?,
+            timestamp_format: vars.get_or("TXH_TIMESTAMP_FORMAT", TimestampFormat::UnixMs)?,
+            api_version: vars.get_or("TXH_API_VERSION", 1u8)?.clamp(1, 2),
+            lock_ttl_secs: vars.get_or("TXH_LOCK_TTL_SECS", 30)?,
+            max_lock_ttl_secs: vars.get_or("TXH_MAX_LOCK_TTL_SECS", 3600)?,
+            max_outputs: vars.get_or("TXH_MAX_OUTPUTS", 256)?,
+            duplicate_receivers: vars.get_or("TXH_DUPLICATE_RECEIVERS", DuplicateReceivers::Merge)?,
+            batch_chunk_size: vars.get_or("TXH_BATCH_CHUNK_SIZE", 1)?,
+            velocity_limit: vars.get_or("TXH_VELOCITY_LIMIT", 0)?,
+            velocity_window_secs: vars.get_or("TXH_VELOCITY_WINDOW_SECS", 3600)?,
+            min_account_age_secs: vars.get_or("TXH_MIN_ACCOUNT_AGE", 0)?,
+            max_history: vars.get_or("TXH_MAX_HISTORY", 0)?,
+            inactive_ttl_secs: vars.get_or("TXH_INACTIVE_TTL", 0)?,
+            janitor_interval_secs: vars.get_or("TXH_JANITOR_INTERVAL_SECS", 60)?,
+            max_inflight: vars.get_or("TXH_MAX_INFLIGHT", 0)?,
+            max_event_subscribers: vars.get_or("TXH_MAX_EVENT_SUBSCRIBERS", 1000)?,
+            shed_overload: vars.get_or("TXH_SHED_OVERLOAD", false)?,
+            role: vars.get_or("TXH_ROLE", Role::Primary)?,
+            primary_url: vars.optional("TXH_PRIMARY_URL"),
+            primary_token: vars.secret("TXH_PRIMARY_TOKEN"),
+            sync_interval_secs: vars.get_or("TXH_SYNC_INTERVAL_SECS", 5)?,
+            log_bodies: vars.get_or("TXH_LOG_BODIES", false)?,
+            trace_sample_rate: vars.get_or("TXH_TRACE_SAMPLE_RATE", 1.0)?,
+            signed_receipts: vars.get_or("TXH_SIGNED_RECEIPTS", false)?,
+            server_key: vars.secret("TXH_SERVER_KEY"),
+            state_file: vars.optional("TXH_STATE_FILE"),
+            genesis_file: vars.optional("TXH_GENESIS_FILE"),
+            genesis: vars.genesis.clone(),
+            tenants: vars.list("TXH_TENANTS"),
+            listen: vars.get_or("TXH_LISTEN", "127.0.0.1:3000".to_string())?,
+            snapshot_every: vars.get_or("TXH_SNAPSHOT_EVERY", 1000)?,
+            storage_retries: vars.get_or("TXH_STORAGE_RETRIES", 3)?,
+            storage_retry_backoff_ms: vars.get_or("TXH_STORAGE_RETRY_BACKOFF_MS", 20)?,
+            maintenance_start_ms: vars.get_or("TXH_MAINTENANCE_START", 0)?,
+            maintenance_end_ms: vars.get_or("TXH_MAINTENANCE_END", 0)?,
+            #[cfg(feature = "chaos")]
+            chaos_delay_ms: vars.get_or("TXH_CHAOS_DELAY_MS", 0)?,
+            #[cfg(feature = "chaos")]
+            chaos_error_rate: vars.get_or("TXH_CHAOS_ERROR_RATE", 0.0)?,
+            #[cfg(feature = "chaos")]
+            chaos_seed: vars.get_or("TXH_CHAOS_SEED", 0)?,
+        };
+        if config.sequencer_key.as_deref().is_some_and(|key| parse_public_key(key).is_none()) {
+            return Err("TXH_SEQUENCER_KEY must be a hex encoded 32 byte ed25519 public key".to_string());
+        }
+        if config.listen.parse::<SocketAddr>().is_err() {
+            return Err(format!("TXH_LISTEN must be an ip:port address, got {}", config.listen));
+        }
+        if config.primary_url.as_deref().is_some_and(|url| !url.starts_with("http://")) {
+            return Err("TXH_PRIMARY_URL must be an http:// URL".to_string());
+        }
+        if config.fee_burn_bps > 10_000 {
+            return Err(format!("TXH_FEE_BURN_BPS must be at most 10000, got {}", config.fee_burn_bps));
+        }
+        if config.snapshot_every == 0 {
+            return Err("TXH_SNAPSHOT_EVERY must be at least 1".to_string());
+        }
+        if config.batch_chunk_size == 0 {
+            return Err("TXH_BATCH_CHUNK_SIZE must be at least 1".to_string());
+        }
+        if config.lock_ttl_secs == 0 {
+            return Err("TXH_LOCK_TTL_SECS must be at least 1".to_string());
+        }
+        if config.lock_ttl_secs > config.max_lock_ttl_secs {
+            return Err("TXH_LOCK_TTL_SECS must not exceed TXH_MAX_LOCK_TTL_SECS".to_string());
+        }
+        if let Some(id) = config.tenants.iter().find(|id| !id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')) {
+            return Err(format!("TXH_TENANTS ids may only use letters, digits, '-' and '_', got {}", id));
+        }
+        if let Some(id) = config.tenants.iter().enumerate().find(|(i, id)| config.tenants[..*i].contains(id)).map(|(_, id)| id) {
+            return Err(format!("TXH_TENANTS lists {} twice", id));
+        }
+        if config.genesis.is_some() && config.genesis_file.is_some() {
+            return Err("genesis accounts are given both in the config file and by TXH_GENESIS_FILE".to_string());
+        }
+        let unknown = vars.unknown_file_keys();
+        if !unknown.is_empty() {
+            return Err(format!("unknown settings in the config file: {}", unknown.join(", ")));
+        }
+        Ok(config)
+    }
+}
+
+impl Config {
+    // End of the scheduled maintenance window if now falls inside it
+    pub fn maintenance_until(&self, now: u64) -> Option<u64> {
+        (self.maintenance_start_ms <= now && now < self.maintenance_end_ms).then_some(self.maintenance_end_ms)
+    }
+
+    // Fee on moving amount between existing accounts, rounded up so the collector never loses dust
+    pub fn transfer_fee(&self, amount: u64) -> u64 {
+        let mut fee = self.fee_flat;
+        if self.fee_bps > 0 {
+            let share = (amount as u128 * self.fee_bps as u128).div_ceil(10_000);
+            fee = fee.saturating_add(u64::try_from(share).unwrap_or(u64::MAX).max(self.fee_min));
+        }
+        fee
+    }
+
+    // Part of fee that is burned rather than credited to the fee collector, rounded down so the collector keeps the dust
+    pub fn fee_burn(&self, fee: u64) -> u64 {
+        let burn = (fee as u128 * self.fee_burn_bps.min(10_000) as u128) / 10_000;
+        u64::try_from(burn).unwrap_or(fee)
+    }
+
+    // Where a tenant's ledger is saved: next to TXH_STATE_FILE, with the tenant id appended
+    pub fn state_file_for(&self, tenant: Option<&str>) -> Option<String> {
+        let path = self.state_file.as_deref()?;
+        Some(match tenant {
+            Some(tenant) => format!("{}.tenant-{}", path, tenant),
+            None => path.to_string(),
+        })
+    }
+
+    pub fn charges_fees(&self) -> bool {
+        self.account_creation_fee > 0 || self.fee_flat > 0 || self.fee_bps > 0
+    }
+
+    // In strict mode, refuses a config that would route fees to an account that doesn't exist,
+    // e.g. because of a typo in TXH_FEE_COLLECTOR
+    pub fn check_fee_collector(&self, accounts: &AccountStore) -> Result<(), String> {
+        if self.require_fee_collector && self.charges_fees() && !accounts.contains_key(&self.fee_collector) {
+            return Err(format!("fee collector {} does not exist (TXH_REQUIRE_FEE_COLLECTOR is on)", self.fee_collector));
+        }
+        Ok(())
+    }
+}
+
+// Raw TXH_* settings: the process environment, on top of the optional config file
+#[derive(Debug, Default)]
+pub struct ConfigVars {
+    values: HashMap<String, String>,
+    file_keys: Vec<String>, // settings that came from the config file, by TXH_* name
+    genesis: Option<Vec<GenesisAccount>>, // the config file's genesis list
+    env_overrides: Vec<String>, // config file settings replaced by a different value from the environment
+    read: RefCell<HashSet<String>>, // settings looked up so far, to catch misspelled ones in the file
+}
+
+// A config file: TXH_* settings keyed by their lowercase name without the prefix
+// (`fee_flat = 2` for TXH_FEE_FLAT), plus an optional list of genesis accounts
+#[derive(Debug, Deserialize)]
+struct ConfigFile {
+    #[serde(default)]
+    genesis: Option<Vec<GenesisAccount>>,
+    #[serde(flatten)]
+    settings: BTreeMap<String, FileValue>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum FileValue {
+    Bool(bool),
+    Unsigned(u64),
+    Signed(i64),
+    Float(f64),
+    Text(String),
+    List(Vec<String>), // for comma separated settings such as known_receivers
+}
+
+impl std::fmt::Display for FileValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FileValue::Bool(value) => value.fmt(f),
+            FileValue::Unsigned(value) => value.fmt(f),
+            FileValue::Signed(value) => value.fmt(f),
+            FileValue::Float(value) => value.fmt(f),
+            FileValue::Text(value) => value.fmt(f),
+            FileValue::List(values) => values.join(",").fmt(f),
+        }
+    }
+}
+
+impl ConfigVars {
+    // Settings as TXH_* name and value pairs, without a config file
+    pub fn new<K: Into<String>, V: Into<String>>(pairs: impl IntoIterator<Item = (K, V)>) -> ConfigVars {
+        ConfigVars {
+            values: pairs.into_iter().map(|(key, value)| (key.into(), value.into())).collect(),
+            ..Default::default()
+        }
+    }
+
+    // The config file is named by `--config PATH`, or else TXH_CONFIG_FILE. It is JSON if the name ends
+    // in .json and TOML otherwise.
+    pub fn load() -> Result<ConfigVars, String> {
+        let args: Vec<String> = std::env::args().collect();
+        let flag = args.iter().position(|arg| arg == "--config").map(|pos| args.get(pos + 1).cloned());
+        let path = match flag {
+            Some(None) => return Err("--config needs a file path".to_string()),
+            Some(Some(path)) => Some(path),
+            None => std::env::var("TXH_CONFIG_FILE").ok(),
+        };
+        let mut vars = match path {
+            Some(path) => ConfigVars::from_file(&path)?,
+            None => ConfigVars::default(),
+        };
+        vars.overlay_env(std::env::vars());
+        Ok(vars)
+    }
+
+    // Environment TXH_* variables win over the same setting in the config file
+    fn overlay_env(&mut self, env: impl IntoIterator<Item = (String, String)>) {
+        for (key, value) in env.into_iter().filter(|(key, _)| key.starts_with("TXH_")) {
+            if self.file_keys.contains(&key) && self.values.get(&key) != Some(&value) {
+                self.env_overrides.push(key.clone());
+            }
+            self.values.insert(key, value);
+        }
+        self.env_overrides.sort();
+    }
+
+    // Config file settings that have no effect because the environment sets them to something else
+    pub fn env_overrides(&self) -> &[String] {
+        &self.env_overrides
+    }
+
+    pub fn from_file(path: &str) -> Result<ConfigVars, String> {
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+        let file: ConfigFile = if path.ends_with(".json") {
+            serde_json::from_str(&contents).map_err(|e| format!("{} is not a valid config file: {}", path, e))?
+        } else {
+            toml::from_str(&contents).map_err(|e| format!("{} is not a valid config file: {}", path, e))?
+        };
+        if let Some(genesis) = &file.genesis {
+            check_genesis(genesis).map_err(|e| format!("{}: {}", path, e))?;
+        }
+        let mut vars = ConfigVars { genesis: file.genesis, ..Default::default() };
+        for (key, value) in file.settings {
+            let name = format!("TXH_{}", key.to_uppercase());
+            vars.values.insert(name.clone(), value.to_string());
+            vars.file_keys.push(name);
+        }
+        Ok(vars)
+    }
+
+    // Config file settings that Config never looked up, i.e. misspelled or unknown names
+    fn unknown_file_keys(&self) -> Vec<String> {
+        let read = self.read.borrow();
+        self.file_keys.iter()
+            .filter(|key| !read.contains(*key))
+            .map(|key| key.trim_start_matches("TXH_").to_lowercase())
+            .collect()
+    }
+
+    fn value(&self, key: &str) -> Option<&String> {
+        self.read.borrow_mut().insert(key.to_string());
+        self.values.get(key)
+    }
+
+    // Parse a setting, falling back to the default when unset or empty. A value that doesn't parse is
+    // an error naming the setting, rather than a silent fallback to the default.
+    pub fn get_or<T: std::str::FromStr>(&self, key: &str, default: T) -> Result<T, String> {
+        match self.value(key).filter(|v| !v.is_empty()) {
+            Some(v) => v.parse().map_err(|_| format!("{} has an invalid value {:?}", key, v)),
+            None => Ok(default),
+        }
+    }
+
+    // A setting without a default; empty counts as unset
+    pub fn optional(&self, key: &str) -> Option<String> {
+        self.value(key).filter(|v| !v.is_empty()).cloned()
+    }
+
+    pub fn secret(&self, key: &str) -> Option<Secret> {
+        self.optional(key).map(Secret)
+    }
+
+    // Parse a comma separated setting, ignoring empty entries
+    pub fn list(&self, key: &str) -> Vec<String> {
+        self.value(key)
+            .map(|v| v.split(',').map(|s| s.trim().to_string()).filter(|s| !s.is_empty()).collect())
+            .unwrap_or_default()
+    }
+}
+
+// A config value that must never end up in logs
+#[derive(Clone, Default, PartialEq, Eq)]
+pub struct Secret(pub String);
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+impl Serialize for Secret {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str("<redacted>")
+    }
+}
+
+// What /distribute does with a recipient listed more than once
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicateReceivers {
+    #[default]
+    Merge, // sum the amounts and credit once
+    Reject, // fail the whole distribution with DuplicateReceiver
+}
+
+impl std::str::FromStr for DuplicateReceivers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "merge" => Ok(DuplicateReceivers::Merge),
+            "reject" => Ok(DuplicateReceivers::Reject),
+            other => Err(format!("unknown duplicate receiver handling {}", other)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> ConfigVars {
+        ConfigVars::new(pairs.iter().copied())
+    }
+
+    #[test]
+    fn unset_settings_take_their_defaults() {
+        let config = Config::from_vars(&ConfigVars::default()).unwrap();
+        assert_eq!(config.role, Role::Primary);
+        assert_eq!(config.fee_collector, "fees");
+        assert_eq!(config.lock_ttl_secs, 30);
+        assert!(config.nonce_hints);
+        assert_eq!(config.listen, "127.0.0.1:3000");
+    }
+
+    #[test]
+    fn parses_set_values() {
+        let config = Config::from_vars(&vars(&[
+            ("TXH_ROLE", "replica"),
+            ("TXH_FEE_FLAT", "3"),
+            ("TXH_SHED_OVERLOAD", "true"),
+            ("TXH_KNOWN_RECEIVERS", "Bob, Carol,"),
+            ("TXH_MAX_HISTORY", ""),
+        ])).unwrap();
+        assert_eq!(config.role, Role::Replica);
+        assert_eq!(config.fee_flat, 3);
+        assert!(config.shed_overload);
+        assert_eq!(config.known_receivers, vec!["Bob", "Carol"]);
+        assert_eq!(config.max_history, 0);
+    }
+
+    #[test]
+    fn malformed_values_name_the_setting_and_the_value() {
+        for (key, value) in [
+            ("TXH_ROLE", "replcia"),
+            ("TXH_FEE_FLAT", "-1"),
+            ("TXH_SHED_OVERLOAD", "yes"),
+            ("TXH_TRACE_SAMPLE_RATE", "half"),
+            ("TXH_DUPLICATE_RECEIVERS", "sum"),
+        ] {
+            let err = Config::from_vars(&vars(&[(key, value)])).unwrap_err();
+            assert!(err.contains(key) && err.contains(value), "{}", err);
+        }
+    }
+
+    #[test]
+    fn rejects_an_unusable_listen_address() {
+        let err = Config::from_vars(&vars(&[("TXH_LISTEN", "localhost")])).unwrap_err();
+        assert!(err.contains("TXH_LISTEN"), "{}", err);
+    }
+
+    #[test]
+    fn tenants_get_their_own_state_file_and_need_distinct_plain_ids() {
+        let config = Config::from_vars(&vars(&[("TXH_TENANTS", "acme, globex"), ("TXH_STATE_FILE", "/data/ledger.json")])).unwrap();
+        assert_eq!(config.tenants, vec!["acme", "globex"]);
+        assert_eq!(config.state_file_for(None).as_deref(), Some("/data/ledger.json"));
+        assert_eq!(config.state_file_for(Some("acme")).as_deref(), Some("/data/ledger.json.tenant-acme"));
+
+        for (tenants, expected) in [("acme,../etc", "may only use"), ("acme,globex,acme", "acme twice")] {
+            let err = Config::from_vars(&vars(&[("TXH_TENANTS", tenants)])).unwrap_err();
+            assert!(err.contains(expected), "{}", err);
+        }
+    }
+
+    // Writes contents to a file unique to this test process and returns its path
+    fn file(name: &str, contents: &str) -> String {
+        let path = std::env::temp_dir().join(format!("txh-config-{}-{}", std::process::id(), name));
+        std::fs::write(&path, contents).unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    #[test]
+    fn reads_a_toml_config_file() {
+        let path = file("valid.toml", r#"
+            listen = "0.0.0.0:8080"
+            fee_flat = 2
+            shed_overload = true
+            trace_sample_rate = 0.5
+            known_receivers = ["Bob", "Carol"]
+
+            [[genesis]]
+            id = "Alice"
+            balance = 1000
+
+            [[genesis]]
+            id = "Bob"
+            balance = 500
+            nonce = 3
+        "#);
+        let config = Config::from_vars(&ConfigVars::from_file(&path).unwrap()).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:8080");
+        assert_eq!(config.fee_flat, 2);
+        assert!(config.shed_overload);
+        assert_eq!(config.trace_sample_rate, 0.5);
+        assert_eq!(config.known_receivers, vec!["Bob", "Carol"]);
+        assert_eq!(config.genesis, Some(vec![
+            GenesisAccount { id: "Alice".into(), balance: 1000, nonce: 0 },
+            GenesisAccount { id: "Bob".into(), balance: 500, nonce: 3 },
+        ]));
+    }
+
+    #[test]
+    fn reads_a_json_config_file() {
+        let path = file("valid.json", r#"{"listen": "0.0.0.0:8080", "role": "replica", "genesis": [{"id": "Alice", "balance": 7}]}"#);
+        let config = Config::from_vars(&ConfigVars::from_file(&path).unwrap()).unwrap();
+        assert_eq!(config.listen, "0.0.0.0:8080");
+        assert_eq!(config.role, Role::Replica);
+        assert_eq!(config.genesis, Some(vec![GenesisAccount { id: "Alice".into(), balance: 7, nonce: 0 }]));
+    }
+
+    #[test]
+    fn rejects_invalid_genesis_lists() {
+        for (name, genesis, expected) in [
+            ("duplicate.toml", "[[genesis]]\nid = \"Alice\"\n[[genesis]]\nid = \"Alice\"", "listed twice"),
+            ("empty.toml", "[[genesis]]\nid = \"\"", "must not be empty"),
+            // TOML integers stop at i64::MAX, so only a JSON file can get near the u64 limit
+            (
+                "overflow.json",
+                r#"{"genesis": [{"id": "Alice", "balance": 18446744073709551615}, {"id": "Bob", "balance": 1}]}"#,
+                "add up to more than",
+            ),
+        ] {
+            let path = file(name, genesis);
+            let err = ConfigVars::from_file(&path).unwrap_err();
+            assert!(err.contains(expected), "{}: {}", name, err);
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_settings_and_malformed_files() {
+        let path = file("unknown.toml", "fee_flatt = 2");
+        let err = Config::from_vars(&ConfigVars::from_file(&path).unwrap()).unwrap_err();
+        assert!(err.contains("fee_flatt"), "{}", err);
+
+        let path = file("bad-value.toml", "fee_flat = -2");
+        let err = Config::from_vars(&ConfigVars::from_file(&path).unwrap()).unwrap_err();
+        assert!(err.contains("TXH_FEE_FLAT") && err.contains("-2"), "{}", err);
+
+        let path = file("not-toml.toml", "TXH_FEE_FLAT=2\nlisten");
+        assert!(ConfigVars::from_file(&path).is_err());
+    }
+
+    #[test]
+    fn environment_wins_over_the_file_and_is_reported() {
+        let path = file("overridden.toml", "fee_flat = 2\nfee_bps = 10\nmax_amount = 50\n");
+        let mut vars = ConfigVars::from_file(&path).unwrap();
+        vars.overlay_env([
+            ("TXH_FEE_FLAT".to_string(), "5".to_string()),
+            ("TXH_FEE_BPS".to_string(), "10".to_string()),
+            ("TXH_VELOCITY_LIMIT".to_string(), "7".to_string()),
+            ("HOME".to_string(), "/root".to_string()),
+        ]);
+        let config = Config::from_vars(&vars).unwrap();
+        assert_eq!((config.fee_flat, config.fee_bps, config.max_amount, config.velocity_limit), (5, 10, 50, 7));
+        assert_eq!(vars.env_overrides(), ["TXH_FEE_FLAT"]);
+        let _ = std::fs::remove_file(path);
+    }
+}
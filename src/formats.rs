@@ -0,0 +1,329 @@
+// JSON wire formats for nonces, amounts and timestamps, shared by every request and response type
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::config::{Config, TimestampFormat};
+
+// Nonce wire format, fixed at startup from TXH_HEX_NONCES. Serde helpers can't see the app state,
+// so this one setting lives in a process-wide flag.
+static HEX_NONCES: AtomicBool = AtomicBool::new(false);
+
+// Nonces as JSON numbers by default, or as "0x.." hex strings when TXH_HEX_NONCES is on.
+// Numeric nonces are always accepted on input.
+pub mod nonce_format {
+    use super::HEX_NONCES;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::sync::atomic::Ordering;
+
+    pub fn serialize<S: Serializer>(nonce: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_as(nonce, HEX_NONCES.load(Ordering::Relaxed), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+        deserialize_as(deserializer, HEX_NONCES.load(Ordering::Relaxed))
+    }
+
+    // The same with the format given rather than read from the flag
+    pub(super) fn serialize_as<S: Serializer>(nonce: &u32, hex: bool, serializer: S) -> Result<S::Ok, S::Error> {
+        if hex {
+            serializer.serialize_str(&format!("0x{:x}", nonce))
+        } else {
+            serializer.serialize_u32(*nonce)
+        }
+    }
+
+    pub(super) fn deserialize_as<'de, D: Deserializer<'de>>(deserializer: D, hex: bool) -> Result<u32, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Wire {
+            Number(u32),
+            Hex(String),
+        }
+
+        match Wire::deserialize(deserializer)? {
+            Wire::Number(nonce) => Ok(nonce),
+            Wire::Hex(_) if !hex => {
+                Err(serde::de::Error::custom("nonce must be a number (hex nonces are disabled)"))
+            }
+            Wire::Hex(s) => s.strip_prefix("0x")
+                .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid hex nonce {:?}, expected e.g. \"0x1a\"", s))),
+        }
+    }
+}
+
+// Amount wire format, from TXH_STRING_AMOUNTS; a process-wide flag for the same reason as HEX_NONCES
+static STRING_AMOUNTS: AtomicBool = AtomicBool::new(false);
+
+// From TXH_FLEXIBLE_AMOUNTS: accept string amounts on input while still answering with numbers
+static FLEXIBLE_AMOUNTS: AtomicBool = AtomicBool::new(false);
+
+// Amounts as JSON numbers by default, or as decimal strings of base units when TXH_STRING_AMOUNTS is on,
+// for clients whose numbers lose precision above 2^53. Numeric amounts are always accepted on input.
+pub mod amount_format {
+    use super::{FLEXIBLE_AMOUNTS, STRING_AMOUNTS};
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::sync::atomic::Ordering;
+
+    pub fn serialize<S: Serializer>(amount: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_as(amount, STRING_AMOUNTS.load(Ordering::Relaxed), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        let strings = STRING_AMOUNTS.load(Ordering::Relaxed) || FLEXIBLE_AMOUNTS.load(Ordering::Relaxed);
+        deserialize_as(deserializer, strings)
+    }
+
+    // The same with the format given rather than read from the flags
+    pub(super) fn serialize_as<S: Serializer>(amount: &u64, string: bool, serializer: S) -> Result<S::Ok, S::Error> {
+        if string {
+            serializer.serialize_str(&amount.to_string())
+        } else {
+            serializer.serialize_u64(*amount)
+        }
+    }
+
+    pub(super) fn deserialize_as<'de, D: Deserializer<'de>>(deserializer: D, strings: bool) -> Result<u64, D::Error> {
+        let invalid = |msg: String| serde::de::Error::custom(format!("InvalidAmountFormat: {}", msg));
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Number(n) => n.as_u64()
+                .ok_or_else(|| invalid(format!("amount {} is not a non-negative integer in base units", n))),
+            serde_json::Value::String(_) if !strings => {
+                Err(invalid("amount must be a number (string amounts are disabled)".to_string()))
+            }
+            serde_json::Value::String(s) => {
+                if s.starts_with('-') {
+                    Err(invalid(format!("amount {:?} is negative", s)))
+                } else if s.contains('.') {
+                    Err(invalid(format!("amount {:?} has a fractional part, amounts are integers in base units", s)))
+                } else if s.is_empty() || !s.bytes().all(|b| b.is_ascii_digit()) {
+                    Err(invalid(format!("amount {:?} is not an integer in base units", s)))
+                } else {
+                    s.parse().map_err(|_| invalid(format!("amount {:?} exceeds the maximum of {}", s, u64::MAX)))
+                }
+            }
+            other => Err(invalid(format!("amount must be a number or a decimal string, got {}", other))),
+        }
+    }
+}
+
+// Timestamp wire format, from TXH_TIMESTAMP_FORMAT; a process-wide flag for the same reason as HEX_NONCES
+static RFC3339_TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+// Timestamps as unix milliseconds by default, or as RFC 3339 UTC strings with millisecond precision
+// ("2024-05-01T12:00:00.000Z") when TXH_TIMESTAMP_FORMAT=rfc3339. Both forms are accepted on input,
+// so state files written in either format load back.
+pub mod timestamp_format {
+    use super::RFC3339_TIMESTAMPS;
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::sync::atomic::Ordering;
+
+    pub fn serialize<S: Serializer>(ms: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serialize_as(ms, RFC3339_TIMESTAMPS.load(Ordering::Relaxed), serializer)
+    }
+
+    // The same with the format given rather than read from the flag
+    pub(super) fn serialize_as<S: Serializer>(ms: &u64, rfc3339: bool, serializer: S) -> Result<S::Ok, S::Error> {
+        if rfc3339 {
+            serializer.serialize_str(&to_rfc3339(*ms))
+        } else {
+            serializer.serialize_u64(*ms)
+        }
+    }
+
+    pub fn serialize_option<S: Serializer>(ms: &Option<u64>, serializer: S) -> Result<S::Ok, S::Error> {
+        match ms {
+            Some(ms) => serialize(ms, serializer),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Wire {
+            Millis(u64),
+            Rfc3339(String),
+        }
+        match Wire::deserialize(deserializer)? {
+            Wire::Millis(ms) => Ok(ms),
+            Wire::Rfc3339(s) => from_rfc3339(&s)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid timestamp {:?}, expected unix ms or RFC 3339 UTC", s))),
+        }
+    }
+
+    fn to_rfc3339(ms: u64) -> String {
+        let secs = ms / 1000;
+        let (year, month, day) = civil_from_days((secs / 86_400) as i64);
+        let time = secs % 86_400;
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            year, month, day, time / 3600, time % 3600 / 60, time % 60, ms % 1000
+        )
+    }
+
+    // Parses the format to_rfc3339 writes; the fraction may have up to 3 digits or be left out
+    fn from_rfc3339(s: &str) -> Option<u64> {
+        let (date, time) = s.strip_suffix('Z')?.split_once('T')?;
+        let mut date = date.splitn(3, '-').map(|part| part.parse::<i64>().ok());
+        let (year, month, day) = (date.next()??, date.next()??, date.next()??);
+        let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+        let mut time = time.splitn(3, ':').map(|part| part.parse::<u64>().ok());
+        let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+        if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59
+            || fraction.is_empty() || fraction.len() > 3 || !fraction.bytes().all(|b| b.is_ascii_digit())
+        {
+            return None;
+        }
+        let millis: u64 = format!("{:0<3}", fraction).parse().ok()?;
+        let days = u64::try_from(days_from_civil(year, month as u32, day as u32)).ok()?;
+        Some(((days * 86_400 + hour * 3600 + minute * 60 + second) * 1000) + millis)
+    }
+
+    // Days since 1970-01-01 to (year, month, day) in the proleptic Gregorian calendar, and back
+    // (http://howardhinnant.github.io/date_algorithms.html)
+    fn civil_from_days(days: i64) -> (i64, u32, u32) {
+        let z = days + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        (yoe + era * 400 + i64::from(month <= 2), month, day)
+    }
+
+    fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+        let year = if month <= 2 { year - 1 } else { year };
+        let era = year.div_euclid(400);
+        let yoe = year - era * 400;
+        let mp = (i64::from(month) + 9) % 12;
+        let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+        let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+        era * 146_097 + doe - 719_468
+    }
+}
+
+pub fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+// Points the serde helpers at the formats chosen in `config`
+pub fn set_wire_formats(config: &Config) {
+    HEX_NONCES.store(config.hex_nonces, Ordering::Relaxed);
+    RFC3339_TIMESTAMPS.store(config.timestamp_format == TimestampFormat::Rfc3339, Ordering::Relaxed);
+    STRING_AMOUNTS.store(config.string_amounts, Ordering::Relaxed);
+    FLEXIBLE_AMOUNTS.store(config.flexible_amounts, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    #[test]
+    fn hex_nonces_round_trip_and_numbers_are_still_accepted() {
+        for nonce in [0, 26, u32::MAX] {
+            let wire = nonce_format::serialize_as(&nonce, true, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(format!("0x{:x}", nonce)));
+            assert_eq!(nonce_format::deserialize_as(wire, true).unwrap(), nonce);
+
+            let wire = nonce_format::serialize_as(&nonce, false, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(nonce));
+            assert_eq!(nonce_format::deserialize_as(wire.clone(), false).unwrap(), nonce);
+            assert_eq!(nonce_format::deserialize_as(wire, true).unwrap(), nonce);
+        }
+    }
+
+    #[test]
+    fn malformed_or_disabled_hex_nonces_are_rejected() {
+        assert!(nonce_format::deserialize_as(json!("0x1a"), false).unwrap_err().to_string().contains("disabled"));
+        for wire in ["1a", "0x", "0xzz", "0x100000000", "-0x1"] {
+            assert!(nonce_format::deserialize_as(Value::from(wire), true).is_err(), "{}", wire);
+        }
+        assert!(nonce_format::deserialize_as(json!(-1), true).is_err());
+    }
+
+    #[test]
+    fn string_amounts_round_trip_beyond_the_precision_of_json_numbers() {
+        for amount in [0, 1500, (1 << 53) + 1, u64::MAX] {
+            let wire = amount_format::serialize_as(&amount, true, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(amount.to_string()));
+            assert_eq!(amount_format::deserialize_as(wire, true).unwrap(), amount);
+
+            let wire = amount_format::serialize_as(&amount, false, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(amount));
+            assert_eq!(amount_format::deserialize_as(wire, true).unwrap(), amount);
+        }
+    }
+
+    #[test]
+    fn string_amounts_must_be_whole_non_negative_base_units() {
+        for (wire, reason) in [
+            ("-5", "negative"),
+            ("1.5", "fractional"),
+            ("", "not an integer"),
+            ("12abc", "not an integer"),
+            (" 12", "not an integer"),
+            ("18446744073709551616", "exceeds the maximum"),
+        ] {
+            let err = amount_format::deserialize_as(Value::from(wire), true).unwrap_err().to_string();
+            assert!(err.starts_with("InvalidAmountFormat: ") && err.contains(reason), "{:?}: {}", wire, err);
+        }
+        for wire in [json!(-5), json!(1.5), json!(true), json!(null)] {
+            assert!(amount_format::deserialize_as(wire.clone(), true).is_err(), "{}", wire);
+        }
+    }
+
+    #[test]
+    fn flexible_amounts_accept_both_forms_and_answer_with_numbers() {
+        // TXH_FLEXIBLE_AMOUNTS without TXH_STRING_AMOUNTS: strings accepted on input, numbers written
+        for wire in [json!(1500), json!("1500")] {
+            let amount = amount_format::deserialize_as(wire.clone(), true).unwrap();
+            assert_eq!(amount, 1500, "{}", wire);
+            assert_eq!(amount_format::serialize_as(&amount, false, serde_json::value::Serializer).unwrap(), json!(1500));
+        }
+        let err = amount_format::deserialize_as(json!("1500"), false).unwrap_err().to_string();
+        assert!(err.contains("string amounts are disabled"), "{}", err);
+        for wire in [json!("abc"), json!("99999999999999999999"), json!(-1)] {
+            let err = amount_format::deserialize_as(wire.clone(), true).unwrap_err().to_string();
+            assert!(err.starts_with("InvalidAmountFormat: "), "{}: {}", wire, err);
+        }
+    }
+
+    #[test]
+    fn rfc3339_timestamps_round_trip_with_millisecond_precision() {
+        for (ms, text) in [
+            (0, "1970-01-01T00:00:00.000Z"),
+            (1_714_564_800_123, "2024-05-01T12:00:00.123Z"),
+            (951_782_400_000, "2000-02-29T00:00:00.000Z"),
+            (4_102_444_799_999, "2099-12-31T23:59:59.999Z"),
+        ] {
+            let wire = timestamp_format::serialize_as(&ms, true, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(text));
+            assert_eq!(timestamp_format::deserialize(wire).unwrap(), ms);
+            let wire = timestamp_format::serialize_as(&ms, false, serde_json::value::Serializer).unwrap();
+            assert_eq!(wire, json!(ms));
+            assert_eq!(timestamp_format::deserialize(wire).unwrap(), ms);
+        }
+        // Shorter or missing fractions are read as milliseconds
+        assert_eq!(timestamp_format::deserialize(json!("2024-05-01T12:00:00.5Z")).unwrap(), 1_714_564_800_500);
+        assert_eq!(timestamp_format::deserialize(json!("2024-05-01T12:00:00Z")).unwrap(), 1_714_564_800_000);
+    }
+
+    #[test]
+    fn malformed_timestamps_are_rejected() {
+        for wire in [
+            "2024-05-01T12:00:00.000",
+            "2024-05-01 12:00:00.000Z",
+            "2024-13-01T12:00:00.000Z",
+            "2024-05-01T24:00:00.000Z",
+            "2024-05-01T12:00:00.1234Z",
+            "2024-05-01T12:00:00.Z",
+            "1969-12-31T23:59:59.999Z",
+        ] {
+            assert!(timestamp_format::deserialize(Value::from(wire)).is_err(), "{}", wire);
+        }
+    }
+}
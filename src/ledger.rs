@@ -0,0 +1,869 @@
+// Accounts, the applied-transaction history and the admin log, plus the state file and genesis
+// formats that seed them
+
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::formats::{is_zero, timestamp_format};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)] 
+pub struct Account {
+    pub balance: u64,
+    pub nonce: u32, 
+    pub created_seq: u64, // global sequence at which the account was created (0 for seeded accounts)
+    #[serde(default)]
+    pub created_ms: u64, // clock time the account was created (0 for seeded accounts)
+    pub public_key: Option<String>, // hex ed25519 key; once set, transactions from this account must be signed by it
+    pub last_active_ms: u64, // clock time of the last transaction this account sent or received
+    pub lock: Option<AccountLock>, // while held, transfers from this account must present the lock token
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lock_signed_at: Option<u64>, // timestamp of the last signed lock request, so it can't be replayed
+    pub last_tx_timestamp: Option<u64>, // timestamp carried by the last transaction this account sent
+    #[serde(default)]
+    pub frozen: bool, // set by an admin; a frozen account can receive but not send
+    #[serde(default, skip_serializing_if = "VecDeque::is_empty")]
+    pub outbound: VecDeque<(u64, u64)>, // (clock time, amount) of transfers sent within the velocity window, oldest first
+}
+
+// Client-side optimistic lock on an account's outgoing transfers
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AccountLock {
+    pub token: String,
+    pub expires_at_ms: u64,
+}
+
+// Accounts by id. Reads go straight to the map; writes go through the methods below, which remember
+// the ids they touched so storage can log just those accounts on the next commit.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct AccountStore {
+    accounts: HashMap<String, Account>,
+    #[serde(skip)]
+    touched: HashSet<String>, // ids written since the last commit, including removed ones
+}
+
+impl std::ops::Deref for AccountStore {
+    type Target = HashMap<String, Account>;
+
+    fn deref(&self) -> &HashMap<String, Account> {
+        &self.accounts
+    }
+}
+
+impl AccountStore {
+    pub fn new() -> AccountStore {
+        AccountStore::default()
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Account> {
+        let account = self.accounts.get_mut(id)?;
+        self.touched.insert(id.to_string());
+        Some(account)
+    }
+
+    pub fn insert(&mut self, id: String, account: Account) -> Option<Account> {
+        self.touched.insert(id.clone());
+        self.accounts.insert(id, account)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Account> {
+        let account = self.accounts.remove(id)?;
+        self.touched.insert(id.to_string());
+        Some(account)
+    }
+
+    pub fn entry(&mut self, id: String) -> Entry<'_, String, Account> {
+        self.touched.insert(id.clone());
+        self.accounts.entry(id)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&String, &Account) -> bool) {
+        let touched = &mut self.touched;
+        self.accounts.retain(|id, account| {
+            let kept = keep(id, account);
+            if !kept {
+                touched.insert(id.clone());
+            }
+            kept
+        });
+    }
+
+    // Ids written since the last call, sorted, each with its current state (None once removed)
+    pub fn take_touched(&mut self) -> Vec<(String, Option<Account>)> {
+        let mut ids: Vec<String> = self.touched.drain().collect();
+        ids.sort();
+        ids.into_iter().map(|id| {
+            let account = self.accounts.get(&id).cloned();
+            (id, account)
+        }).collect()
+    }
+}
+
+impl FromIterator<(String, Account)> for AccountStore {
+    fn from_iter<I: IntoIterator<Item = (String, Account)>>(iter: I) -> AccountStore {
+        AccountStore { accounts: iter.into_iter().collect(), touched: HashSet::new() }
+    }
+}
+
+impl<'a> IntoIterator for &'a AccountStore {
+    type Item = (&'a String, &'a Account);
+    type IntoIter = std::collections::hash_map::Iter<'a, String, Account>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.accounts.iter()
+    }
+}
+
+// One applied transaction, as recorded in the ledger history
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct HistoryEntry {
+    pub seq: u64,
+    pub sender: String,
+    pub receiver: String,
+    pub amount: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub fee: u64, // paid by the sender on top of amount
+    #[serde(default)]
+    #[serde(with = "timestamp_format")]
+    pub at_ms: u64, // clock time the transaction was applied
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub tx_id: String, // see tx_id() and multi_tx_id(); empty for admin distribution legs, which aren't submitted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sender_balance: Option<u64>, // sender's balance right after this transaction
+}
+
+impl HistoryEntry {
+    // Signed balance change this entry caused for the account
+    pub fn change_for(&self, id: &str) -> i128 {
+        let mut change = 0i128;
+        if self.receiver == id {
+            change += self.amount as i128;
+        }
+        if self.sender == id {
+            change -= self.amount as i128 + self.fee as i128;
+        }
+        change
+    }
+}
+
+// Account state plus the global sequence, which counts successfully applied transactions,
+// and the history of those transactions in sequence order
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Ledger {
+    pub accounts: AccountStore,
+    pub seq: u64,
+    pub history: VecDeque<HistoryEntry>, // oldest first, capped at max_history entries
+    #[serde(default)]
+    pub admin_log: Vec<AdminEntry>, // admin operations, oldest first; rare enough to keep in full
+    #[serde(default)]
+    pub fee_buckets: VecDeque<(u64, u64)>, // (unix second, fees collected in it), oldest first, FEE_VOLUME_RETENTION_SECS deep
+    #[serde(default)]
+    pub expected_supply: Option<u128>, // what the total supply should be; only admin balance changes and burned fees move it
+    #[serde(default)]
+    pub sequencer_seq: u64, // sequence of the last transaction applied in sequencer order
+    #[serde(default)]
+    pub evicted_through: u64, // seq of the newest history entry evicted by max_history (0 = none evicted)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub integrity_warnings: Vec<String>, // set when the file was saved at shutdown despite a failed audit
+    #[serde(default)]
+    pub commits: u64, // storage commits this state includes; log records at or below it are already in it
+    #[serde(skip)]
+    pub changes: Changes,
+}
+
+// What changed since the last storage commit, besides the accounts the store tracks itself
+#[derive(Debug, Clone)]
+pub struct Changes {
+    pub rewritten: bool, // replaced or edited in ways the counters don't capture; only a full snapshot saves it
+    pub history: usize, // entries appended to history
+    pub admin: usize, // entries appended to the admin log
+    pub fees: bool, // the newest fee bucket changed
+}
+
+impl Changes {
+    pub fn none() -> Changes {
+        Changes { rewritten: false, history: 0, admin: 0, fees: false }
+    }
+}
+
+// A ledger that didn't come out of storage (fresh, deserialized, restored) has never been saved as a whole
+impl Default for Changes {
+    fn default() -> Changes {
+        Changes { rewritten: true, history: 0, admin: 0, fees: false }
+    }
+}
+
+// Outcome of Ledger::audit
+#[derive(Debug)]
+pub struct IntegrityReport {
+    pub total_supply: u128,
+    pub accounts: usize,
+    pub discrepancies: Vec<String>,
+}
+
+// How far back collected fees can be reported
+pub const FEE_VOLUME_RETENTION_SECS: u64 = 24 * 60 * 60;
+
+// One admin operation as it affected one account
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AdminEntry {
+    pub seq: u64, // global sequence at the time, to order it against transactions
+    #[serde(with = "timestamp_format")]
+    pub at_ms: u64,
+    pub account: String,
+    pub action: String,
+    pub detail: String,
+}
+
+impl Ledger {
+    // Sequence of the oldest transaction still in history; anything before it was evicted
+    pub fn first_retained_seq(&self) -> u64 {
+        self.evicted_through + 1
+    }
+
+    // Adds a collected fee to its one-second bucket and drops buckets past the retention window
+    pub fn record_fee(&mut self, now: u64, fee: u64) {
+        let second = now / 1000;
+        let total = match self.fee_buckets.back() {
+            Some((last, total)) if *last == second => total + fee,
+            _ => fee,
+        };
+        self.set_fee_bucket(second, total);
+    }
+
+    // Takes burned fees out of the supply the audit expects
+    pub fn burn(&mut self, amount: u64) {
+        if let Some(expected) = &mut self.expected_supply {
+            *expected = expected.saturating_sub(amount as u128);
+        }
+    }
+
+    // Sets the total of the newest bucket, starting it if second is new, and drops buckets past the retention window
+    pub fn set_fee_bucket(&mut self, second: u64, total: u64) {
+        match self.fee_buckets.back_mut() {
+            Some((last, current)) if *last == second => *current = total,
+            _ => self.fee_buckets.push_back((second, total)),
+        }
+        self.changes.fees = true;
+        while self.fee_buckets.front().is_some_and(|(bucket, _)| bucket + FEE_VOLUME_RETENTION_SECS <= second) {
+            self.fee_buckets.pop_front();
+        }
+    }
+
+    // Fees collected during the last window_secs seconds, including the current one
+    pub fn fee_volume(&self, now: u64, window_secs: u64) -> u64 {
+        let since = (now / 1000).saturating_sub(window_secs.saturating_sub(1));
+        self.fee_buckets.iter().rev()
+            .take_while(|(bucket, _)| *bucket >= since)
+            .map(|(_, total)| total)
+            .sum()
+    }
+
+    pub fn record_admin(&mut self, now: u64, account: &str, action: &str, detail: String) {
+        self.admin_log.push(AdminEntry {
+            seq: self.seq,
+            at_ms: now,
+            account: account.to_string(),
+            action: action.to_string(),
+            detail,
+        });
+        self.changes.admin += 1;
+    }
+
+    // Flags changes the change counters can't describe, such as history entries taken back out,
+    // so the next commit saves the whole ledger
+    pub fn mark_rewritten(&mut self) {
+        self.changes.rewritten = true;
+    }
+
+    // Forgets the tracked changes once they're saved
+    pub fn clear_changes(&mut self) {
+        self.accounts.take_touched();
+        self.changes = Changes::none();
+    }
+
+    // Takes over a ledger from a state file or snapshot: ledgers saved before supply tracking start
+    // from their current total, and warnings left by a failed shutdown audit are reported once and returned
+    pub fn adopt(&mut self, source: &str) -> Vec<String> {
+        if self.expected_supply.is_none() {
+            self.expected_supply = Some(total_supply(&self.accounts));
+            self.mark_rewritten();
+        }
+        if !self.integrity_warnings.is_empty() {
+            tracing::warn!(source, discrepancies = ?self.integrity_warnings, "ledger was saved as potentially inconsistent");
+            self.mark_rewritten();
+        }
+        std::mem::take(&mut self.integrity_warnings)
+    }
+
+    // Checks the invariants every mutation is supposed to keep: transfers conserve the total supply,
+    // and history and account creation never run ahead of the global sequence
+    pub fn audit(&self) -> IntegrityReport {
+        let supply = total_supply(&self.accounts);
+        let mut discrepancies = Vec::new();
+        if let Some(expected) = self.expected_supply
+            && supply != expected
+        {
+            discrepancies.push(format!("total supply is {} but should be {}", supply, expected));
+        }
+        let mut previous = 0;
+        for entry in &self.history {
+            // Entries of one distribution or multi transfer share a seq, so equal neighbours are fine
+            if entry.seq == 0 || entry.seq < previous || entry.seq > self.seq {
+                discrepancies.push(format!("history entry with seq {} is out of order (ledger seq {})", entry.seq, self.seq));
+            }
+            previous = entry.seq;
+        }
+        let mut ahead: Vec<&String> = self.accounts.iter()
+            .filter(|(_, account)| account.created_seq > self.seq)
+            .map(|(id, _)| id)
+            .collect();
+        ahead.sort();
+        for id in ahead {
+            discrepancies.push(format!("account {} was created after the current seq {}", id, self.seq));
+        }
+        IntegrityReport { total_supply: supply, accounts: self.accounts.len(), discrepancies }
+    }
+
+    // Opens a new account, the one way accounts come into being outside of transfers. A starting balance
+    // is new supply. Returns false, changing nothing, if the id is taken.
+    // The creation takes the next global sequence number, so an /accounts/since cursor taken before it sees it.
+    pub fn create_account(&mut self, id: &str, balance: u64, now: u64) -> bool {
+        if self.accounts.contains_key(id) {
+            return false;
+        }
+        self.seq += 1;
+        self.accounts.insert(id.to_string(), Account {
+            balance,
+            created_seq: self.seq,
+            created_ms: now,
+            last_active_ms: now,
+            ..Default::default()
+        });
+        if let Some(expected) = &mut self.expected_supply {
+            *expected += balance as u128;
+        }
+        true
+    }
+
+    // Appends to history, evicting the oldest entries across all accounts beyond max_history (0 = unbounded)
+    pub fn record(&mut self, entry: HistoryEntry, max_history: usize) {
+        self.history.push_back(entry);
+        self.changes.history += 1;
+        if max_history > 0 {
+            while self.history.len() > max_history {
+                if let Some(evicted) = self.history.pop_front() {
+                    self.evicted_through = evicted.seq;
+                }
+            }
+        }
+    }
+}
+
+// Loads a ledger saved by save_ledger; Ok(None) if the file doesn't exist yet
+pub fn load_ledger(path: &str) -> Result<Option<Ledger>, String> {
+    match std::fs::File::open(path) {
+        Ok(file) => serde_json::from_reader(std::io::BufReader::new(file))
+            .map(Some)
+            .map_err(|e| format!("{} is not a valid ledger: {}", path, e)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(format!("can't read {}: {}", path, e)),
+    }
+}
+
+// Writes the ledger as JSON to a temporary file next to path and renames it into place,
+// so a crash mid-write leaves the previous file intact
+pub fn save_ledger(path: &str, ledger: &Ledger) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let tmp = format!("{}.tmp", path);
+    let file = std::fs::File::create(&tmp)?;
+    let mut writer = std::io::BufWriter::new(file);
+    serde_json::to_writer(&mut writer, ledger)?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    // The rename itself only survives a crash once the directory is synced
+    #[cfg(unix)]
+    {
+        let dir = std::path::Path::new(path).parent().filter(|dir| !dir.as_os_str().is_empty());
+        std::fs::File::open(dir.unwrap_or(std::path::Path::new(".")))?.sync_all()?;
+    }
+    Ok(())
+}
+
+// One account a fresh ledger starts with
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisAccount {
+    pub id: String,
+    #[serde(default)]
+    pub balance: u64,
+    #[serde(default)]
+    pub nonce: u32,
+}
+
+// Reads and validates a TXH_GENESIS_FILE, a JSON list of accounts
+pub fn load_genesis(path: &str) -> Result<Vec<GenesisAccount>, String> {
+    let file = std::fs::File::open(path).map_err(|e| format!("can't read {}: {}", path, e))?;
+    let accounts: Vec<GenesisAccount> = serde_json::from_reader(std::io::BufReader::new(file))
+        .map_err(|e| format!("{} is not a valid genesis file: {}", path, e))?;
+    check_genesis(&accounts).map_err(|e| format!("{}: {}", path, e))?;
+    Ok(accounts)
+}
+
+// Ids must be non-empty and unique, and the balances must add up to no more than u64::MAX,
+// so any one account could hold the whole supply
+pub fn check_genesis(accounts: &[GenesisAccount]) -> Result<(), String> {
+    let mut seen = std::collections::HashSet::new();
+    let mut total: u64 = 0;
+    for account in accounts {
+        if account.id.is_empty() {
+            return Err("account ids must not be empty".to_string());
+        }
+        if !seen.insert(account.id.as_str()) {
+            return Err(format!("account {} is listed twice", account.id));
+        }
+        total = total.checked_add(account.balance)
+            .ok_or_else(|| format!("balances add up to more than {}", u64::MAX))?;
+    }
+    Ok(())
+}
+
+impl Ledger {
+    // A fresh ledger holding exactly the genesis accounts, all created at seq 0
+    pub fn from_genesis(accounts: &[GenesisAccount]) -> Ledger {
+        let accounts: AccountStore = accounts.iter()
+            .map(|account| (account.id.clone(), Account { balance: account.balance, nonce: account.nonce, ..Default::default() }))
+            .collect();
+        let supply = total_supply(&accounts);
+        Ledger { accounts, expected_supply: Some(supply), ..Default::default() }
+    }
+}
+
+// The state a run of transactions over a known set of accounts can change, copied before the run so it can be
+// undone without cloning the whole ledger
+#[derive(Debug)]
+pub struct Savepoint {
+    accounts: Vec<(String, Option<Account>)>, // every account the run may touch, as it was
+    seq: u64,
+    sequencer_seq: u64,
+    evicted_through: u64,
+    expected_supply: Option<u128>,
+    history_len: usize,
+    evictable: Vec<HistoryEntry>, // oldest history entries, as many as the run's records could evict
+    admin_len: usize,
+    fee_buckets_len: usize,
+    newest_fee_bucket: Option<(u64, u64)>,
+    expiring_fee_buckets: Vec<(u64, u64)>, // buckets a fee recorded at `now` would drop
+    changes: Changes,
+}
+
+impl Ledger {
+    // Copies what `records` transactions between the given accounts, applied at `now`, can change
+    pub fn savepoint<'a>(&self, ids: impl IntoIterator<Item = &'a str>, records: usize, max_history: usize, now: u64) -> Savepoint {
+        let mut ids: Vec<&str> = ids.into_iter().collect();
+        ids.sort_unstable();
+        ids.dedup();
+        let evictable = if max_history > 0 { (self.history.len() + records).saturating_sub(max_history) } else { 0 };
+        let second = now / 1000;
+        Savepoint {
+            accounts: ids.into_iter().map(|id| (id.to_string(), self.accounts.get(id).cloned())).collect(),
+            seq: self.seq,
+            sequencer_seq: self.sequencer_seq,
+            evicted_through: self.evicted_through,
+            expected_supply: self.expected_supply,
+            history_len: self.history.len(),
+            evictable: self.history.iter().take(evictable).cloned().collect(),
+            admin_len: self.admin_log.len(),
+            fee_buckets_len: self.fee_buckets.len(),
+            newest_fee_bucket: self.fee_buckets.back().copied(),
+            expiring_fee_buckets: self.fee_buckets.iter()
+                .take_while(|(bucket, _)| bucket + FEE_VOLUME_RETENTION_SECS <= second)
+                .copied()
+                .collect(),
+            changes: self.changes.clone(),
+        }
+    }
+
+    // Puts back everything the savepoint covers, undoing the run since it was taken
+    pub fn rollback_to(&mut self, savepoint: Savepoint) {
+        for (id, account) in savepoint.accounts {
+            match account {
+                Some(account) => self.accounts.insert(id, account),
+                None => self.accounts.remove(&id),
+            };
+        }
+        while self.history.back().is_some_and(|entry| entry.seq > savepoint.seq) {
+            self.history.pop_back();
+        }
+        let evicted = savepoint.history_len - self.history.len();
+        for entry in savepoint.evictable.into_iter().take(evicted).rev() {
+            self.history.push_front(entry);
+        }
+        self.admin_log.truncate(savepoint.admin_len);
+
+        match savepoint.newest_fee_bucket {
+            Some((second, total)) => {
+                while self.fee_buckets.back().is_some_and(|(bucket, _)| *bucket > second) {
+                    self.fee_buckets.pop_back();
+                }
+                if let Some(newest) = self.fee_buckets.back_mut() {
+                    *newest = (second, total);
+                } else {
+                    self.fee_buckets.push_back((second, total));
+                }
+            }
+            None => self.fee_buckets.clear(),
+        }
+        let dropped = savepoint.fee_buckets_len - self.fee_buckets.len();
+        for bucket in savepoint.expiring_fee_buckets.into_iter().take(dropped).rev() {
+            self.fee_buckets.push_front(bucket);
+        }
+
+        self.seq = savepoint.seq;
+        self.sequencer_seq = savepoint.sequencer_seq;
+        self.evicted_through = savepoint.evicted_through;
+        self.expected_supply = savepoint.expected_supply;
+        self.changes = savepoint.changes;
+    }
+}
+
+// Copies of the accounts one transfer touches, to apply it to without holding the ledger lock, and write
+// back afterwards. The fee collector, which every fee-paying transfer credits, is written back by adding
+// what the transfer paid it, so transfers only conflict over their own sender and receiver.
+#[derive(Debug)]
+pub struct Stage {
+    pub ledger: Ledger, // the copied accounts, plus the global state the checks read
+    accounts: Vec<(String, Option<Account>)>, // sender and receiver as they were copied
+    collector: Option<(String, Option<Account>)>, // the fee collector as it was copied, unless it is one of them
+}
+
+impl Ledger {
+    // Copies the sender, receiver and fee collector into a ledger of their own
+    pub fn stage(&self, sender: &str, receiver: &str, collector: &str) -> Stage {
+        let mut ids = vec![sender, receiver];
+        ids.sort_unstable();
+        ids.dedup();
+        let copy = |id: &str| (id.to_string(), self.accounts.get(id).cloned());
+        let accounts: Vec<_> = ids.iter().map(|id| copy(id)).collect();
+        let collector = (!ids.contains(&collector)).then(|| copy(collector));
+        let staged = accounts.iter().chain(&collector)
+            .filter_map(|(id, account)| Some((id.clone(), account.clone()?)))
+            .collect();
+        Stage {
+            ledger: Ledger { accounts: staged, seq: self.seq, sequencer_seq: self.sequencer_seq, ..Default::default() },
+            accounts,
+            collector,
+        }
+    }
+
+    // Whether every account the stage copied is still as it was, the fee collector included
+    pub fn unchanged_since(&self, stage: &Stage) -> bool {
+        stage.accounts.iter().chain(&stage.collector).all(|(id, account)| self.accounts.get(id) == account.as_ref())
+    }
+
+    // Writes back a stage one transfer was applied to: its accounts, its history entry under the next seq and its fee.
+    // Returns false, changing nothing, if the sender or receiver changed since the copy was taken or the
+    // fee collector can't take the fee anymore; the transfer then has to be applied to the ledger itself.
+    pub fn merge(&mut self, stage: &Stage, max_history: usize, now: u64) -> bool {
+        if !stage.accounts.iter().all(|(id, account)| self.accounts.get(id) == account.as_ref()) {
+            return false;
+        }
+        let collected = stage.collector.as_ref().map(|(id, before)| {
+            let after = stage.ledger.accounts.get(id).map_or(0, |account| account.balance);
+            (id, after - before.as_ref().map_or(0, |account| account.balance))
+        });
+        let collector_balance = match collected {
+            Some((id, fee)) if fee > 0 => {
+                match self.accounts.get(id).map_or(0, |account| account.balance).checked_add(fee) {
+                    Some(balance) => Some((id, balance)),
+                    None => return false,
+                }
+            }
+            _ => None,
+        };
+
+        let seq = self.seq + 1;
+        for (id, before) in &stage.accounts {
+            if let Some(account) = stage.ledger.accounts.get(id) {
+                let mut account = account.clone();
+                if before.is_none() {
+                    account.created_seq = seq;
+                }
+                self.accounts.insert(id.clone(), account);
+            }
+        }
+        if let Some((id, balance)) = collector_balance {
+            self.accounts.entry(id.clone()).or_insert(Account { created_seq: seq, created_ms: now, ..Default::default() }).balance = balance;
+        }
+        let fees: u64 = stage.ledger.fee_buckets.iter().map(|(_, total)| total).sum();
+        // A transfer only moves funds between the staged accounts, so whatever they lost in total was burned
+        let staged = stage.accounts.iter().chain(&stage.collector);
+        let before: u128 = staged.clone().map(|(_, account)| account.as_ref().map_or(0, |account| account.balance as u128)).sum();
+        let after: u128 = staged.map(|(id, _)| stage.ledger.accounts.get(id).map_or(0, |account| account.balance as u128)).sum();
+        let burned = u64::try_from(before.saturating_sub(after)).unwrap_or(u64::MAX);
+        for entry in &stage.ledger.history {
+            self.record(HistoryEntry { seq, ..entry.clone() }, max_history);
+        }
+        if fees > 0 {
+            self.record_fee(now, fees);
+        }
+        if burned > 0 {
+            self.burn(burned);
+        }
+        self.seq = seq;
+        true
+    }
+}
+
+// Feeds one account's id and state into a commitment; None marks an account that doesn't exist
+pub fn hash_account_state(hasher: &mut Sha256, id: &str, account: Option<&Account>) {
+    hasher.update((id.len() as u64).to_be_bytes());
+    hasher.update(id.as_bytes());
+    match account {
+        Some(account) => {
+            hasher.update([1]);
+            hasher.update(account.balance.to_be_bytes());
+            hasher.update(account.nonce.to_be_bytes());
+        }
+        None => hasher.update([0]),
+    }
+}
+
+// Hex SHA-256 over every account's balance and nonce in id order. Any applied transaction changes it.
+pub fn state_root(accounts: &AccountStore) -> String {
+    let mut ids: Vec<&String> = accounts.keys().collect();
+    ids.sort();
+    let mut hasher = Sha256::new();
+    for id in ids {
+        hash_account_state(&mut hasher, id, accounts.get(id));
+    }
+    hex::encode(hasher.finalize())
+}
+
+// Sum of all balances. Transfers never change it, only admin operations do
+pub fn total_supply(accounts: &AccountStore) -> u128 {
+    accounts.values().map(|acct| acct.balance as u128).sum()
+}
+
+// Removes accounts that hold nothing and have been idle for at least ttl_ms, returning their ids.
+// Accounts with a registered key are kept: recreating them would reset the nonce to 0
+// and make their old signed transactions replayable. Frozen and locked accounts are kept too, since
+// recreating them would silently drop the freeze or lock. Each removal goes into the admin log.
+pub fn prune_inactive(ledger: &mut Ledger, now: u64, ttl_ms: u64) -> Vec<String> {
+    let mut expired: Vec<String> = ledger.accounts.iter()
+        .filter(|(_, acct)| {
+            acct.balance == 0
+                && acct.public_key.is_none()
+                && !acct.frozen
+                && acct.lock.as_ref().is_none_or(|lock| lock.expires_at_ms <= now)
+                && now.saturating_sub(acct.last_active_ms) >= ttl_ms
+        })
+        .map(|(id, _)| id.clone())
+        .collect();
+    expired.sort();
+    for id in &expired {
+        let idle_ms = now.saturating_sub(ledger.accounts[id].last_active_ms);
+        ledger.accounts.remove(id);
+        ledger.record_admin(now, id, "prune", format!("removed after {} ms without activity", idle_ms));
+    }
+    expired
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn genesis() -> Vec<GenesisAccount> {
+        vec![
+            GenesisAccount { id: "Alice".into(), balance: 1000, nonce: 0 },
+            GenesisAccount { id: "Bob".into(), balance: 500, nonce: 7 },
+            GenesisAccount { id: "Carol".into(), balance: 0, nonce: 0 },
+        ]
+    }
+
+    #[test]
+    fn seeds_exactly_the_genesis_accounts() {
+        let ledger = Ledger::from_genesis(&genesis());
+        assert_eq!(ledger.accounts.len(), 3);
+        for account in genesis() {
+            let seeded = &ledger.accounts[&account.id];
+            assert_eq!((seeded.balance, seeded.nonce, seeded.created_seq), (account.balance, account.nonce, 0));
+        }
+        assert_eq!(ledger.seq, 0);
+        assert_eq!(ledger.expected_supply, Some(1500));
+        assert!(ledger.audit().discrepancies.is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_genesis_files() {
+        for (name, contents, expected) in [
+            ("duplicate", r#"[{"id": "Alice"}, {"id": "Alice"}]"#, "listed twice"),
+            ("empty", r#"[{"id": ""}]"#, "must not be empty"),
+            ("overflow", r#"[{"id": "Alice", "balance": 18446744073709551615}, {"id": "Bob", "balance": 1}]"#, "add up to more than"),
+            ("unknown-field", r#"[{"id": "Alice", "balanse": 1}]"#, "not a valid genesis file"),
+        ] {
+            let path = std::env::temp_dir().join(format!("txh-genesis-{}-{}.json", std::process::id(), name));
+            std::fs::write(&path, contents).unwrap();
+            let err = load_genesis(path.to_str().unwrap()).unwrap_err();
+            assert!(err.contains(expected), "{}: {}", name, err);
+        }
+    }
+
+    #[test]
+    fn audit_accepts_the_shared_seq_of_a_multi_recipient_transfer() {
+        use crate::config::{Config, ConfigVars};
+        use crate::validation::{handle_distribution, handle_multi_transfer, Distribution, MultiTransfer};
+
+        let config = Config::from_vars(&ConfigVars::default()).unwrap();
+        let mut ledger = Ledger::from_genesis(&genesis());
+        let dist = Distribution { from: "Alice".into(), recipients: vec![("Bob".into(), 10), ("Carol".into(), 20), ("Dave".into(), 30)] };
+        handle_distribution(&dist, &mut ledger, &config, 0, false).unwrap();
+        let multi = MultiTransfer {
+            sender: "Bob".into(),
+            outputs: vec![("Alice".into(), 1), ("Carol".into(), 2)],
+            nonce: 7,
+            signature: None,
+            lock_token: None,
+            chain_id: None,
+            timestamp: None,
+            expected_state_root: None,
+        };
+        let dist = Distribution { from: multi.sender.clone(), recipients: multi.outputs.clone() };
+        handle_multi_transfer(&multi, &dist, &mut ledger, &config, 0).unwrap();
+
+        assert_eq!(ledger.history.len(), 5);
+        assert_eq!(ledger.history.iter().map(|entry| entry.seq).collect::<Vec<_>>(), vec![1, 1, 1, 2, 2]);
+        assert_eq!(ledger.audit().discrepancies, Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_merged_stage_matches_applying_to_the_ledger_directly() {
+        use crate::config::{Config, ConfigVars};
+        use crate::validation::{handle_transaction, Transaction};
+
+        let vars = ConfigVars::new([("TXH_FEE_FLAT", "2"), ("TXH_FEE_COLLECTOR", "Fees")]);
+        let config = Config::from_vars(&vars).unwrap();
+        let tx = Transaction { sender: "Alice".into(), receiver: "Dave".into(), amount: 10, nonce: 0, ..Default::default() };
+        let mut direct = Ledger::from_genesis(&genesis());
+        handle_transaction(&tx, &mut direct, &config, 5_000).unwrap();
+
+        let mut ledger = Ledger::from_genesis(&genesis());
+        let mut stage = ledger.stage(&tx.sender, &tx.receiver, &config.fee_collector);
+        handle_transaction(&tx, &mut stage.ledger, &config, 5_000).unwrap();
+        // Someone else's transfer lands in between; it doesn't touch Alice or Dave
+        ledger.create_account("Erin", 0, 5_000);
+        assert!(ledger.merge(&stage, 0, 5_000));
+
+        for id in ["Alice", "Dave", "Fees"] {
+            assert_eq!(ledger.accounts[id].balance, direct.accounts[id].balance, "{}", id);
+        }
+        assert_eq!((ledger.seq, ledger.accounts["Dave"].created_seq, ledger.accounts["Fees"].created_seq), (2, 2, 2));
+        assert_eq!((ledger.history.len(), ledger.history[0].seq), (1, 2));
+        assert_eq!(ledger.fee_volume(5_000, 1), 2);
+        assert_eq!(ledger.audit().discrepancies, Vec::<String>::new());
+    }
+
+    #[test]
+    fn a_stage_is_not_merged_over_a_changed_account() {
+        use crate::config::{Config, ConfigVars};
+        use crate::validation::{handle_transaction, Transaction};
+
+        let config = Config::from_vars(&ConfigVars::default()).unwrap();
+        let tx = Transaction { sender: "Alice".into(), receiver: "Bob".into(), amount: 10, nonce: 0, ..Default::default() };
+        let mut ledger = Ledger::from_genesis(&genesis());
+        let mut stage = ledger.stage(&tx.sender, &tx.receiver, &config.fee_collector);
+        handle_transaction(&tx, &mut stage.ledger, &config, 0).unwrap();
+        ledger.accounts.get_mut("Bob").unwrap().frozen = true;
+
+        assert!(!ledger.unchanged_since(&stage));
+        assert!(!ledger.merge(&stage, 0, 0));
+        assert_eq!((ledger.seq, ledger.accounts["Alice"].balance, ledger.history.len()), (0, 1000, 0));
+    }
+
+    #[test]
+    fn audit_flags_history_out_of_order() {
+        let mut ledger = Ledger::from_genesis(&genesis());
+        ledger.seq = 2;
+        for seq in [2, 1] {
+            ledger.record(HistoryEntry { seq, sender: "Alice".into(), receiver: "Bob".into(), ..Default::default() }, 0);
+        }
+        let report = ledger.audit();
+        assert_eq!(report.discrepancies.len(), 1, "{:?}", report.discrepancies);
+        assert!(report.discrepancies[0].contains("seq 1"));
+    }
+
+    #[test]
+    fn created_accounts_are_newer_than_a_cursor_taken_before_them() {
+        let mut ledger = Ledger::from_genesis(&genesis());
+        ledger.seq = 4; // e.g. after four transfers
+        let cursor = ledger.seq;
+        assert!(ledger.create_account("Dave", 0, 0));
+        assert!(!ledger.create_account("Dave", 0, 0));
+        assert!(ledger.accounts["Dave"].created_seq > cursor);
+        assert_eq!(ledger.seq, cursor + 1);
+        assert!(ledger.audit().discrepancies.is_empty());
+    }
+
+    #[test]
+    fn evicting_history_moves_the_first_retained_seq() {
+        let mut ledger = Ledger::from_genesis(&genesis());
+        assert_eq!(ledger.first_retained_seq(), 1);
+        for seq in 1..=3 {
+            ledger.seq = seq;
+            ledger.record(HistoryEntry { seq, ..Default::default() }, 2);
+        }
+        assert_eq!(ledger.first_retained_seq(), 2);
+    }
+
+    #[test]
+    fn rolling_back_to_a_savepoint_undoes_evictions_and_expired_fee_buckets() {
+        let now = 2 * FEE_VOLUME_RETENTION_SECS * 1000;
+        let mut ledger = Ledger::from_genesis(&genesis());
+        for seq in 1..=2 {
+            ledger.seq = seq;
+            ledger.record(HistoryEntry { seq, ..Default::default() }, 2);
+        }
+        ledger.record_fee(1000, 7);
+        ledger.record_fee(now - 1000, 3);
+        let before = serde_json::to_value(&ledger).unwrap();
+
+        let savepoint = ledger.savepoint(["Alice", "Carol"], 3, 2, now);
+        for seq in 3..=5 {
+            ledger.seq = seq;
+            ledger.record(HistoryEntry { seq, ..Default::default() }, 2);
+        }
+        ledger.accounts.get_mut("Alice").unwrap().balance = 0;
+        ledger.accounts.insert("Carol".to_string(), Account::default());
+        ledger.record_fee(now, 1);
+        ledger.record_admin(now, "Alice", "note", String::new());
+        assert_eq!(ledger.fee_buckets.len(), 2);
+
+        ledger.rollback_to(savepoint);
+        assert_eq!(serde_json::to_value(&ledger).unwrap(), before);
+        assert_eq!(ledger.changes.history, 2);
+    }
+
+    #[test]
+    fn pruning_keeps_frozen_and_locked_accounts_and_logs_removals() {
+        let mut ledger = Ledger::from_genesis(&[
+            GenesisAccount { id: "Idle".into(), balance: 0, nonce: 0 },
+            GenesisAccount { id: "Frozen".into(), balance: 0, nonce: 0 },
+            GenesisAccount { id: "Locked".into(), balance: 0, nonce: 0 },
+            GenesisAccount { id: "LockExpired".into(), balance: 0, nonce: 0 },
+        ]);
+        ledger.accounts.get_mut("Frozen").unwrap().frozen = true;
+        ledger.accounts.get_mut("Locked").unwrap().lock = Some(AccountLock { token: "t".into(), expires_at_ms: 2_000 });
+        ledger.accounts.get_mut("LockExpired").unwrap().lock = Some(AccountLock { token: "t".into(), expires_at_ms: 500 });
+
+        let pruned = prune_inactive(&mut ledger, 1_000, 1_000);
+        assert_eq!(pruned, ["Idle", "LockExpired"]);
+        assert!(ledger.accounts.contains_key("Frozen") && ledger.accounts.contains_key("Locked"));
+        let logged: Vec<(&str, &str)> = ledger.admin_log.iter().map(|e| (e.account.as_str(), e.action.as_str())).collect();
+        assert_eq!(logged, [("Idle", "prune"), ("LockExpired", "prune")]);
+    }
+}
@@ -0,0 +1,10 @@
+// Transaction handler: a ledger of accounts, the rules for applying transfers to it, and the HTTP API
+// that serves both. The binary in main.rs only loads the configuration and state and starts serving.
+
+pub mod bench;
+pub mod config;
+pub mod formats;
+pub mod ledger;
+pub mod server;
+pub mod storage;
+pub mod validation;
@@ -1,143 +1,171 @@
-use std::collections::HashMap;
-
-use axum::{
-    routing::post,
-    Json, Router,
-    extract::State,
-};
-use serde::{Deserialize, Serialize};
 use std::net::SocketAddr;
-use tokio::net::TcpListener;
-
-use std::sync::{Arc, Mutex};
 
-#[derive(Debug, Clone)] 
-struct Account {
-    balance: u64,
-    nonce: u32, 
-}
+use ed25519_dalek::SigningKey;
+use tokio::net::TcpListener;
 
-#[derive(Debug, Clone, Deserialize)]
-struct Transaction {
-    sender: String,
-    receiver: String,
-    amount: u64,
-    nonce: u32,
-    // signature: String, // Omitted for simplicity in prototype.
+use transaction_handler_microservice::bench::{run_bench, Locking};
+use transaction_handler_microservice::config::{Config, Role};
+use transaction_handler_microservice::formats::set_wire_formats;
+use transaction_handler_microservice::ledger::{load_genesis, GenesisAccount, Ledger};
+use transaction_handler_microservice::server::{
+    build_tenant_router, final_integrity_check, run_janitor, run_replica_sync, AppState, Severity,
+};
+use transaction_handler_microservice::storage::{FileStorage, Storage};
+
+// Resolves on Ctrl-C or SIGTERM; in-flight requests then finish before serve returns
+async fn shutdown_signal() {
+    let ctrl_c = async { tokio::signal::ctrl_c().await.expect("can't listen for Ctrl-C") };
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("can't listen for SIGTERM")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+    tracing::info!("shutting down");
 }
 
-#[derive(Debug)]
-enum TransactionError {
-    AccountNotFound, // Sender account doesn't exist
-    AmountIsZero, // Transcation amount is zero
-    SenderIsReceiver, // Sender and receiver are the same 
-    InsufficientFunds, //  Sender has sufficient funds
-    InvalidNonce, // Transaction's nonce isn't the sender's current nonce
+// Loads the saved ledger of the default ledger or a tenant, or seeds a fresh one from the genesis accounts.
+// Also returns the problems the saved ledger was flagged with at its last shutdown.
+fn open_ledger(config: &Config, tenant: Option<&str>) -> (Ledger, Vec<String>) {
+    let state_file = config.state_file_for(tenant);
+    let saved = match &state_file {
+        Some(path) => FileStorage::new(path, config.snapshot_every).load()
+            .unwrap_or_else(|e| exit_with_error(format!("can't load {}: {}", path, e))),
+        None => None,
+    };
+    if let Some(mut ledger) = saved {
+        tracing::info!(tenant, accounts = ledger.accounts.len(), seq = ledger.seq, path = state_file.as_deref().unwrap(), "loaded ledger");
+        let inherited = ledger.adopt("TXH_STATE_FILE");
+        return (ledger, inherited);
+    }
+    let genesis = match (&config.genesis, &config.genesis_file) {
+        (Some(accounts), _) => Some(accounts.clone()),
+        (None, Some(path)) => Some(load_genesis(path).unwrap_or_else(|e| exit_with_error(format!("invalid TXH_GENESIS_FILE: {}", e)))),
+        (None, None) => None,
+    };
+    let ledger = match genesis {
+        Some(accounts) => Ledger::from_genesis(&accounts),
+        // Populate with some initial accounts
+        None => Ledger::from_genesis(&[
+            GenesisAccount { id: "Alice".to_string(), balance: 1000, nonce: 0 },
+            GenesisAccount { id: "Bob".to_string(), balance: 500, nonce: 0 },
+        ]),
+    };
+    tracing::info!(tenant, accounts = ledger.accounts.len(), supply = ?ledger.expected_supply, "seeded ledger");
+    (ledger, Vec::new())
 }
 
-#[derive(Debug, Serialize)]
-struct TxResponse {
-    status: String,
-    message: String,
+// Startup errors end the process with a non-zero status instead of a panic backtrace
+fn exit_with_error(message: String) -> ! {
+    tracing::error!("{}", message);
+    std::process::exit(1);
 }
 
-type AccountStore = HashMap<String, Account>;
-type SharedAccountStore = Arc<Mutex<AccountStore>>;
-
-
-// Function handles a single transaction, validating then updating account balances and nonces
-// if valid, it updates the sender and receiver balances and increments the sender's nonce
-// if the recewiver account doesn't exist, it's created with 0 balance and 0 nonce before receiving funds
+#[tokio::main]
+async fn main() {
 
-fn handle_transaction(
-    tx: &Transaction,
-    accts: &mut AccountStore,
-) -> Result<(), TransactionError> {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info".into()),
+        )
+        .init();
+
+    let config = Config::load().unwrap_or_else(|e| exit_with_error(format!("invalid configuration: {}", e)));
+
+    // `--bench N` runs the built-in load test instead of serving
+    let args: Vec<String> = std::env::args().collect();
+    if let Some(pos) = args.iter().position(|arg| arg == "--bench") {
+        let total = args.get(pos + 1).and_then(|n| n.parse().ok())
+            .unwrap_or_else(|| exit_with_error("--bench needs a transaction count".to_string()));
+        // The same load under the single ledger lock and under account locks, for comparison
+        for locking in [Locking::Ledger, Locking::Accounts] {
+            let report = run_bench(total, config.clone(), locking).await;
+            tracing::info!(
+                locking = ?report.locking,
+                total = report.total,
+                ok = report.ok,
+                senders = report.senders,
+                elapsed = ?report.elapsed,
+                throughput_per_sec = report.throughput_per_sec as u64,
+                "bench finished"
+            );
+            tracing::info!(p50 = ?report.p50, p90 = ?report.p90, p99 = ?report.p99, max = ?report.max, "bench latency");
+        }
+        return;
+    }
 
-    // 1. Verify sender account exists by using get and unwrap before cloning it
-   let mut sender_account_clone = accts.get(&tx.sender).unwrap().clone();
+    // Serialized rather than Debug-printed so secrets stay redacted and an inline genesis list is left out
+    tracing::info!(config = %serde_json::to_string(&config).unwrap_or_default(), "effective config");
+    set_wire_formats(&config);
 
-    // 2. Transaction amount is not zero
-    if tx.amount == 0 {
-        return Err(TransactionError::AmountIsZero);
-    }
+    let (ledger, inherited) = open_ledger(&config, None);
 
-    // 3. validate sender isn't receiver
-    if tx.sender == tx.receiver {
-        return Err(TransactionError::SenderIsReceiver);
-    }
+    let server_key = match &config.server_key {
+        Some(seed) => {
+            let seed: [u8; 32] = hex::decode(&seed.0).ok().and_then(|b| b.try_into().ok())
+                .unwrap_or_else(|| exit_with_error("TXH_SERVER_KEY must be a hex encoded 32 byte ed25519 seed".to_string()));
+            SigningKey::from_bytes(&seed)
+        }
+        None => SigningKey::generate(&mut rand::rngs::OsRng),
+    };
+    tracing::info!(public_key = %hex::encode(server_key.verifying_key().to_bytes()), "server key");
 
-    // 4. Sender has sufficient funds
-    if sender_account_clone.balance < tx.amount {
-        return Err(TransactionError::InsufficientFunds);
+    if let Err(e) = config.check_fee_collector(&ledger.accounts) {
+        exit_with_error(format!("invalid configuration: {}", e));
     }
-
-    // 5. Transaction's nonce is the sender's current nonce
-    if sender_account_clone.nonce != tx.nonce {
-        return Err(TransactionError::InvalidNonce);
+    let tenant_ledgers: Vec<_> = config.tenants.iter().map(|tenant| {
+        let (ledger, inherited) = open_ledger(&config, Some(tenant));
+        if let Err(e) = config.check_fee_collector(&ledger.accounts) {
+            exit_with_error(format!("invalid configuration for tenant {}: {}", tenant, e));
+        }
+        (tenant.clone(), ledger, inherited)
+    }).collect();
+
+    let state = AppState::new(config, ledger, server_key);
+    let mut states = vec![(state.clone(), inherited)];
+    for (tenant, ledger, inherited) in tenant_ledgers {
+        states.push((state.for_tenant(&tenant, ledger), inherited));
     }
-
-    // It's Valid. 
-    // // Update Sender bal
-    sender_account_clone.balance -= tx.amount;
-    // // Increment Sender Nonce
-    sender_account_clone.nonce += 1;
-    
-    // // Update Receiver Bal. If receiver account, doesn't exist, create it.
-    let receiver_account = accts.entry(tx.receiver.clone()).or_insert(Account {balance: 0, nonce: 0 });
-    receiver_account.balance += tx.amount;
-
-    // put the modified sender back into the AccountStore
-    accts.insert(tx.sender.clone(), sender_account_clone);
-    
-    println!("Updated accounts {:#?}", accts);
-
-    Ok(())
-}
-
-async fn submit_transaction(
-    State(accounts): State<SharedAccountStore>,
-    Json(tx): Json<Transaction>,
-) -> Json<TxResponse> {
-    
-    let mut accts = accounts.lock().unwrap();
-
-    match handle_transaction(&tx,&mut accts) {
-        Ok(_) => Json(TxResponse {
-            status: "ok".to_string(),
-            message: format!("Processed transaction from {} to {} for {}", tx.sender, tx.receiver, tx.amount),
-        }),
-        Err(e) => Json(TxResponse {
-            status: "error".to_string(),
-            message: format!("{:?}", e),
-        }),
+    for (state, inherited) in &states {
+        if !inherited.is_empty() {
+            state.raise_alert("inconsistent_ledger", Severity::Critical, format!("TXH_STATE_FILE was flagged at shutdown: {}", inherited.join("; ")));
+        }
+        if state.config().inactive_ttl_secs > 0 {
+            tokio::spawn(run_janitor(state.clone()));
+        }
+        if state.config().role == Role::Replica && state.config().primary_url.is_some() {
+            tokio::spawn(run_replica_sync(state.clone()));
+        }
     }
-    
-}
+    let states: Vec<AppState> = states.into_iter().map(|(state, _)| state).collect();
 
-#[tokio::main]
-async fn main() {
+    let app = build_tenant_router(state.clone(), states[1..].to_vec());
 
-    let accounts: SharedAccountStore = Arc::new(Mutex::new({
-        let mut accts: AccountStore = HashMap::new();
-        // Populate with some initial accounts
-        accts.insert("Alice".to_string(), Account { balance: 1000, nonce: 0 });
-        accts.insert("Bob".to_string(), Account { balance: 500, nonce: 0 });
-        println!("initial accounts {:?}", accts.keys());
-        accts
-    }));
-    
-    let app = Router::new()
-        .route("/submit_transaction", post(submit_transaction))
-        .with_state(accounts);
-   
-    let addr = SocketAddr::from(([127, 0, 0, 1], 3000));
-    println!("Listening on {}", addr);
+    let addr: SocketAddr = state.config().listen.parse().unwrap();
+    tracing::info!(%addr, "listening");
     let listener = TcpListener::bind(addr).await.unwrap();
     axum::serve(listener, app.into_make_service())
+        .with_graceful_shutdown({
+            let state = state.clone();
+            async move {
+                shutdown_signal().await;
+                state.begin_shutdown();
+            }
+        })
         .await
         .unwrap();
 
+    for state in &states {
+        final_integrity_check(state);
+    }
+
    // After starting this server, test it by sending a transaction using the following curl command in a separate terminal window
    // curl -X POST -H "Content-Type: application/json" -d '{"sender": "Alice", "receiver":"Bob", "amount":100, "nonce":0}' http://127.0.0.1:3000/submit_transaction
 
@@ -0,0 +1,3701 @@
+// The HTTP API: shared app state, middleware and one handler per route
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use axum::error_handling::HandleErrorLayer;
+use axum::{
+    routing::{get, post},
+    Json, Router,
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+};
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tower::limit::GlobalConcurrencyLimitLayer;
+use tower::load_shed::LoadShedLayer;
+use tower::ServiceBuilder;
+
+use crate::config::{Config, ConfigVars, DuplicateReceivers, Role};
+use crate::formats::{amount_format, is_zero, nonce_format, set_wire_formats, timestamp_format};
+use crate::ledger::{
+    hash_account_state, prune_inactive, state_root, total_supply, Account, AccountLock, AdminEntry, HistoryEntry, Ledger,
+    FEE_VOLUME_RETENTION_SECS,
+};
+use crate::storage::{is_transient, FileStorage, Storage};
+use crate::validation::{
+    handle_distribution, handle_multi_transfer, handle_transaction, handle_transaction_staged, lock_signing_bytes, reads_whole_ledger, max_spendable, parse_public_key,
+    signing_bytes, multi_tx_id, tx_id, verify_signature, verify_signed_bytes, Distribution, MultiTransfer, Transaction, TransactionError,
+};
+
+impl axum::response::IntoResponse for TransactionError {
+    fn into_response(self) -> axum::response::Response {
+        api_error(self.status_code(), format!("{:?}", self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct TxResponse {
+    status: String,
+    message: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    applied: Option<Box<Applied>>, // set for applied transactions and replays of them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<Box<Receipt>>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    hint: Option<RejectionHint>,
+}
+
+// Where an applied transaction ended up
+#[derive(Debug, Serialize)]
+struct Applied {
+    tx_id: String, // see tx_id()
+    seq: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    fee: u64, // charged to the sender on top of the amount
+}
+
+// Extra fields on some rejections that tell the client how to succeed next time
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(untagged)]
+enum RejectionHint {
+    Nonce(NonceHint),
+    Velocity(VelocityHint),
+}
+
+// Attached to VelocityLimitExceeded: what is left of the limit and when the rejected amount would fit
+#[derive(Debug, Clone, Copy, Serialize)]
+struct VelocityHint {
+    velocity_remaining: u64,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "timestamp_format::serialize_option")]
+    velocity_resets_at_ms: Option<u64>, // None if the amount is above the limit by itself
+}
+
+impl VelocityHint {
+    fn for_account(account: &Account, amount: u64, config: &Config, now: u64) -> Self {
+        let window_ms = config.velocity_window_secs.saturating_mul(1000);
+        let recent: Vec<&(u64, u64)> = account.outbound.iter()
+            .filter(|(at_ms, _)| at_ms.saturating_add(window_ms) > now)
+            .collect();
+        let spent: u64 = recent.iter().map(|(_, amount)| amount).sum();
+        // Walk the window oldest first until enough of it has expired to make room for the amount
+        let mut resets_at = (spent.saturating_add(amount) <= config.velocity_limit).then_some(now);
+        if resets_at.is_none() && amount <= config.velocity_limit {
+            let mut still_spent = spent;
+            for (at_ms, spent_then) in recent {
+                still_spent -= spent_then;
+                if still_spent + amount <= config.velocity_limit {
+                    resets_at = Some(at_ms + window_ms);
+                    break;
+                }
+            }
+        }
+        VelocityHint { velocity_remaining: config.velocity_limit.saturating_sub(spent), velocity_resets_at_ms: resets_at }
+    }
+}
+
+// Attached to nonce rejections so the client can resubmit with the right nonce without another round trip
+#[derive(Debug, Clone, Copy, Serialize)]
+struct NonceHint {
+    #[serde(serialize_with = "nonce_format::serialize")]
+    expected_nonce: u32, // the nonce the next transaction from the sender must carry
+}
+
+impl NonceHint {
+    fn for_account(account: &Account) -> Self {
+        // Nonces start at 0 and count the transactions sent, so the counter is the next nonce
+        NonceHint { expected_nonce: account.nonce }
+    }
+}
+
+// The hint for a rejection of `amount` from sender, if the error has one: every nonce error (with
+// TXH_NONCE_HINTS) and VelocityLimitExceeded
+fn rejection_hint(e: &TransactionError, sender: Option<&Account>, amount: u64, config: &Config, now: u64) -> Option<RejectionHint> {
+    let sender = sender?;
+    match e {
+        TransactionError::InvalidNonce | TransactionError::NonceAlreadyQueued | TransactionError::NonceExhausted
+            if config.nonce_hints =>
+        {
+            Some(RejectionHint::Nonce(NonceHint::for_account(sender)))
+        }
+        TransactionError::VelocityLimitExceeded => {
+            Some(RejectionHint::Velocity(VelocityHint::for_account(sender, amount, config, now)))
+        }
+        _ => None,
+    }
+}
+
+// Error reply for a rejected transaction, with its hint if it has one
+fn rejection(e: TransactionError, hint: Option<RejectionHint>) -> ApiError {
+    let (code, Json(mut response)) = api_error(e.status_code(), format!("{:?}", e));
+    response.hint = hint;
+    (code, Json(response))
+}
+
+// Proof that the service applied a transaction, signed with the server key (see receipt_bytes)
+#[derive(Debug, Serialize)]
+struct Receipt {
+    tx_id: String, // hex SHA-256 of the transaction's signing_bytes
+    seq: u64,
+    sender: String,
+    receiver: String,
+    amount: u64,
+    new_sender_balance: u64,
+    signature: String, // hex ed25519 signature by the server key over receipt_bytes
+}
+
+// Public view of an account's state
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct AccountView {
+    #[serde(with = "amount_format")]
+    balance: u64,
+    #[serde(with = "nonce_format")]
+    nonce: u32,
+}
+
+impl From<&Account> for AccountView {
+    fn from(account: &Account) -> Self {
+        AccountView { balance: account.balance, nonce: account.nonce }
+    }
+}
+
+// Error reply used by endpoints that report failures through the HTTP status
+type ApiError = (StatusCode, Json<TxResponse>);
+
+fn api_error(code: StatusCode, message: impl Into<String>) -> ApiError {
+    (code, Json(TxResponse { status: "error".to_string(), message: message.into(), applied: None, receipt: None, hint: None }))
+}
+
+type SharedLedger = Arc<Mutex<Ledger>>;
+
+// Locks on individual accounts, so single transfers that share an account take turns while transfers between
+// disjoint accounts run side by side, meeting only briefly on the ledger lock (see handle_transaction_staged).
+// Ids hash onto a fixed set of shards, and the shards one transfer needs are always taken in index order,
+// so A to B and B to A can't deadlock. Always taken before the ledger lock, never while holding it.
+pub struct AccountLocks {
+    shards: Vec<Mutex<()>>,
+}
+
+const ACCOUNT_LOCK_SHARDS: usize = 256;
+
+impl Default for AccountLocks {
+    fn default() -> AccountLocks {
+        AccountLocks { shards: (0..ACCOUNT_LOCK_SHARDS).map(|_| Mutex::new(())).collect() }
+    }
+}
+
+impl AccountLocks {
+    // Locks every given account until the guards drop
+    pub fn lock<'a>(&self, ids: impl IntoIterator<Item = &'a str>) -> Vec<MutexGuard<'_, ()>> {
+        let mut shards: Vec<usize> = ids.into_iter()
+            .map(|id| {
+                let mut hasher = std::hash::DefaultHasher::new();
+                std::hash::Hash::hash(id, &mut hasher);
+                std::hash::Hasher::finish(&hasher) as usize % self.shards.len()
+            })
+            .collect();
+        shards.sort_unstable();
+        shards.dedup();
+        // The guarded data is (), so a poisoned shard has nothing inconsistent behind it
+        shards.into_iter().map(|shard| self.shards[shard].lock().unwrap_or_else(|poisoned| poisoned.into_inner())).collect()
+    }
+}
+
+// Swapped as a whole on reload; handlers take a snapshot with AppState::config()
+type SharedConfig = Arc<std::sync::RwLock<Arc<Config>>>;
+
+// Source of "now" for anything time based, so it can be swapped out (e.g. a fixed clock in tests)
+pub trait Clock: Send + Sync {
+    fn now_ms(&self) -> u64;
+}
+
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now_ms(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    }
+}
+
+// When each recently applied transaction was seen, used for the time based de-dup window. `order` keeps
+// them oldest first, so expiring them only looks at the ones that fell out of the window.
+#[derive(Default)]
+struct RecentTransactions {
+    seen: HashMap<Transaction, u64>,
+    order: VecDeque<(Transaction, u64)>,
+}
+
+impl RecentTransactions {
+    fn insert(&mut self, tx: &Transaction, now: u64) {
+        self.seen.insert(tx.clone(), now);
+        self.order.push_back((tx.clone(), now));
+    }
+
+    fn expire(&mut self, now: u64, window_ms: u64) {
+        while let Some((tx, seen)) = self.order.front() {
+            if now.saturating_sub(*seen) < window_ms {
+                break;
+            }
+            // A later insert of the same transaction keeps its own, newer entry
+            if self.seen.get(tx) == Some(seen) {
+                self.seen.remove(tx);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.seen.clear();
+        self.order.clear();
+    }
+}
+
+// A transaction held until its sender's nonce catches up with it
+#[derive(Debug)]
+struct QueuedTransaction {
+    tx: Transaction,
+    queued_at_ms: u64,
+}
+
+// Queued transactions per sender, by nonce
+type Mempool = HashMap<String, BTreeMap<u32, QueuedTransaction>>;
+
+// Operational counters exposed on /metrics
+#[derive(Debug, Default)]
+struct Metrics {
+    lock_waiters: AtomicU64, // requests currently blocked on the ledger lock
+    last_lock_wait_micros: AtomicU64, // how long the most recent ledger lock acquisition waited
+    requests: AtomicU64, // every HTTP request served
+    traced_requests: AtomicU64, // requests sampled into a tracing span
+    transactions: Mutex<BTreeMap<String, u64>>, // submitted transactions by outcome code
+    value_transferred: AtomicU64, // sum of applied transaction amounts
+    latency_buckets: [AtomicU64; LATENCY_BUCKETS.len()], // per bucket, not cumulative
+    latency_micros_sum: AtomicU64,
+    latency_count: AtomicU64,
+}
+
+// Upper bounds in seconds of the transaction latency histogram buckets, +Inf aside
+const LATENCY_BUCKETS: [f64; 8] = [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05, 0.25, 1.0];
+
+impl Metrics {
+    fn count_transaction(&self, outcome: &str, applied_amount: u64) {
+        *self.transactions.lock().unwrap().entry(outcome.to_string()).or_insert(0) += 1;
+        self.value_transferred.fetch_add(applied_amount, Ordering::Relaxed);
+    }
+
+    fn count_rejection(&self, e: &TransactionError) {
+        self.count_transaction(&format!("{:?}", e), 0);
+    }
+
+    fn observe_latency(&self, elapsed: std::time::Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = LATENCY_BUCKETS.iter().position(|bound| seconds <= *bound) {
+            self.latency_buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.latency_micros_sum.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+        self.latency_count.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Critical,
+}
+
+// Something an operator should look at, active until acknowledged on /admin/alerts/:id/ack
+#[derive(Debug, Clone, Serialize)]
+struct Alert {
+    id: u64,
+    kind: &'static str, // what raised it; a repeat of an active kind bumps that alert instead of adding one
+    severity: Severity,
+    message: String,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    first_at_ms: u64,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    last_at_ms: u64,
+    count: u64, // times it was raised while active
+}
+
+#[derive(Debug, Default)]
+struct Alerts {
+    next_id: u64,
+    active: Vec<Alert>, // oldest first
+}
+
+#[derive(Clone)]
+pub struct AppState {
+    ledger: SharedLedger,
+    account_locks: Arc<AccountLocks>, // taken around single transfers, before the ledger lock
+    config: SharedConfig,
+    clock: Arc<dyn Clock>,
+    recent_txs: Arc<Mutex<RecentTransactions>>,
+    metrics: Arc<Metrics>,
+    server_key: Arc<SigningKey>,
+    storage: Option<Arc<dyn Storage>>, // where changes are committed; None keeps the ledger in memory only
+    alerts: Arc<Mutex<Alerts>>,
+    mempool: Arc<Mutex<Mempool>>, // locked after the ledger when both are needed
+    events: tokio::sync::broadcast::Sender<TxEvent>, // applied transactions, for /events subscribers
+    event_subscribers: Arc<AtomicUsize>, // open /events streams, of every tenant
+    shutdown: Arc<tokio::sync::watch::Sender<bool>>, // flips to true on Ctrl-C or SIGTERM so open streams end
+    tenant: Option<String>, // None for the default ledger, else one of TXH_TENANTS
+    #[cfg(feature = "chaos")]
+    chaos_rng: Arc<Mutex<rand::rngs::StdRng>>, // picks the requests chaos fails, seeded by TXH_CHAOS_SEED
+}
+
+// Applied transactions a slow /events subscriber can fall behind by before it gets a gap event
+const EVENT_BUFFER: usize = 1024;
+
+// An applied transaction as published on /events
+#[derive(Debug, Clone, Serialize)]
+struct TxEvent {
+    seq: u64,
+    sender: String,
+    receiver: String,
+    #[serde(with = "amount_format")]
+    amount: u64,
+    #[serde(with = "nonce_format")]
+    sender_nonce: u32, // the sender's nonce after this transaction
+}
+
+impl TxEvent {
+    fn new(tx: &Transaction, seq: u64) -> TxEvent {
+        TxEvent {
+            seq,
+            sender: tx.sender.clone(),
+            receiver: tx.receiver.clone(),
+            amount: tx.amount,
+            sender_nonce: tx.nonce + 1,
+        }
+    }
+}
+
+impl AppState {
+    pub fn new(config: Config, ledger: Ledger, server_key: SigningKey) -> AppState {
+        let storage = config.state_file_for(None)
+            .map(|path| Arc::new(FileStorage::new(&path, config.snapshot_every)) as Arc<dyn Storage>);
+        #[cfg(feature = "chaos")]
+        let chaos_rng = {
+            use rand::SeedableRng;
+            match config.chaos_seed {
+                0 => rand::rngs::StdRng::from_entropy(),
+                seed => rand::rngs::StdRng::seed_from_u64(seed),
+            }
+        };
+        AppState {
+            ledger: Arc::new(Mutex::new(ledger)),
+            account_locks: Arc::new(AccountLocks::default()),
+            config: Arc::new(std::sync::RwLock::new(Arc::new(config))),
+            clock: Arc::new(SystemClock),
+            recent_txs: Arc::new(Mutex::new(RecentTransactions::default())),
+            metrics: Arc::new(Metrics::default()),
+            server_key: Arc::new(server_key),
+            storage,
+            alerts: Arc::new(Mutex::new(Alerts::default())),
+            mempool: Arc::new(Mutex::new(HashMap::new())),
+            events: tokio::sync::broadcast::channel(EVENT_BUFFER).0,
+            event_subscribers: Arc::new(AtomicUsize::new(0)),
+            shutdown: Arc::new(tokio::sync::watch::channel(false).0),
+            tenant: None,
+            #[cfg(feature = "chaos")]
+            chaos_rng: Arc::new(Mutex::new(chaos_rng)),
+        }
+    }
+
+    // The state of one of TXH_TENANTS: its own ledger, locks, history, metrics, alerts and storage, sharing
+    // only the config, clock, server key and shutdown signal with this one
+    pub fn for_tenant(&self, tenant: &str, ledger: Ledger) -> AppState {
+        let config = self.config();
+        let storage = config.state_file_for(Some(tenant))
+            .map(|path| Arc::new(FileStorage::new(&path, config.snapshot_every)) as Arc<dyn Storage>);
+        AppState {
+            ledger: Arc::new(Mutex::new(ledger)),
+            account_locks: Arc::new(AccountLocks::default()),
+            config: self.config.clone(),
+            clock: self.clock.clone(),
+            recent_txs: Arc::new(Mutex::new(RecentTransactions::default())),
+            metrics: Arc::new(Metrics::default()),
+            server_key: self.server_key.clone(),
+            storage,
+            alerts: Arc::new(Mutex::new(Alerts::default())),
+            mempool: Arc::new(Mutex::new(HashMap::new())),
+            events: tokio::sync::broadcast::channel(EVENT_BUFFER).0,
+            event_subscribers: self.event_subscribers.clone(),
+            shutdown: self.shutdown.clone(),
+            tenant: Some(tenant.to_string()),
+            #[cfg(feature = "chaos")]
+            chaos_rng: self.chaos_rng.clone(),
+        }
+    }
+
+    // Replaces the clock, e.g. with a manually advanced one in tests
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> AppState {
+        self.clock = clock;
+        self
+    }
+
+    // Replaces the storage TXH_STATE_FILE would give, e.g. with one that fails on demand in tests
+    pub fn with_storage(mut self, storage: Arc<dyn Storage>) -> AppState {
+        self.storage = Some(storage);
+        self
+    }
+
+    // The effective config; a reload mid-request doesn't affect the snapshot already taken
+    pub fn config(&self) -> Arc<Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    // Commits the changes made since the last commit, returning once they are on disk, so call it before
+    // acknowledging them. Called with the ledger lock held so commits land in the same order as the changes.
+    // A transient failure is retried up to TXH_STORAGE_RETRIES times with doubling backoff. A commit that
+    // still fails puts the ledger back to its last saved state, raises an alert and answers 503 if the
+    // error was transient and 500 otherwise: nothing is reported done that a restart would lose.
+    fn persist(&self, ledger: &mut Ledger) -> Result<(), ApiError> {
+        let Some(storage) = &self.storage else {
+            ledger.clear_changes();
+            return Ok(());
+        };
+        let config = self.config();
+        let mut backoff_ms = config.storage_retry_backoff_ms;
+        let mut retries = 0;
+        let e = loop {
+            let e = match storage.commit(ledger) {
+                Ok(()) => return Ok(()),
+                Err(e) => e,
+            };
+            if !is_transient(&e) || retries >= config.storage_retries {
+                break e;
+            }
+            retries += 1;
+            tracing::warn!(error = %e, retry = retries, backoff_ms, "transient error saving ledger, retrying");
+            // The failed attempt may have used up the change tracking, so the retry saves the whole ledger
+            ledger.mark_rewritten();
+            std::thread::sleep(std::time::Duration::from_millis(backoff_ms));
+            backoff_ms = backoff_ms.saturating_mul(2);
+        };
+        tracing::error!(error = %e, retries, "failed to save ledger");
+        let status = if is_transient(&e) { StatusCode::SERVICE_UNAVAILABLE } else { StatusCode::INTERNAL_SERVER_ERROR };
+        self.raise_alert("save_failed", Severity::Critical, format!("Can't save the ledger: {}", e));
+        match storage.load() {
+            Ok(Some(saved)) => {
+                *ledger = saved;
+                Err(api_error(status, format!("Can't save the change, it was rolled back: {}", e)))
+            }
+            // Nothing to roll back to; the next commit retries with the whole ledger
+            Ok(None) | Err(_) => {
+                ledger.mark_rewritten();
+                Err(api_error(status, format!("Can't save the change: {}", e)))
+            }
+        }
+    }
+
+    // Publishes to /events without waiting on subscribers; called with the ledger lock held so events
+    // go out in sequence order
+    fn publish(&self, event: TxEvent) {
+        let _ = self.events.send(event); // only fails when nobody is subscribed
+    }
+
+    pub fn raise_alert(&self, kind: &'static str, severity: Severity, message: String) {
+        let now = self.clock.now_ms();
+        let mut alerts = self.alerts.lock().unwrap();
+        if let Some(alert) = alerts.active.iter_mut().find(|alert| alert.kind == kind) {
+            alert.message = message;
+            alert.last_at_ms = now;
+            alert.count += 1;
+            return;
+        }
+        alerts.next_id += 1;
+        let id = alerts.next_id;
+        alerts.active.push(Alert { id, kind, severity, message, first_at_ms: now, last_at_ms: now, count: 1 });
+    }
+
+    // Lock the ledger, keeping the contention gauges up to date.
+    // A panic while the lock was held doesn't take the service down with it: every mutation validates
+    // before it writes, so the ledger behind a poisoned lock is still consistent.
+    pub fn lock_ledger(&self) -> MutexGuard<'_, Ledger> {
+        self.metrics.lock_waiters.fetch_add(1, Ordering::Relaxed);
+        let started = Instant::now();
+        let ledger = self.ledger.lock().unwrap_or_else(|poisoned| {
+            tracing::error!("ledger lock was poisoned by a panicking request, recovering");
+            self.raise_alert("lock_poisoned", Severity::Warning, "A request panicked while holding the ledger lock".to_string());
+            self.ledger.clear_poison();
+            poisoned.into_inner()
+        });
+        self.metrics.last_lock_wait_micros.store(started.elapsed().as_micros() as u64, Ordering::Relaxed);
+        self.metrics.lock_waiters.fetch_sub(1, Ordering::Relaxed);
+        ledger
+    }
+
+    // Ends open /events streams so a graceful shutdown isn't held up by subscribers
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+}
+
+// Bytes a receipt signature covers: the raw 32 byte tx id, seq as u64 big-endian,
+// sender and receiver as u32 big-endian length plus UTF-8 bytes, then amount and new sender balance as u64 big-endian
+fn receipt_bytes(tx_id: &[u8], seq: u64, tx: &Transaction, new_sender_balance: u64) -> Vec<u8> {
+    let mut bytes = tx_id.to_vec();
+    bytes.extend_from_slice(&seq.to_be_bytes());
+    for id in [&tx.sender, &tx.receiver] {
+        bytes.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(id.as_bytes());
+    }
+    bytes.extend_from_slice(&tx.amount.to_be_bytes());
+    bytes.extend_from_slice(&new_sender_balance.to_be_bytes());
+    bytes
+}
+
+fn sign_receipt(key: &SigningKey, tx: &Transaction, seq: u64, new_sender_balance: u64) -> Receipt {
+    let id = Sha256::digest(signing_bytes(tx));
+    let signature = key.sign(&receipt_bytes(&id, seq, tx, new_sender_balance));
+    Receipt {
+        tx_id: hex::encode(id),
+        seq,
+        sender: tx.sender.clone(),
+        receiver: tx.receiver.clone(),
+        amount: tx.amount,
+        new_sender_balance,
+        signature: hex::encode(signature.to_bytes()),
+    }
+}
+
+// Background task that periodically prunes inactive, empty accounts
+pub async fn run_janitor(state: AppState) {
+    let ttl_ms = state.config().inactive_ttl_secs * 1000;
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(state.config().janitor_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        let now = state.clock.now_ms();
+        let mut ledger = state.lock_ledger();
+        let pruned = prune_inactive(&mut ledger, now, ttl_ms);
+        if !pruned.is_empty() && state.persist(&mut ledger).is_ok() {
+            tracing::info!(accounts = ?pruned, "pruned inactive accounts");
+        }
+    }
+}
+
+// Gives up on a primary that doesn't deliver its snapshot within this long
+const SYNC_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+// Downloads the snapshot of the primary at TXH_PRIMARY_URL and takes it over, unless the local ledger
+// already matches it. Returns the seq the ledger is at afterwards.
+pub async fn sync_from_primary(state: &AppState) -> Result<u64, String> {
+    use http_body_util::{BodyExt, Empty};
+    use hyper_util::client::legacy::Client;
+    use hyper_util::rt::TokioExecutor;
+
+    let config = state.config();
+    let primary = config.primary_url.as_deref().ok_or("TXH_PRIMARY_URL is not set")?;
+    let mut request = axum::http::Request::get(format!("{}/admin/snapshot?gzip=true", primary.trim_end_matches('/')));
+    if let Some(token) = &config.primary_token {
+        request = request.header("authorization", format!("Bearer {}", token.0));
+    }
+    if let Some(tenant) = &state.tenant {
+        request = request.header("x-tenant", tenant);
+    }
+    let request = request.body(Empty::<axum::body::Bytes>::new())
+        .map_err(|e| format!("Invalid TXH_PRIMARY_URL: {}", e))?;
+
+    let client = Client::builder(TokioExecutor::new()).build_http();
+    let download = async {
+        let response = client.request(request).await.map_err(|e| format!("Can't reach the primary: {}", e))?;
+        if !response.status().is_success() {
+            return Err(format!("The primary answered {} to the snapshot request", response.status()));
+        }
+        let body = response.into_body().collect().await.map_err(|e| format!("Snapshot download failed: {}", e))?;
+        Ok(body.to_bytes())
+    };
+    let bytes = tokio::time::timeout(SYNC_TIMEOUT, download).await
+        .map_err(|_| "Snapshot download timed out".to_string())??;
+    let mut snapshot = tokio::task::spawn_blocking(move || parse_snapshot(&bytes)).await
+        .map_err(|e| e.to_string())??;
+
+    let mut ledger = state.lock_ledger();
+    if snapshot.seq == ledger.seq && state_root(&snapshot.accounts) == state_root(&ledger.accounts) {
+        return Ok(ledger.seq);
+    }
+    let inherited = snapshot.adopt("primary snapshot");
+    if !inherited.is_empty() {
+        state.raise_alert("inconsistent_ledger", Severity::Critical, format!("Primary snapshot was flagged: {}", inherited.join("; ")));
+    }
+    *ledger = snapshot;
+    state.persist(&mut ledger).map_err(|(_, Json(body))| body.message)?;
+    tracing::info!(seq = ledger.seq, "synced ledger from the primary");
+    Ok(ledger.seq)
+}
+
+// Background task of a replica with TXH_PRIMARY_URL: pulls the primary's ledger every TXH_SYNC_INTERVAL_SECS.
+// Sits idle while the instance is reloaded into a primary.
+pub async fn run_replica_sync(state: AppState) {
+    let mut interval = tokio::time::interval(std::time::Duration::from_secs(state.config().sync_interval_secs.max(1)));
+    loop {
+        interval.tick().await;
+        if state.config().role != Role::Replica {
+            continue;
+        }
+        if let Err(e) = sync_from_primary(&state).await {
+            tracing::warn!(error = %e, "replica sync failed");
+            state.raise_alert("replica_sync_failed", Severity::Warning, e);
+        }
+    }
+}
+
+// Merges repeated recipients, or refuses them under TXH_DUPLICATE_RECEIVERS=reject
+fn resolve_duplicates(dist: &mut Distribution, config: &Config) -> Result<(), ApiError> {
+    let duplicate = dist.merge_duplicates()
+        .map_err(|e| api_error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:?}", e)))?;
+    if let Some(receiver) = duplicate
+        && config.duplicate_receivers == DuplicateReceivers::Reject
+    {
+        return Err(api_error(StatusCode::BAD_REQUEST, format!("DuplicateReceiver: {} is listed more than once", receiver)));
+    }
+    Ok(())
+}
+
+// Returns when an identical transaction was last applied, if that was within the de-dup window.
+// Entries older than the window are pruned as a side effect.
+fn recently_applied(recent: &mut RecentTransactions, tx: &Transaction, now: u64, window_ms: u64) -> Option<u64> {
+    recent.expire(now, window_ms);
+    recent.seen.get(tx).copied()
+}
+
+fn prune_expired(mempool: &mut Mempool, now: u64, ttl_ms: u64) {
+    for queue in mempool.values_mut() {
+        queue.retain(|_, queued| now.saturating_sub(queued.queued_at_ms) < ttl_ms);
+    }
+    mempool.retain(|_, queue| !queue.is_empty());
+}
+
+// Holds a transaction for a later nonce. The first transaction queued for a nonce wins,
+// resubmitting that same one is accepted again.
+fn enqueue(mempool: &mut Mempool, tx: &Transaction, now: u64, ttl_ms: u64) -> Result<(), TransactionError> {
+    prune_expired(mempool, now, ttl_ms);
+    let queue = mempool.entry(tx.sender.clone()).or_default();
+    match queue.get(&tx.nonce) {
+        Some(queued) if queued.tx != *tx => Err(TransactionError::NonceAlreadyQueued),
+        Some(_) => Ok(()),
+        None => {
+            queue.insert(tx.nonce, QueuedTransaction { tx: tx.clone(), queued_at_ms: now });
+            Ok(())
+        }
+    }
+}
+
+// Applies the sender's queued transactions that are next in line, in nonce order. One that fails is
+// dropped, and the ones behind it keep waiting for a replacement or their expiry. If the drained ones
+// can't be saved they go back in the queue; they were acknowledged with a 202.
+fn drain_queued(state: &AppState, ledger: &mut Ledger, sender: &str, config: &Config, now: u64) {
+    let mut mempool = state.mempool.lock().unwrap();
+    prune_expired(&mut mempool, now, config.nonce_queue_ttl_secs.saturating_mul(1000));
+    let Some(queue) = mempool.get_mut(sender) else {
+        return;
+    };
+    let mut drained = Vec::new();
+    while let Some(nonce) = ledger.accounts.get(sender).map(|account| account.nonce) {
+        queue.retain(|queued, _| *queued >= nonce);
+        let Some(queued) = queue.remove(&nonce) else {
+            break;
+        };
+        match handle_transaction(&queued.tx, ledger, config, now) {
+            Ok(()) => drained.push((queued, ledger.seq)),
+            Err(e) => tracing::info!(sender, nonce, error = ?e, "dropped queued transaction"),
+        }
+    }
+    if drained.is_empty() {
+        if queue.is_empty() {
+            mempool.remove(sender);
+        }
+        return;
+    }
+    // A failed save rolled the drained transactions back, so they wait again for the sender's next
+    // submission or their expiry
+    if state.persist(ledger).is_err() {
+        for (queued, _) in drained {
+            tracing::warn!(sender, nonce = queued.tx.nonce, "requeued a transaction that couldn't be saved");
+            queue.insert(queued.tx.nonce, queued);
+        }
+        return;
+    }
+    if queue.is_empty() {
+        mempool.remove(sender);
+    }
+    for (queued, seq) in drained {
+        record_applied(state, &queued.tx, seq, config, now);
+    }
+}
+
+// Whether the sender's queue holds its next nonce, which only happens when a drained transaction
+// couldn't be saved
+fn has_queued_next(state: &AppState, ledger: &Ledger, sender: &str) -> bool {
+    let Some(account) = ledger.accounts.get(sender) else {
+        return false;
+    };
+    state.mempool.lock().unwrap().get(sender).is_some_and(|queue| queue.contains_key(&account.nonce))
+}
+
+// Drains the queues of every account a saved change advanced the nonce of: the sender, and the receivers
+// too under TXH_BUMP_RECEIVER_NONCE
+fn drain_advanced<'a>(
+    state: &AppState,
+    ledger: &mut Ledger,
+    sender: &str,
+    receivers: impl IntoIterator<Item = &'a str>,
+    config: &Config,
+    now: u64,
+) {
+    drain_queued(state, ledger, sender, config, now);
+    if config.bump_receiver_nonce {
+        for receiver in receivers {
+            drain_queued(state, ledger, receiver, config, now);
+        }
+    }
+}
+
+// Fault injection for resilience testing (only built with `--features chaos`):
+// delays each transaction request and fails a random fraction of them with a 500
+#[cfg(feature = "chaos")]
+async fn chaos(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+    use rand::Rng;
+
+    let config = state.config();
+    if config.chaos_delay_ms > 0 {
+        tokio::time::sleep(std::time::Duration::from_millis(config.chaos_delay_ms)).await;
+    }
+    if state.chaos_rng.lock().unwrap().r#gen::<f64>() < config.chaos_error_rate {
+        let body = Json(TxResponse {
+            status: "error".to_string(),
+            message: "Injected failure (chaos)".to_string(),
+            applied: None,
+            receipt: None,
+            hint: None,
+        });
+        return (axum::http::StatusCode::INTERNAL_SERVER_ERROR, body).into_response();
+    }
+    next.run(req).await
+}
+
+// JSON fields whose values never make it into logs
+const REDACTED_FIELDS: [&str; 6] = ["signature", "public_key", "private_key", "secret", "token", "lock_token"];
+
+// Renders a body for logging, replacing sensitive JSON values; non-JSON bodies are summarized by size
+fn sanitize_body(bytes: &[u8]) -> String {
+    fn redact(value: &mut serde_json::Value) {
+        match value {
+            serde_json::Value::Object(map) => {
+                for (key, field) in map.iter_mut() {
+                    if REDACTED_FIELDS.contains(&key.as_str()) {
+                        *field = serde_json::Value::String("<redacted>".to_string());
+                    } else {
+                        redact(field);
+                    }
+                }
+            }
+            serde_json::Value::Array(items) => items.iter_mut().for_each(redact),
+            _ => {}
+        }
+    }
+
+    if bytes.is_empty() {
+        return String::new();
+    }
+    match serde_json::from_slice::<serde_json::Value>(bytes) {
+        Ok(mut value) => {
+            redact(&mut value);
+            value.to_string()
+        }
+        Err(_) => format!("<{} bytes, not JSON>", bytes.len()),
+    }
+}
+
+// Counts every request and runs a TXH_TRACE_SAMPLE_RATE fraction of them inside a `request` span
+async fn trace_requests(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use tracing::Instrument;
+
+    state.metrics.requests.fetch_add(1, Ordering::Relaxed);
+    let rate = state.config().trace_sample_rate;
+    if rate <= 0.0 || (rate < 1.0 && rand::random::<f64>() >= rate) {
+        return next.run(req).await;
+    }
+
+    state.metrics.traced_requests.fetch_add(1, Ordering::Relaxed);
+    let span = tracing::info_span!("request", method = %req.method(), uri = %req.uri());
+    async move {
+        let started = Instant::now();
+        let res = next.run(req).await;
+        tracing::debug!(status = %res.status(), elapsed_micros = started.elapsed().as_micros() as u64, "completed");
+        res
+    }
+    .instrument(span)
+    .await
+}
+
+// Debug logging of full request and response bodies, enabled with TXH_LOG_BODIES. Only bodies of a known
+// size up to MAX_LOGGED_BODY are buffered; streams (/events, snapshots) and large uploads pass through untouched
+// with just their size hint logged, so logging never holds up or rejects a request.
+async fn log_bodies(
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::body::{to_bytes, Body, HttpBody};
+    use axum::response::IntoResponse;
+
+    const MAX_LOGGED_BODY: u64 = 1024 * 1024;
+
+    // The body back, rebuilt from the buffered bytes if it was small enough to log, and what to log for it
+    async fn buffer(body: Body) -> Result<(Body, String), axum::Error> {
+        Ok(match body.size_hint().exact() {
+            Some(len) if len <= MAX_LOGGED_BODY => {
+                let bytes = to_bytes(body, len as usize).await?;
+                let logged = sanitize_body(&bytes);
+                (Body::from(bytes), logged)
+            }
+            Some(len) => (body, format!("<{} bytes, not logged>", len)),
+            None => (body, "<streamed, not logged>".to_string()),
+        })
+    }
+
+    let (parts, body) = req.into_parts();
+    let Ok((body, logged)) = buffer(body).await else {
+        return api_error(StatusCode::BAD_REQUEST, "Can't read the request body").into_response();
+    };
+    tracing::debug!(method = %parts.method, uri = %parts.uri, body = %logged, "request");
+    let res = next.run(axum::extract::Request::from_parts(parts, body)).await;
+
+    let (parts, body) = res.into_parts();
+    let Ok((body, logged)) = buffer(body).await else {
+        return api_error(StatusCode::INTERNAL_SERVER_ERROR, "Can't read the response body").into_response();
+    };
+    tracing::debug!(status = %parts.status, body = %logged, "response");
+    axum::response::Response::from_parts(parts, body)
+}
+
+// Guards every mutating route: a replica never accepts writes
+async fn reject_writes_on_replica(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    if state.config().role == Role::Replica {
+        return api_error(StatusCode::FORBIDDEN, "This instance is a read-only replica, send writes to the primary")
+            .into_response();
+    }
+    next.run(req).await
+}
+
+fn maintenance_message(until: u64) -> String {
+    format!("Writes are paused for scheduled maintenance until {} (unix ms)", until)
+}
+
+// Guards the same routes during a scheduled maintenance window, which ends by itself on the clock.
+// Admin writes are held back like any other; /admin/reload_config isn't behind it, so the window can still be moved.
+async fn reject_writes_during_maintenance(
+    State(state): State<AppState>,
+    req: axum::extract::Request,
+    next: axum::middleware::Next,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let now = state.clock.now_ms();
+    if let Some(until) = state.config().maintenance_until(now) {
+        let retry_after = until.saturating_sub(now).div_ceil(1000).to_string();
+        return (
+            [(axum::http::header::RETRY_AFTER, retry_after)],
+            api_error(StatusCode::SERVICE_UNAVAILABLE, maintenance_message(until)),
+        )
+            .into_response();
+    }
+    next.run(req).await
+}
+
+// Outcome details only the v2 response envelope exposes
+struct TxDetails {
+    code: String, // "Processed", "Duplicate" or the TransactionError variant
+    error: Option<TransactionError>, // why the transaction was rejected
+    seq: Option<u64>, // sequence assigned to an applied transaction
+    sender: Option<AccountView>, // sender state after processing
+}
+
+// Applies a submitted transaction and counts the outcome on /metrics
+fn process_transaction(state: &AppState, tx: &Transaction) -> (TxResponse, TxDetails) {
+    let started = Instant::now();
+    let (response, details) = apply_submitted(state, tx);
+    state.metrics.observe_latency(started.elapsed());
+    // Applied ones were counted by record_applied
+    if details.code != "Processed" {
+        state.metrics.count_transaction(&details.code, 0);
+    }
+    tracing::debug!(
+        sender = %tx.sender,
+        receiver = %tx.receiver,
+        amount = tx.amount,
+        outcome = %details.code,
+        "submitted transaction"
+    );
+    (response, details)
+}
+
+// Applies a submitted transaction, collapsing rapid retries, and builds the response
+fn apply_submitted(state: &AppState, tx: &Transaction) -> (TxResponse, TxDetails) {
+    let config = state.config();
+    let _accounts = state.account_locks.lock([tx.sender.as_str(), tx.receiver.as_str()]);
+    let mut ledger = state.lock_ledger();
+    let now = state.clock.now_ms();
+    let sender_view = |ledger: &Ledger| ledger.accounts.get(&tx.sender).map(AccountView::from);
+
+    // Transactions a failed save put back in the queue were there first, so they go before this one
+    if has_queued_next(state, &ledger, &tx.sender) {
+        drain_queued(state, &mut ledger, &tx.sender, &config, now);
+    }
+
+    if let Some(duplicate) = find_duplicate(state, &ledger, tx, &config, now) {
+        let response = TxResponse {
+            status: "ok".to_string(),
+            message: duplicate.message,
+            applied: duplicate.entry.as_ref()
+                .map(|entry| Box::new(Applied { tx_id: entry.tx_id.clone(), seq: entry.seq, fee: entry.fee })),
+            receipt: duplicate.entry.as_ref().filter(|_| config.signed_receipts).map(|entry| {
+                let new_sender_balance = entry.sender_balance.unwrap_or_default();
+                Box::new(sign_receipt(&state.server_key, tx, entry.seq, new_sender_balance))
+            }),
+            hint: None,
+        };
+        let details = TxDetails {
+            code: "Duplicate".to_string(),
+            error: None,
+            seq: duplicate.entry.map(|entry| entry.seq),
+            sender: sender_view(&ledger),
+        };
+        return (response, details);
+    }
+
+    let (mut ledger, mut result) = handle_transaction_staged(tx, ledger, || state.lock_ledger(), &config, now);
+
+    // A nonce a little ahead of the sender's waits for the ones before it. Only the checks before the nonce
+    // comparison have passed at this point; the timestamp, known-receiver, balance overflow and nonce
+    // exhaustion checks after it run when the transaction drains, and drop it if it fails them then.
+    if matches!(result, Err(TransactionError::InvalidNonce)) && config.nonce_queue_gap > 0 {
+        let expected = ledger.accounts.get(&tx.sender).map_or(0, |account| account.nonce);
+        if tx.nonce > expected && tx.nonce - expected <= config.nonce_queue_gap {
+            let ttl_ms = config.nonce_queue_ttl_secs.saturating_mul(1000);
+            match enqueue(&mut state.mempool.lock().unwrap(), tx, now, ttl_ms) {
+                Ok(()) => {
+                    let response = TxResponse {
+                        status: "queued".to_string(),
+                        message: format!("Queued until nonce {} from {} is applied", expected, tx.sender),
+                        applied: None,
+                        receipt: None,
+                        hint: None,
+                    };
+                    let details = TxDetails {
+                        code: "Queued".to_string(),
+                        error: None,
+                        seq: None,
+                        sender: sender_view(&ledger),
+                    };
+                    return (response, details);
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+    }
+
+    // Saved before anything is answered or published, so a failed save rolls the transaction back
+    if result.is_ok()
+        && let Err((status, _)) = state.persist(&mut ledger)
+    {
+        result = Err(if status == StatusCode::SERVICE_UNAVAILABLE {
+            TransactionError::StorageUnavailable
+        } else {
+            TransactionError::StorageFailed
+        });
+    }
+
+    let applied = result.is_ok();
+    let outcome = match result {
+        Ok(_) => (
+            TxResponse {
+                status: "ok".to_string(),
+                message: format!("Processed transaction from {} to {} for {}", tx.sender, tx.receiver, tx.amount),
+                applied: Some(Box::new(Applied {
+                    tx_id: tx_id(tx),
+                    seq: ledger.seq,
+                    fee: ledger.history.back().map_or(0, |entry| entry.fee), // just recorded by handle_transaction
+                })),
+                receipt: config.signed_receipts.then(|| {
+                    let new_sender_balance = ledger.accounts[&tx.sender].balance;
+                    Box::new(sign_receipt(&state.server_key, tx, ledger.seq, new_sender_balance))
+                }),
+                hint: None,
+            },
+            TxDetails {
+                code: "Processed".to_string(),
+                error: None,
+                seq: Some(ledger.seq),
+                sender: sender_view(&ledger),
+            },
+        ),
+        Err(e) => (
+            TxResponse {
+                status: "error".to_string(),
+                message: format!("{:?}", e),
+                applied: None,
+                receipt: None,
+                hint: rejection_hint(&e, ledger.accounts.get(&tx.sender), tx.amount, &config, now),
+            },
+            TxDetails {
+                code: format!("{:?}", e),
+                error: Some(e),
+                seq: None,
+                sender: sender_view(&ledger),
+            },
+        ),
+    };
+
+    if applied {
+        record_applied(state, tx, ledger.seq, &config, now);
+        drain_advanced(state, &mut ledger, &tx.sender, [tx.receiver.as_str()], &config, now);
+    }
+    outcome
+}
+
+// An earlier application of the same transaction
+struct Duplicate {
+    message: String,
+    entry: Option<HistoryEntry>, // the original history entry, if it was found there
+}
+
+// Finds an earlier application of tx, so a retry gets the original outcome back instead of being applied
+// twice or rejected. Rapid retries are caught by the TXH_DEDUP_WINDOW_MS window; after that, for as long as
+// the history retains it. Looked up before validation, since by now the sender may fail checks that come
+// before the nonce (funds, freeze, lock, timestamps). Only nonces the sender already used can be in its history.
+fn find_duplicate(state: &AppState, ledger: &Ledger, tx: &Transaction, config: &Config, now: u64) -> Option<Duplicate> {
+    let window_ms = config.dedup_window_ms;
+    if window_ms > 0
+        && let Some(seen) = recently_applied(&mut state.recent_txs.lock().unwrap(), tx, now, window_ms)
+    {
+        let message = format!("Duplicate of a transaction applied {}ms ago, not applied again", now - seen);
+        return Some(Duplicate { message, entry: None });
+    }
+    if ledger.accounts.get(&tx.sender).is_some_and(|account| tx.nonce < account.nonce) {
+        let id = tx_id(tx);
+        if let Some(entry) = ledger.history.iter().rev().find(|entry| entry.sender == tx.sender && entry.tx_id == id) {
+            let message = format!("Duplicate of the transaction applied at seq {}, not applied again", entry.seq);
+            return Some(Duplicate { message, entry: Some(entry.clone()) });
+        }
+    }
+    None
+}
+
+// Follow-up to a transfer that was applied and saved, whichever route it came through: the processed
+// counter and the /events feed. Called with the ledger lock held, in seq order, so events go out in order.
+fn record_transfer(state: &AppState, amount: u64, events: impl IntoIterator<Item = TxEvent>) {
+    state.metrics.count_transaction("Processed", amount);
+    events.into_iter().for_each(|event| state.publish(event));
+}
+
+// record_transfer for a single transaction, which also enters the retry dedup window
+fn record_applied(state: &AppState, tx: &Transaction, seq: u64, config: &Config, now: u64) {
+    record_transfer(state, tx.amount, [TxEvent::new(tx, seq)]);
+    if config.dedup_window_ms > 0 {
+        state.recent_txs.lock().unwrap().insert(tx, now);
+    }
+}
+
+// v2 response envelope: the v1 fields plus a machine-readable code and the resulting state
+#[derive(Debug, Serialize)]
+struct TxResponseV2 {
+    version: u8,
+    status: String,
+    code: String,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    tx_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
+    #[serde(skip_serializing_if = "is_zero")]
+    fee: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sender: Option<AccountView>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    receipt: Option<Box<Receipt>>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    hint: Option<RejectionHint>,
+}
+
+// Response envelope version from the X-API-Version header, falling back to the configured default
+fn api_version(config: &Config, headers: &HeaderMap) -> Result<u8, ApiError> {
+    match headers.get("x-api-version") {
+        None => Ok(config.api_version),
+        Some(value) => match value.to_str().ok().and_then(|v| v.trim().trim_start_matches('v').parse().ok()) {
+            Some(version @ (1 | 2)) => Ok(version),
+            _ => Err(api_error(StatusCode::BAD_REQUEST, "Unsupported X-API-Version, expected 1 or 2")),
+        },
+    }
+}
+
+async fn submit_transaction(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(tx): Json<Transaction>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let version = match api_version(&state.config(), &headers) {
+        Ok(version) => version,
+        Err(e) => return e.into_response(),
+    };
+    let (response, details) = process_transaction(&state, &tx);
+
+    let status_code = match &details.error {
+        Some(e) => e.status_code(),
+        None if details.code == "Queued" => StatusCode::ACCEPTED,
+        None => StatusCode::OK,
+    };
+    if version == 1 {
+        return (status_code, Json(response)).into_response();
+    }
+    let body = Json(TxResponseV2 {
+        version,
+        status: response.status,
+        code: details.code,
+        message: response.message,
+        fee: response.applied.as_ref().map_or(0, |applied| applied.fee),
+        tx_id: response.applied.map(|applied| applied.tx_id),
+        seq: details.seq,
+        sender: details.sender,
+        receipt: response.receipt,
+        hint: response.hint,
+    });
+    (status_code, body).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcCall {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: serde_json::Value,
+    id: Option<serde_json::Value>, // calls without an id are notifications and get no response
+}
+
+#[derive(Debug, Deserialize)]
+struct RpcAccountParams {
+    id: String,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<serde_json::Value>,
+}
+
+#[derive(Debug, Serialize)]
+struct RpcResponse {
+    jsonrpc: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<RpcError>,
+    id: serde_json::Value,
+}
+
+// JSON-RPC 2.0 error codes; -32000 and below are application defined
+const RPC_INVALID_REQUEST: i64 = -32600;
+
+const RPC_METHOD_NOT_FOUND: i64 = -32601;
+
+const RPC_INVALID_PARAMS: i64 = -32602;
+
+const RPC_ACCOUNT_NOT_FOUND: i64 = -32001;
+
+const RPC_TRANSACTION_REJECTED: i64 = -32002;
+
+const RPC_READ_ONLY: i64 = -32003;
+
+fn rpc_error(code: i64, message: impl Into<String>) -> RpcError {
+    RpcError { code, message: message.into(), data: None }
+}
+
+fn rpc_params<T: serde::de::DeserializeOwned>(params: serde_json::Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|e| rpc_error(RPC_INVALID_PARAMS, e.to_string()))
+}
+
+// Runs one JSON-RPC method against the same logic as the REST endpoints
+async fn rpc_dispatch(state: &AppState, call: RpcCall) -> Result<serde_json::Value, RpcError> {
+    let lookup = |id: &str| {
+        state.lock_ledger().accounts.get(id).map(AccountView::from)
+            .ok_or_else(|| rpc_error(RPC_ACCOUNT_NOT_FOUND, format!("Account {} not found", id)))
+    };
+
+    match call.method.as_str() {
+        "get_account" => {
+            let params: RpcAccountParams = rpc_params(call.params)?;
+            Ok(serde_json::to_value(lookup(&params.id)?).unwrap())
+        }
+        "get_nonce" => {
+            let params: RpcAccountParams = rpc_params(call.params)?;
+            Ok(serde_json::to_value(lookup(&params.id)?).unwrap()["nonce"].take())
+        }
+        "get_sequence" => Ok(serde_json::json!(state.lock_ledger().seq)),
+        "submit_transaction" => {
+            if state.config().role == Role::Replica {
+                return Err(rpc_error(RPC_READ_ONLY, "This instance is a read-only replica, send writes to the primary"));
+            }
+            if let Some(until) = state.config().maintenance_until(state.clock.now_ms()) {
+                return Err(rpc_error(RPC_READ_ONLY, maintenance_message(until)));
+            }
+            let tx: Transaction = rpc_params(call.params)?;
+            let (response, _) = process_transaction(state, &tx);
+            if response.status == "error" {
+                let mut error = rpc_error(RPC_TRANSACTION_REJECTED, response.message);
+                error.data = response.hint.map(|hint| serde_json::to_value(hint).unwrap());
+                return Err(error);
+            }
+            Ok(serde_json::to_value(response).unwrap())
+        }
+        other => Err(rpc_error(RPC_METHOD_NOT_FOUND, format!("Unknown method {}", other))),
+    }
+}
+
+// JSON-RPC 2.0 entry point accepting a single call or a batch; calls in a batch run in order
+async fn rpc(State(state): State<AppState>, Json(body): Json<serde_json::Value>) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let (calls, batch) = match body {
+        serde_json::Value::Array(calls) => (calls, true),
+        call => (vec![call], false),
+    };
+    if calls.is_empty() {
+        let error = rpc_error(RPC_INVALID_REQUEST, "Empty batch");
+        return Json(RpcResponse { jsonrpc: "2.0", result: None, error: Some(error), id: serde_json::Value::Null }).into_response();
+    }
+
+    let mut responses = Vec::with_capacity(calls.len());
+    for call in calls {
+        let call = match serde_json::from_value::<RpcCall>(call) {
+            Ok(call) if call.jsonrpc == "2.0" => call,
+            _ => {
+                let error = rpc_error(RPC_INVALID_REQUEST, "Invalid JSON-RPC 2.0 request");
+                responses.push(RpcResponse { jsonrpc: "2.0", result: None, error: Some(error), id: serde_json::Value::Null });
+                continue;
+            }
+        };
+        let id = call.id.clone();
+        let outcome = rpc_dispatch(&state, call).await;
+        if let Some(id) = id {
+            let (result, error) = match outcome {
+                Ok(result) => (Some(result), None),
+                Err(error) => (None, Some(error)),
+            };
+            responses.push(RpcResponse { jsonrpc: "2.0", result, error, id });
+        }
+    }
+
+    if responses.is_empty() {
+        StatusCode::NO_CONTENT.into_response()
+    } else if batch {
+        Json(responses).into_response()
+    } else {
+        Json(responses.remove(0)).into_response()
+    }
+}
+
+// A batch is either a bare array of transactions or an object carrying per-batch options
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BatchBody {
+    Transactions(Vec<Transaction>),
+    WithOptions(BatchRequest),
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchRequest {
+    transactions: Vec<Transaction>,
+    #[serde(default)]
+    require_existing_receivers: bool, // fail the whole batch instead of auto-creating unknown receivers
+    #[serde(default = "atomic_by_default")]
+    atomic: bool, // /submit_batch: all-or-nothing, or apply what validates and skip the rest
+    #[serde(default)]
+    isolation: Isolation, // /submit_batch: how long the ledger lock is held
+}
+
+// How much of a /submit_batch other requests may see or change while it applies
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Isolation {
+    #[default]
+    Serializable, // one lock hold for the whole batch: nothing interleaves, and atomic means the whole batch
+    ReadCommitted, // the lock is taken and released per TXH_BATCH_CHUNK_SIZE transactions: others interleave, each chunk commits on its own
+}
+
+fn atomic_by_default() -> bool {
+    true
+}
+
+// Most transactions one batch may carry
+const MAX_BATCH_SIZE: usize = 1000;
+
+impl From<BatchBody> for BatchRequest {
+    fn from(body: BatchBody) -> Self {
+        match body {
+            BatchBody::Transactions(transactions) => BatchRequest {
+                transactions,
+                require_existing_receivers: false,
+                atomic: true,
+                isolation: Isolation::Serializable,
+            },
+            BatchBody::WithOptions(request) => request,
+        }
+    }
+}
+
+impl BatchRequest {
+    fn check_size(&self) -> Result<(), ApiError> {
+        if self.transactions.len() > MAX_BATCH_SIZE {
+            return Err(api_error(
+                StatusCode::PAYLOAD_TOO_LARGE,
+                format!("Batch of {} transactions is over the limit of {}", self.transactions.len(), MAX_BATCH_SIZE),
+            ));
+        }
+        Ok(())
+    }
+
+    // The effective config for this batch, with receiver auto-creation turned off when requested
+    fn config(&self, base: &Config) -> Config {
+        let mut config = base.clone();
+        if self.require_existing_receivers {
+            config.require_known_receiver = true;
+            config.known_receivers.clear();
+        }
+        config
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchItemResult {
+    index: usize,
+    status: String,
+    message: String,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    hint: Option<RejectionHint>, // as on a single rejected transaction, from the state at this point of the batch
+}
+
+impl BatchItemResult {
+    fn processed(index: usize, tx: &Transaction) -> Self {
+        let message = format!("Processed transaction from {} to {} for {}", tx.sender, tx.receiver, tx.amount);
+        BatchItemResult { index, status: "ok".to_string(), message, hint: None }
+    }
+
+    fn rejected(index: usize, tx: &Transaction, e: &TransactionError, ledger: &Ledger, config: &Config, now: u64) -> Self {
+        let hint = rejection_hint(e, ledger.accounts.get(&tx.sender), tx.amount, config, now);
+        BatchItemResult { index, status: "error".to_string(), message: format!("{:?}", e), hint }
+    }
+
+    fn skipped(index: usize) -> Self {
+        let message = "Not applied, an earlier transaction failed".to_string();
+        BatchItemResult { index, status: "skipped".to_string(), message, hint: None }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct BatchPreview {
+    results: Vec<BatchItemResult>,
+    accounts: HashMap<String, AccountView>, // projected state of every account the batch touches
+}
+
+// Applies a batch, in order, to a copy of the ledger and reports the cumulative outcome without committing anything.
+// Rejected transactions are reported and skipped, the rest keep building on each other,
+// except that an unknown receiver fails the whole batch when require_existing_receivers is set.
+async fn preview_batch(
+    State(state): State<AppState>,
+    Json(body): Json<BatchBody>,
+) -> Result<Json<BatchPreview>, ApiError> {
+    let batch = BatchRequest::from(body);
+    batch.check_size()?;
+    let config = batch.config(&state.config());
+    let txs = &batch.transactions;
+    let mut projected = state.lock_ledger().clone();
+    let now = state.clock.now_ms();
+
+    let mut results = Vec::with_capacity(txs.len());
+    for (index, tx) in txs.iter().enumerate() {
+        let result = match handle_transaction(tx, &mut projected, &config, now) {
+            Ok(_) => BatchItemResult::processed(index, tx),
+            Err(TransactionError::UnknownReceiver) if batch.require_existing_receivers => {
+                return Err(api_error(
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    format!("Batch rejected: transaction {} sends to unknown receiver {}", index, tx.receiver),
+                ));
+            }
+            Err(e) => BatchItemResult::rejected(index, tx, &e, &projected, &config, now),
+        };
+        results.push(result);
+    }
+
+    let accounts = txs.iter()
+        .flat_map(|tx| [&tx.sender, &tx.receiver])
+        .filter_map(|id| projected.accounts.get(id).map(|account| (id.clone(), AccountView::from(account))))
+        .collect();
+
+    Ok(Json(BatchPreview { results, accounts }))
+}
+
+#[derive(Debug, Serialize)]
+struct BatchOutcome {
+    committed: bool,
+    applied: usize, // transactions that took effect
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failed_index: Option<usize>,
+    results: Vec<BatchItemResult>,
+}
+
+// Why a part of a batch wasn't committed
+enum BatchFailure {
+    Rejected(usize, StatusCode), // index of the transaction that failed the batch, and its status
+    Unsaved(ApiError), // applied but couldn't be saved, so it was rolled back
+}
+
+// Applies a batch in order, so later transactions can build on earlier ones. Serializable batches hold the
+// ledger lock throughout. Read-committed ones are cut into chunks of TXH_BATCH_CHUNK_SIZE transactions, each
+// applied under its own lock hold with a yield in between, so a large batch can't starve other requests:
+// they interleave between chunks, a chunk sees what they did, and an atomic batch is only atomic per chunk,
+// with the chunks before a failure staying committed.
+// Atomic batches apply in place behind a savepoint of the accounts they touch: on the first failure the
+// savepoint is restored, nothing more is committed and the remaining transactions are reported as skipped.
+// Non-atomic batches apply every transaction that validates and report the others, except that an unknown
+// receiver fails them as a whole too when require_existing_receivers is set. A transaction that
+// was already applied is reported as a duplicate, as on /submit_transaction, so a batch can be retried.
+async fn submit_batch(
+    State(state): State<AppState>,
+    Json(body): Json<BatchBody>,
+) -> axum::response::Response {
+    use axum::response::IntoResponse;
+
+    let batch = BatchRequest::from(body);
+    if let Err(e) = batch.check_size() {
+        return e.into_response();
+    }
+    let config = batch.config(&state.config());
+    let chunk_size = match batch.isolation {
+        Isolation::Serializable => batch.transactions.len().max(1),
+        Isolation::ReadCommitted => config.batch_chunk_size,
+    };
+
+    let mut results = Vec::with_capacity(batch.transactions.len());
+    let mut applied = 0;
+    for (chunk_index, chunk) in batch.transactions.chunks(chunk_size).enumerate() {
+        if chunk_index > 0 {
+            tokio::task::yield_now().await;
+        }
+        let offset = chunk_index * chunk_size;
+        match apply_batch_chunk(&state, &batch, offset, chunk, &config, &mut results) {
+            Ok(count) => applied += count,
+            Err(BatchFailure::Unsaved(e)) => return e.into_response(),
+            Err(BatchFailure::Rejected(index, status_code)) => {
+                results.extend((offset + chunk.len()..batch.transactions.len()).map(BatchItemResult::skipped));
+                let outcome = BatchOutcome { committed: false, applied, failed_index: Some(index), results };
+                return (status_code, Json(outcome)).into_response();
+            }
+        }
+    }
+    Json(BatchOutcome { committed: true, applied, failed_index: None, results }).into_response()
+}
+
+// Applies one chunk of a batch, starting at offset, under a single hold of the ledger lock and commits it.
+// Returns how many transactions took effect.
+fn apply_batch_chunk(
+    state: &AppState,
+    batch: &BatchRequest,
+    offset: usize,
+    chunk: &[Transaction],
+    config: &Config,
+    results: &mut Vec<BatchItemResult>,
+) -> Result<usize, BatchFailure> {
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    let savepoint = (batch.atomic || batch.require_existing_receivers).then(|| {
+        let touched = chunk.iter()
+            .flat_map(|tx| [tx.sender.as_str(), tx.receiver.as_str()])
+            .chain([config.fee_collector.as_str()]);
+        ledger.savepoint(touched, chunk.len(), config.max_history, now)
+    });
+
+    let mut failure = None;
+    let mut applied = Vec::new();
+    for (index, tx) in (offset..).zip(chunk) {
+        let result = if failure.is_some() {
+            BatchItemResult::skipped(index)
+        } else if let Some(duplicate) = find_duplicate(state, &ledger, tx, config, now) {
+            state.metrics.count_transaction("Duplicate", 0);
+            BatchItemResult { index, status: "duplicate".to_string(), message: duplicate.message, hint: None }
+        } else {
+            match handle_transaction(tx, &mut ledger, config, now) {
+                Ok(_) => {
+                    applied.push((tx, ledger.seq));
+                    BatchItemResult::processed(index, tx)
+                }
+                Err(e) => {
+                    state.metrics.count_rejection(&e);
+                    let result = BatchItemResult::rejected(index, tx, &e, &ledger, config, now);
+                    let unknown_receiver = matches!(e, TransactionError::UnknownReceiver) && batch.require_existing_receivers;
+                    if batch.atomic || unknown_receiver {
+                        failure = Some((index, e.status_code()));
+                    }
+                    result
+                }
+            }
+        };
+        results.push(result);
+    }
+
+    if let Some((index, status_code)) = failure {
+        if let Some(savepoint) = savepoint {
+            ledger.rollback_to(savepoint);
+        }
+        return Err(BatchFailure::Rejected(index, status_code));
+    }
+    if !applied.is_empty() {
+        state.persist(&mut ledger).map_err(BatchFailure::Unsaved)?;
+        for (tx, seq) in &applied {
+            record_applied(state, tx, *seq, config, now);
+        }
+        // Queued nonces drain after the whole chunk is published, so events stay in seq order
+        let base = state.config();
+        let mut advanced: Vec<&str> = applied.iter()
+            .flat_map(|(tx, _)| [Some(tx.sender.as_str()), base.bump_receiver_nonce.then_some(tx.receiver.as_str())])
+            .flatten()
+            .collect();
+        advanced.sort_unstable();
+        advanced.dedup();
+        for id in advanced {
+            drain_queued(state, &mut ledger, id, &base, now);
+        }
+    }
+    Ok(applied.len())
+}
+
+#[derive(Debug, Deserialize)]
+struct ReplayRequest {
+    transactions: Vec<Transaction>,
+    expected: HashMap<String, AccountView>, // expected final state of the accounts to check
+}
+
+#[derive(Debug, Serialize)]
+struct AccountDiff {
+    account: String,
+    expected: AccountView,
+    actual: Option<AccountView>, // None if the account doesn't exist after the replay
+}
+
+#[derive(Debug, Serialize)]
+struct ReplayReport {
+    matches: bool,
+    results: Vec<BatchItemResult>,
+    diffs: Vec<AccountDiff>,
+}
+
+// Admin regression check: replays recorded transactions on a scratch copy of the ledger
+// and compares the outcome against the expected account states. Nothing is committed.
+async fn replay(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<ReplayRequest>,
+) -> Result<Json<ReplayReport>, ApiError> {
+    let config = state.config();
+    require_admin(&config, &headers)?;
+    let mut scratch = state.lock_ledger().clone();
+    let now = state.clock.now_ms();
+
+    let results = body.transactions.iter().enumerate()
+        .map(|(index, tx)| match handle_transaction(tx, &mut scratch, &config, now) {
+            Ok(_) => BatchItemResult::processed(index, tx),
+            Err(e) => BatchItemResult::rejected(index, tx, &e, &scratch, &config, now),
+        })
+        .collect();
+
+    let mut diffs: Vec<AccountDiff> = body.expected.into_iter()
+        .filter_map(|(account, expected)| {
+            let actual = scratch.accounts.get(&account).map(AccountView::from);
+            (actual.as_ref() != Some(&expected)).then_some(AccountDiff { account, expected, actual })
+        })
+        .collect();
+    diffs.sort_by(|a, b| a.account.cmp(&b.account));
+
+    Ok(Json(ReplayReport { matches: diffs.is_empty(), results, diffs }))
+}
+
+#[derive(Debug, Deserialize)]
+struct SinceQuery {
+    seq: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountsSince {
+    latest: u64, // current global sequence, usable as the next `seq` cursor
+    accounts: Vec<String>,
+}
+
+// Lists accounts created after global sequence `seq`, oldest first, so a replica can pull only new accounts
+async fn accounts_since(
+    State(state): State<AppState>,
+    Query(query): Query<SinceQuery>,
+) -> Json<AccountsSince> {
+    let ledger = state.lock_ledger();
+
+    let mut created: Vec<(&String, u64)> = ledger.accounts.iter()
+        .filter(|(_, acct)| acct.created_seq > query.seq)
+        .map(|(id, acct)| (id, acct.created_seq))
+        .collect();
+    created.sort_by(|a, b| a.1.cmp(&b.1).then_with(|| a.0.cmp(b.0)));
+
+    Json(AccountsSince {
+        latest: ledger.seq,
+        accounts: created.into_iter().map(|(id, _)| id.clone()).collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct DeltaQuery {
+    from_seq: Option<u64>,
+    to_seq: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceDelta {
+    account: String,
+    from_seq: u64,
+    to_seq: u64,
+    delta: i128, // net received minus net sent
+}
+
+// Net balance change of an account over the transactions with from_seq < seq <= to_seq,
+// i.e. the difference between its balance at from_seq and at to_seq
+async fn account_delta(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<DeltaQuery>,
+) -> Result<Json<BalanceDelta>, ApiError> {
+    let ledger = state.lock_ledger();
+    if !ledger.accounts.contains_key(&id) {
+        return Err(api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)));
+    }
+
+    let from_seq = query.from_seq.unwrap_or(0);
+    let to_seq = query.to_seq.unwrap_or(ledger.seq);
+    if from_seq > to_seq {
+        return Err(api_error(StatusCode::BAD_REQUEST, "from_seq must not be greater than to_seq"));
+    }
+    if from_seq.saturating_add(1) < ledger.first_retained_seq() && from_seq < to_seq {
+        return Err(api_error(StatusCode::GONE, format!("History before seq {} has been evicted", ledger.first_retained_seq())));
+    }
+
+    let delta = ledger.history.iter()
+        .filter(|entry| entry.seq > from_seq && entry.seq <= to_seq)
+        .map(|entry| entry.change_for(&id))
+        .sum();
+
+    Ok(Json(BalanceDelta { account: id, from_seq, to_seq, delta }))
+}
+
+#[derive(Debug, Deserialize)]
+struct StatementQuery {
+    from: Option<u64>, // unix ms, inclusive; defaults to the beginning
+    to: Option<u64>, // unix ms, inclusive; defaults to now
+}
+
+#[derive(Debug, Serialize)]
+struct StatementLine {
+    seq: u64,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    at_ms: u64,
+    description: String,
+    amount: i128, // signed change to the balance, fees included
+    balance: i128, // running balance after this line
+}
+
+#[derive(Debug, Serialize)]
+struct Statement {
+    account: String,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    from: u64,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    to: u64,
+    opening_balance: i128,
+    lines: Vec<StatementLine>,
+    closing_balance: i128,
+}
+
+// Account statement for a time range. Balances are reconstructed backwards from the current balance
+// using the retained history, so changes made by admin balance operations aren't itemized.
+async fn account_statement(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<StatementQuery>,
+) -> Result<Json<Statement>, ApiError> {
+    let from = query.from.unwrap_or(0);
+    let to = query.to.unwrap_or_else(|| state.clock.now_ms());
+    if from > to {
+        return Err(api_error(StatusCode::BAD_REQUEST, "from must not be later than to"));
+    }
+
+    let ledger = state.lock_ledger();
+    let balance = ledger.accounts.get(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?
+        .balance;
+    let evicted = ledger.first_retained_seq() > 1;
+    if evicted && ledger.history.front().is_none_or(|oldest| oldest.at_ms >= from) {
+        return Err(api_error(StatusCode::GONE, format!("History before seq {} has been evicted", ledger.first_retained_seq())));
+    }
+
+    let touching = || ledger.history.iter().filter(|entry| entry.sender == id || entry.receiver == id);
+    let after: i128 = touching().filter(|entry| entry.at_ms > to).map(|entry| entry.change_for(&id)).sum();
+    let closing_balance = balance as i128 - after;
+
+    let in_range: Vec<&HistoryEntry> = touching().filter(|entry| entry.at_ms >= from && entry.at_ms <= to).collect();
+    let opening_balance = closing_balance - in_range.iter().map(|entry| entry.change_for(&id)).sum::<i128>();
+
+    let mut running = opening_balance;
+    let lines = in_range.into_iter()
+        .map(|entry| {
+            let amount = entry.change_for(&id);
+            running += amount;
+            let description = if entry.sender == entry.receiver {
+                "nonce bump".to_string()
+            } else if entry.sender == id && entry.fee > 0 {
+                format!("sent to {} (fee {})", entry.receiver, entry.fee)
+            } else if entry.sender == id {
+                format!("sent to {}", entry.receiver)
+            } else {
+                format!("received from {}", entry.sender)
+            };
+            StatementLine { seq: entry.seq, at_ms: entry.at_ms, description, amount, balance: running }
+        })
+        .collect();
+
+    Ok(Json(Statement { account: id, from, to, opening_balance, lines, closing_balance }))
+}
+
+// Page size bounds for history listings
+const DEFAULT_PAGE_LIMIT: usize = 100;
+
+const MAX_PAGE_LIMIT: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct BetweenQuery {
+    a: String,
+    b: String,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct HistoryPage {
+    total: usize, // matching transactions before pagination
+    transactions: Vec<HistoryEntry>,
+}
+
+// Transactions between two accounts in either direction, in sequence order
+async fn transactions_between(
+    State(state): State<AppState>,
+    Query(query): Query<BetweenQuery>,
+) -> Json<HistoryPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let ledger = state.lock_ledger();
+
+    let matching: Vec<&HistoryEntry> = ledger.history.iter()
+        .filter(|entry| {
+            (entry.sender == query.a && entry.receiver == query.b)
+                || (entry.sender == query.b && entry.receiver == query.a)
+        })
+        .collect();
+
+    Json(HistoryPage {
+        total: matching.len(),
+        transactions: matching.into_iter().skip(query.offset).take(limit).cloned().collect(),
+    })
+}
+
+#[derive(Debug, Deserialize)]
+struct LargestQuery {
+    window_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct LargestTransaction {
+    window_secs: u64,
+    transaction: Option<HistoryEntry>, // None if nothing was applied in the window
+}
+
+// The largest applied transaction of the trailing window, the earliest one on a tie.
+// Scans back from the newest history entry until the window starts.
+async fn largest_transaction(
+    State(state): State<AppState>,
+    Query(query): Query<LargestQuery>,
+) -> Result<Json<LargestTransaction>, ApiError> {
+    if query.window_secs == 0 {
+        return Err(api_error(StatusCode::BAD_REQUEST, "window_secs must be positive"));
+    }
+    let since = state.clock.now_ms().saturating_sub(query.window_secs.saturating_mul(1000));
+    let ledger = state.lock_ledger();
+    let largest = ledger.history.iter().rev()
+        .take_while(|entry| entry.at_ms >= since)
+        .fold(None, |best: Option<&HistoryEntry>, entry| match best {
+            Some(best) if best.amount > entry.amount => Some(best),
+            _ => Some(entry),
+        });
+    Ok(Json(LargestTransaction { window_secs: query.window_secs, transaction: largest.cloned() }))
+}
+
+#[derive(Debug, Deserialize)]
+struct LedgerQuery {
+    sender: Option<String>,
+    receiver: Option<String>,
+    min_amount: Option<u64>,
+    from_seq: Option<u64>, // inclusive
+    to_seq: Option<u64>, // inclusive
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+impl LedgerQuery {
+    fn matches(&self, entry: &HistoryEntry) -> bool {
+        self.sender.as_ref().is_none_or(|sender| entry.sender == *sender)
+            && self.receiver.as_ref().is_none_or(|receiver| entry.receiver == *receiver)
+            && self.min_amount.is_none_or(|min| entry.amount >= min)
+            && self.from_seq.is_none_or(|from| entry.seq >= from)
+            && self.to_seq.is_none_or(|to| entry.seq <= to)
+    }
+}
+
+// General history query: every retained transaction matching all given filters, in sequence order
+async fn ledger_history(
+    State(state): State<AppState>,
+    Query(query): Query<LedgerQuery>,
+) -> Json<HistoryPage> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let ledger = state.lock_ledger();
+
+    let matching: Vec<&HistoryEntry> = ledger.history.iter().filter(|entry| query.matches(entry)).collect();
+
+    Json(HistoryPage {
+        total: matching.len(),
+        transactions: matching.into_iter().skip(query.offset).take(limit).cloned().collect(),
+    })
+}
+
+// A single applied transaction by its tx_id, while the history still retains it
+async fn get_transaction(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<HistoryEntry>, ApiError> {
+    let id = id.to_lowercase();
+    state.lock_ledger().history.iter()
+        .find(|entry| entry.tx_id == id)
+        .map(|entry| Json(entry.clone()))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Transaction {} not found", id)))
+}
+
+#[derive(Debug, Deserialize)]
+struct PageQuery {
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+// An account's retained transactions, sent and received, newest first
+async fn account_transactions(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<PageQuery>,
+) -> Result<Json<HistoryPage>, ApiError> {
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).min(MAX_PAGE_LIMIT);
+    let ledger = state.lock_ledger();
+    if !ledger.accounts.contains_key(&id) {
+        return Err(api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)));
+    }
+
+    let matching: Vec<&HistoryEntry> = ledger.history.iter().rev()
+        .filter(|entry| entry.sender == id || entry.receiver == id)
+        .collect();
+
+    Ok(Json(HistoryPage {
+        total: matching.len(),
+        transactions: matching.into_iter().skip(query.offset).take(limit).cloned().collect(),
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct QueuedView {
+    #[serde(with = "nonce_format")]
+    nonce: u32,
+    receiver: String,
+    #[serde(with = "amount_format")]
+    amount: u64,
+    tx_id: String,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    expires_at_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct SenderMempool {
+    sender: String,
+    #[serde(with = "nonce_format")]
+    next_nonce: u32, // the nonce the queue is waiting for
+    transactions: Vec<QueuedView>, // in nonce order
+}
+
+// Transactions held for a sender until the nonces before them are applied
+async fn sender_mempool(
+    State(state): State<AppState>,
+    Path(sender): Path<String>,
+) -> Result<Json<SenderMempool>, ApiError> {
+    let config = state.config();
+    let now = state.clock.now_ms();
+    let next_nonce = state.lock_ledger().accounts.get(&sender)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", sender)))?
+        .nonce;
+
+    let ttl_ms = config.nonce_queue_ttl_secs.saturating_mul(1000);
+    let mut mempool = state.mempool.lock().unwrap();
+    prune_expired(&mut mempool, now, ttl_ms);
+    let transactions = mempool.get(&sender).into_iter().flat_map(|queue| queue.values())
+        .map(|queued| QueuedView {
+            nonce: queued.tx.nonce,
+            receiver: queued.tx.receiver.clone(),
+            amount: queued.tx.amount,
+            tx_id: tx_id(&queued.tx),
+            expires_at_ms: queued.queued_at_ms.saturating_add(ttl_ms),
+        })
+        .collect();
+
+    Ok(Json(SenderMempool { sender, next_nonce, transactions }))
+}
+
+#[derive(Debug, Serialize)]
+struct AccountRank {
+    account: String,
+    rank: usize, // 1 = richest; accounts with equal balances share a rank
+    total_accounts: usize,
+}
+
+// Leaderboard position by balance, found by counting richer accounts instead of sorting
+async fn account_rank(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<AccountRank>, ApiError> {
+    let ledger = state.lock_ledger();
+    let balance = ledger.accounts.get(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?
+        .balance;
+    let richer = ledger.accounts.values().filter(|acct| acct.balance > balance).count();
+
+    Ok(Json(AccountRank { account: id, rank: richer + 1, total_accounts: ledger.accounts.len() }))
+}
+
+async fn get_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<AccountView>, ApiError> {
+    state.lock_ledger().accounts.get(&id)
+        .map(|account| Json(AccountView::from(account)))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))
+}
+
+#[derive(Debug, Deserialize)]
+struct FeeVolumeQuery {
+    window_secs: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct FeeVolume {
+    window_secs: u64,
+    total: u64,
+}
+
+// Fees collected over the trailing window, at one-second resolution
+async fn fee_volume(
+    State(state): State<AppState>,
+    Query(query): Query<FeeVolumeQuery>,
+) -> Result<Json<FeeVolume>, ApiError> {
+    if query.window_secs == 0 || query.window_secs > FEE_VOLUME_RETENTION_SECS {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("window_secs must be between 1 and {}", FEE_VOLUME_RETENTION_SECS),
+        ));
+    }
+    let now = state.clock.now_ms();
+    let total = state.lock_ledger().fee_volume(now, query.window_secs);
+    Ok(Json(FeeVolume { window_secs: query.window_secs, total }))
+}
+
+#[derive(Debug, Serialize)]
+struct AccountSummary {
+    id: String,
+    balance: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct ListAccountsQuery {
+    prefix: Option<String>,
+}
+
+// Every account id with its balance, sorted by id, optionally only ids starting with prefix; meant for debugging
+async fn list_accounts(
+    State(state): State<AppState>,
+    Query(query): Query<ListAccountsQuery>,
+) -> Json<Vec<AccountSummary>> {
+    let ledger = state.lock_ledger();
+    let mut accounts: Vec<AccountSummary> = ledger.accounts.iter()
+        .filter(|(id, _)| query.prefix.as_ref().is_none_or(|prefix| id.starts_with(prefix.as_str())))
+        .map(|(id, account)| AccountSummary { id: id.clone(), balance: account.balance })
+        .collect();
+    accounts.sort_by(|a, b| a.id.cmp(&b.id));
+    Json(accounts)
+}
+
+#[derive(Debug, Deserialize)]
+struct CreateAccount {
+    id: String,
+    #[serde(default, with = "amount_format")]
+    balance: u64,
+    public_key: Option<String>, // registered right away, so the account is never open to unsigned transactions
+}
+
+// Opens an empty account, or with a starting balance for admins, since that adds to the supply.
+// 409 if the id is taken.
+async fn create_account(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<CreateAccount>,
+) -> Result<(StatusCode, Json<AccountSummary>), ApiError> {
+    if body.balance > 0 {
+        require_admin(&state.config(), &headers)?;
+    }
+    if body.id.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "id must not be empty"));
+    }
+    if body.public_key.as_deref().is_some_and(|key| parse_public_key(key).is_none()) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "public_key must be a hex encoded 32 byte ed25519 key"));
+    }
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    if !ledger.create_account(&body.id, body.balance, now) {
+        return Err(api_error(StatusCode::CONFLICT, format!("Account {} already exists", body.id)));
+    }
+    if body.balance > 0 {
+        ledger.record_admin(now, &body.id, "create", format!("created with balance {}", body.balance));
+    }
+    if let Some(public_key) = body.public_key {
+        let public_key = public_key.to_lowercase();
+        ledger.record_admin(now, &body.id, "set_key", format!("registered public key {}", public_key));
+        if let Some(account) = ledger.accounts.get_mut(&body.id) {
+            account.public_key = Some(public_key);
+        }
+    }
+    state.persist(&mut ledger)?;
+    Ok((StatusCode::CREATED, Json(AccountSummary { id: body.id, balance: body.balance })))
+}
+
+const MAX_COMMIT_IDS: usize = 1000;
+
+#[derive(Debug, Deserialize)]
+struct CommitRequest {
+    ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct CommittedAccount {
+    id: String,
+    exists: bool,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    state: Option<AccountView>,
+}
+
+#[derive(Debug, Serialize)]
+struct AccountsCommitment {
+    accounts: Vec<CommittedAccount>,
+    commitment: String, // hex SHA-256 over the returned set
+}
+
+// The state of each requested account plus one hash over all of them, so a light client can cache the set
+// and later tell whether anything in it changed by comparing a single value. Ids are sorted and deduplicated,
+// so the commitment doesn't depend on the order they were asked in; a missing account is part of the
+// commitment too, so it changes when the account gets created.
+async fn commit_accounts(
+    State(state): State<AppState>,
+    Json(request): Json<CommitRequest>,
+) -> Result<Json<AccountsCommitment>, ApiError> {
+    if request.ids.len() > MAX_COMMIT_IDS {
+        return Err(api_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} ids per request", MAX_COMMIT_IDS),
+        ));
+    }
+    let mut ids = request.ids;
+    ids.sort();
+    ids.dedup();
+
+    let ledger = state.lock_ledger();
+    let mut hasher = Sha256::new();
+    let accounts = ids.into_iter()
+        .map(|id| {
+            let account = ledger.accounts.get(&id);
+            hash_account_state(&mut hasher, &id, account);
+            CommittedAccount { id, exists: account.is_some(), state: account.map(AccountView::from) }
+        })
+        .collect();
+    drop(ledger);
+
+    Ok(Json(AccountsCommitment { accounts, commitment: hex::encode(hasher.finalize()) }))
+}
+
+#[derive(Debug, Deserialize)]
+struct NetSettleRequest {
+    accounts: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+struct SuggestedTransfer {
+    from: String,
+    to: String,
+    amount: u64,
+}
+
+#[derive(Debug, Serialize)]
+struct NetSettlement {
+    total: u128,
+    targets: BTreeMap<String, u64>, // balance each account ends up with
+    transfers: Vec<SuggestedTransfer>,
+}
+
+// Netting suggestion that levels the given accounts to their mean balance. When the total doesn't divide
+// evenly, the remainder goes one unit each to the first accounts by id. Nothing is executed.
+// Transfers are matched greedily, largest surplus against largest deficit, which takes at most one
+// transfer fewer than there are accounts.
+async fn net_settle(
+    State(state): State<AppState>,
+    Json(request): Json<NetSettleRequest>,
+) -> Result<Json<NetSettlement>, ApiError> {
+    if request.accounts.len() > MAX_COMMIT_IDS {
+        return Err(api_error(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("At most {} accounts per request", MAX_COMMIT_IDS),
+        ));
+    }
+    let mut ids = request.accounts;
+    ids.sort();
+    ids.dedup();
+
+    let balances: Vec<(String, u64)> = {
+        let ledger = state.lock_ledger();
+        ids.into_iter()
+            .map(|id| match ledger.accounts.get(&id) {
+                Some(account) => Ok((id, account.balance)),
+                None => Err(api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id))),
+            })
+            .collect::<Result<_, _>>()?
+    };
+    let total: u128 = balances.iter().map(|(_, balance)| *balance as u128).sum();
+    if balances.is_empty() {
+        return Ok(Json(NetSettlement { total: 0, targets: BTreeMap::new(), transfers: Vec::new() }));
+    }
+    let count = balances.len() as u128;
+    let (mean, remainder) = (total / count, total % count);
+
+    // (id, target, how far the balance is from it: positive pays, negative receives)
+    let mut surplus = Vec::new();
+    let mut deficit = Vec::new();
+    let mut targets = BTreeMap::new();
+    for (i, (id, balance)) in balances.into_iter().enumerate() {
+        let target = mean + u128::from((i as u128) < remainder);
+        let diff = balance as i128 - target as i128;
+        if diff > 0 {
+            surplus.push((id.clone(), diff as u64));
+        } else if diff < 0 {
+            deficit.push((id.clone(), diff.unsigned_abs() as u64));
+        }
+        // A target never exceeds the largest balance, so it fits in u64
+        targets.insert(id, target as u64);
+    }
+    surplus.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    deficit.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+    let mut transfers = Vec::new();
+    let (mut payers, mut payees) = (surplus.into_iter().peekable(), deficit.into_iter().peekable());
+    while let (Some(payer), Some(payee)) = (payers.peek_mut(), payees.peek_mut()) {
+        let amount = payer.1.min(payee.1);
+        transfers.push(SuggestedTransfer { from: payer.0.clone(), to: payee.0.clone(), amount });
+        payer.1 -= amount;
+        payee.1 -= amount;
+        if payer.1 == 0 {
+            payers.next();
+        }
+        if payee.1 == 0 {
+            payees.next();
+        }
+    }
+
+    Ok(Json(NetSettlement { total, targets, transfers }))
+}
+
+#[derive(Debug, Deserialize)]
+struct AvailabilityQuery {
+    receiver: Option<String>, // price the transfer for this receiver instead of a new one
+}
+
+#[derive(Debug, Serialize)]
+struct Availability {
+    account: String,
+    balance: u64,
+    frozen: bool, // frozen accounts can't send anything
+    locked: bool, // an unexpired lock: only the lock holder can send
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "timestamp_format::serialize_option")]
+    lock_expires_at_ms: Option<u64>,
+    amount_step: u64,
+    spendable: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    fee: u64, // charged on top of spendable when sending all of it
+}
+
+// How much the account can send right now in one transfer, after fees and every amount limit (see max_spendable).
+// A lock doesn't reduce the amount, but restricts who can send it.
+async fn account_available(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<AvailabilityQuery>,
+) -> Result<Json<Availability>, ApiError> {
+    let now = state.clock.now_ms();
+    let config = state.config();
+    let ledger = state.lock_ledger();
+    let account = ledger.accounts.get(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+
+    let lock = account.lock.as_ref().filter(|lock| lock.expires_at_ms > now);
+    let (spendable, fee) = max_spendable(&ledger, &id, query.receiver.as_deref(), &config, now);
+
+    Ok(Json(Availability {
+        account: id.clone(),
+        balance: account.balance,
+        frozen: account.frozen,
+        locked: lock.is_some(),
+        lock_expires_at_ms: lock.map(|lock| lock.expires_at_ms),
+        amount_step: config.amount_step,
+        spendable,
+        fee,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct ProjectedBalance {
+    account: String,
+    balance: u64,
+    projected_balance: u64, // once every queued transaction from the account has applied
+    pending: usize, // queued transactions from the account that the projection applied
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    failing_nonces: Vec<u32>, // queued transactions that would fail their remaining checks and be dropped
+}
+
+// The account's balance once the transactions it has queued under TXH_NONCE_QUEUE_GAP apply, in nonce order.
+// They are applied to a copy of the accounts involved, as if the nonces missing in between arrive first
+// and change nothing else.
+async fn account_projected(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ProjectedBalance>, ApiError> {
+    let now = state.clock.now_ms();
+    let config = state.config();
+    let ledger = state.lock_ledger();
+    let account = ledger.accounts.get(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+    let balance = account.balance;
+
+    let mut mempool = state.mempool.lock().unwrap();
+    prune_expired(&mut mempool, now, config.nonce_queue_ttl_secs.saturating_mul(1000));
+    let queued: Vec<&QueuedTransaction> = mempool.get(&id).into_iter().flat_map(|queue| queue.values()).collect();
+    let mut projection = if queued.iter().any(|queued| reads_whole_ledger(&queued.tx, &config)) {
+        ledger.clone()
+    } else {
+        ledger.stage(&id, &id, &config.fee_collector).ledger
+    };
+    let (mut pending, mut failing_nonces) = (0, Vec::new());
+    for queued in queued {
+        let tx = &queued.tx;
+        if !projection.accounts.contains_key(&tx.receiver)
+            && let Some(receiver) = ledger.accounts.get(&tx.receiver)
+        {
+            projection.accounts.insert(tx.receiver.clone(), receiver.clone());
+        }
+        projection.accounts.get_mut(&id).unwrap().nonce = tx.nonce;
+        match handle_transaction(tx, &mut projection, &config, now) {
+            Ok(()) => pending += 1,
+            Err(_) => failing_nonces.push(tx.nonce),
+        }
+    }
+
+    Ok(Json(ProjectedBalance {
+        account: id.clone(),
+        balance,
+        projected_balance: projection.accounts[&id].balance,
+        pending,
+        failing_nonces,
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct DistributionQuery {
+    buckets: Option<usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct BalanceBucket {
+    min: u64, // inclusive
+    max: u64, // inclusive
+    count: usize,
+}
+
+// Bounds on the number of histogram buckets
+const DEFAULT_BUCKETS: usize = 10;
+
+const MAX_BUCKETS: usize = 1000;
+
+// Histogram of account balances over equal-width buckets spanning 0 to the largest balance
+async fn balance_distribution(
+    State(state): State<AppState>,
+    Query(query): Query<DistributionQuery>,
+) -> Result<Json<Vec<BalanceBucket>>, ApiError> {
+    let buckets = query.buckets.unwrap_or(DEFAULT_BUCKETS);
+    if buckets == 0 || buckets > MAX_BUCKETS {
+        return Err(api_error(StatusCode::BAD_REQUEST, format!("buckets must be between 1 and {}", MAX_BUCKETS)));
+    }
+
+    let ledger = state.lock_ledger();
+    let largest = ledger.accounts.values().map(|acct| acct.balance).max().unwrap_or(0);
+    let width = (largest as u128 + 1).div_ceil(buckets as u128);
+    let mut counts = vec![0usize; buckets];
+    for account in ledger.accounts.values() {
+        counts[(account.balance as u128 / width) as usize] += 1;
+    }
+
+    Ok(Json(counts.into_iter().enumerate()
+        .map(|(i, count)| {
+            let min = i as u128 * width;
+            BalanceBucket { min: min.min(u64::MAX as u128) as u64, max: (min + width - 1).min(u64::MAX as u128) as u64, count }
+        })
+        .collect()))
+}
+
+#[derive(Debug, Serialize)]
+struct SequenceView {
+    latest: u64,
+}
+
+// The global sequence: how many transactions have been applied so far
+async fn sequence(State(state): State<AppState>) -> Json<SequenceView> {
+    Json(SequenceView { latest: state.lock_ledger().seq })
+}
+
+#[derive(Debug, Serialize)]
+struct MaintenanceWindow {
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    start_ms: u64,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    end_ms: u64,
+    active: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct ModeView {
+    role: Role,
+    maintenance: Option<MaintenanceWindow>, // the configured window, unless it is unset or already over
+    writes_accepted: bool, // whether mutating endpoints take writes right now
+    max_inflight: usize, // 0 = unlimited
+    shed_overload: bool,
+}
+
+// Summary of the operational toggles, as they apply at this moment
+async fn mode(State(state): State<AppState>) -> Json<ModeView> {
+    let config = state.config();
+    let now = state.clock.now_ms();
+    let active = config.maintenance_until(now).is_some();
+    let maintenance = (config.maintenance_end_ms > now).then(|| MaintenanceWindow {
+        start_ms: config.maintenance_start_ms,
+        end_ms: config.maintenance_end_ms,
+        active,
+    });
+    Json(ModeView {
+        role: config.role,
+        maintenance,
+        writes_accepted: config.role == Role::Primary && !active,
+        max_inflight: config.max_inflight,
+        shed_overload: config.shed_overload,
+    })
+}
+
+#[derive(Debug, Serialize)]
+struct StateRootView {
+    state_root: String,
+    seq: u64,
+}
+
+// The current state root, to pass back as expected_state_root on a conditional transaction
+async fn get_state_root(State(state): State<AppState>) -> Json<StateRootView> {
+    let ledger = state.lock_ledger();
+    Json(StateRootView { state_root: state_root(&ledger.accounts), seq: ledger.seq })
+}
+
+#[derive(Debug, Serialize)]
+struct Stats {
+    accounts: usize,
+    latest_seq: u64,
+    history_size: usize, // history entries currently held in memory
+    first_retained_seq: u64,
+    total_supply: u128,
+}
+
+async fn stats(State(state): State<AppState>) -> Json<Stats> {
+    let ledger = state.lock_ledger();
+    Json(Stats {
+        accounts: ledger.accounts.len(),
+        latest_seq: ledger.seq,
+        history_size: ledger.history.len(),
+        first_retained_seq: ledger.first_retained_seq(),
+        total_supply: total_supply(&ledger.accounts),
+    })
+}
+
+// Admin endpoints require `Authorization: Bearer <TXH_ADMIN_TOKEN>`
+fn require_admin(config: &Config, headers: &HeaderMap) -> Result<(), ApiError> {
+    let Some(token) = &config.admin_token else {
+        return Err(api_error(StatusCode::FORBIDDEN, "Admin API is disabled (TXH_ADMIN_TOKEN is not set)"));
+    };
+    let presented = headers.get("authorization")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    if presented != Some(token.0.as_str()) {
+        return Err(api_error(StatusCode::UNAUTHORIZED, "Missing or invalid admin token"));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct AccountKey {
+    public_key: String,
+}
+
+// Registers (or replaces) the ed25519 public key transactions from this account must be signed with
+async fn set_account_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    Json(body): Json<AccountKey>,
+) -> Result<Json<AccountKey>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    if parse_public_key(&body.public_key).is_none() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "public_key must be a hex encoded 32 byte ed25519 key"));
+    }
+
+    let mut ledger = state.lock_ledger();
+    let account = ledger.accounts.get_mut(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+    account.public_key = Some(body.public_key.to_lowercase());
+    ledger.record_admin(state.clock.now_ms(), &id, "set_key", format!("registered public key {}", body.public_key.to_lowercase()));
+    state.persist(&mut ledger)?;
+    Ok(Json(AccountKey { public_key: body.public_key.to_lowercase() }))
+}
+
+async fn get_account_key(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<AccountKey>, ApiError> {
+    let ledger = state.lock_ledger();
+    ledger.accounts.get(&id)
+        .and_then(|account| account.public_key.clone())
+        .map(|public_key| Json(AccountKey { public_key }))
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("No key registered for {}", id)))
+}
+
+// Admin operations that targeted the account, oldest first
+async fn admin_history(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Json<Vec<AdminEntry>> {
+    let ledger = state.lock_ledger();
+    Json(ledger.admin_log.iter().filter(|entry| entry.account == id).cloned().collect())
+}
+
+#[derive(Debug, Serialize)]
+struct KeyAccounts {
+    public_key: String,
+    accounts: Vec<String>,
+}
+
+// Accounts whose spends the given public key can sign, i.e. every account it is registered on
+async fn key_accounts(
+    State(state): State<AppState>,
+    Path(public_key): Path<String>,
+) -> Result<Json<KeyAccounts>, ApiError> {
+    if parse_public_key(&public_key).is_none() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "public_key must be a hex encoded 32 byte ed25519 key"));
+    }
+    let public_key = public_key.to_lowercase();
+
+    let ledger = state.lock_ledger();
+    let mut accounts: Vec<String> = ledger.accounts.iter()
+        .filter(|(_, account)| account.public_key.as_deref() == Some(public_key.as_str()))
+        .map(|(id, _)| id.clone())
+        .collect();
+    accounts.sort();
+    Ok(Json(KeyAccounts { public_key, accounts }))
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct LockRequest {
+    ttl_secs: Option<u64>, // capped at TXH_MAX_LOCK_TTL_SECS
+    timestamp: Option<u64>, // unix ms, signed along with the request
+    signature: Option<String>, // hex ed25519 signature over lock_signing_bytes by the account's key
+}
+
+#[derive(Debug, Serialize)]
+struct LockGrant {
+    token: String,
+    #[serde(serialize_with = "timestamp_format::serialize")]
+    expires_at_ms: u64,
+}
+
+// How far a signed lock request's timestamp may be from the server clock
+const LOCK_SIGNATURE_WINDOW_MS: u64 = 60_000;
+
+// Locks an account's outgoing transfers to whoever holds the returned token, until it is released or expires.
+// Needs the admin token or a fresh request signed by the account's registered key.
+// Fails with 409 while another unexpired lock is held; a signed request is only used up by the lock it takes.
+async fn lock_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+    body: Option<Json<LockRequest>>,
+) -> Result<Json<LockGrant>, ApiError> {
+    let config = state.config();
+    let req = body.map(|Json(req)| req).unwrap_or_default();
+    let is_admin = require_admin(&config, &headers).is_ok();
+    if req.ttl_secs == Some(0) {
+        return Err(api_error(StatusCode::BAD_REQUEST, "ttl_secs must be at least 1"));
+    }
+    let ttl_secs = req.ttl_secs.unwrap_or(config.lock_ttl_secs).min(config.max_lock_ttl_secs);
+    let now = state.clock.now_ms();
+    let expires_at_ms = ttl_secs.checked_mul(1000)
+        .and_then(|ttl_ms| now.checked_add(ttl_ms))
+        .ok_or_else(|| api_error(StatusCode::BAD_REQUEST, "ttl_secs is too large"))?;
+
+    let mut ledger = state.lock_ledger();
+    let account = ledger.accounts.get_mut(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+    if !is_admin {
+        let Some(public_key) = &account.public_key else {
+            return Err(api_error(StatusCode::UNAUTHORIZED, "Locking an account without a registered key needs the admin token"));
+        };
+        let Some(timestamp) = req.timestamp else {
+            return Err(api_error(StatusCode::UNAUTHORIZED, "A signed lock request needs a timestamp"));
+        };
+        let bytes = lock_signing_bytes(config.chain_id.as_deref(), &id, req.ttl_secs.unwrap_or(0), timestamp);
+        if verify_signed_bytes(&bytes, req.signature.as_deref(), public_key).is_err() {
+            return Err(api_error(StatusCode::UNAUTHORIZED, "Invalid lock signature"));
+        }
+        if timestamp.abs_diff(now) > LOCK_SIGNATURE_WINDOW_MS
+            || account.lock_signed_at.is_some_and(|last| timestamp <= last)
+        {
+            return Err(api_error(StatusCode::UNAUTHORIZED, "Lock request timestamp is stale or reused"));
+        }
+    }
+    if account.lock.as_ref().is_some_and(|lock| lock.expires_at_ms > now) {
+        return Err(api_error(StatusCode::CONFLICT, format!("Account {} is already locked", id)));
+    }
+    if !is_admin {
+        account.lock_signed_at = req.timestamp;
+    }
+
+    let lock = AccountLock {
+        token: hex::encode(rand::random::<[u8; 16]>()),
+        expires_at_ms,
+    };
+    let grant = LockGrant { token: lock.token.clone(), expires_at_ms: lock.expires_at_ms };
+    account.lock = Some(lock);
+    state.persist(&mut ledger)?;
+    Ok(Json(grant))
+}
+
+#[derive(Debug, Deserialize)]
+struct UnlockRequest {
+    token: String,
+}
+
+// Releases an account lock; only the token holder can release it before it expires
+async fn unlock_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Json(body): Json<UnlockRequest>,
+) -> Result<Json<TxResponse>, ApiError> {
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    let account = ledger.accounts.get_mut(&id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+
+    match &account.lock {
+        Some(lock) if lock.expires_at_ms > now && lock.token != body.token => {
+            Err(api_error(StatusCode::FORBIDDEN, "Lock token does not match"))
+        }
+        _ => {
+            account.lock = None;
+            state.persist(&mut ledger)?;
+            Ok(Json(TxResponse { status: "ok".to_string(), message: format!("Account {} unlocked", id), applied: None, receipt: None, hint: None }))
+        }
+    }
+}
+
+// Admin freeze: the account keeps receiving but can't send until unfrozen
+async fn freeze_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<TxResponse>, ApiError> {
+    set_frozen(&state, &id, &headers, true)
+}
+
+async fn unfreeze_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    headers: HeaderMap,
+) -> Result<Json<TxResponse>, ApiError> {
+    set_frozen(&state, &id, &headers, false)
+}
+
+fn set_frozen(state: &AppState, id: &str, headers: &HeaderMap, frozen: bool) -> Result<Json<TxResponse>, ApiError> {
+    require_admin(&state.config(), headers)?;
+    let mut ledger = state.lock_ledger();
+    let account = ledger.accounts.get_mut(id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)))?;
+    account.frozen = frozen;
+
+    let (action, done) = if frozen { ("freeze", "frozen") } else { ("unfreeze", "unfrozen") };
+    ledger.record_admin(state.clock.now_ms(), id, action, format!("account {}", done));
+    state.persist(&mut ledger)?;
+    Ok(Json(TxResponse { status: "ok".to_string(), message: format!("Account {} {}", id, done), applied: None, receipt: None, hint: None }))
+}
+
+#[derive(Debug, Deserialize)]
+struct QuarantineTransfer {
+    transaction: Transaction,
+    #[serde(default)]
+    force: bool, // allow crediting a receiver that is already frozen with funds in it
+}
+
+// Admin transfer into quarantine: credits the receiver and freezes it in one step under the ledger lock,
+// so the funds can't move on before someone reviews them. A receiver that is already frozen and
+// holding funds is refused unless forced, to avoid mixing new funds into a case under review.
+async fn transfer_and_freeze(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(body): Json<QuarantineTransfer>,
+) -> Result<Json<TxResponse>, axum::response::Response> {
+    use axum::response::IntoResponse;
+
+    let config = state.config();
+    require_admin(&config, &headers).map_err(IntoResponse::into_response)?;
+    let tx = &body.transaction;
+    let now = state.clock.now_ms();
+
+    let mut ledger = state.lock_ledger();
+    if !body.force && ledger.accounts.get(&tx.receiver).is_some_and(|acct| acct.frozen && acct.balance > 0) {
+        return Err(api_error(
+            StatusCode::CONFLICT,
+            format!("Receiver {} is already frozen with funds, pass \"force\": true to credit it anyway", tx.receiver),
+        ).into_response());
+    }
+    if let Err(e) = handle_transaction(tx, &mut ledger, &config, now) {
+        state.metrics.count_rejection(&e);
+        let hint = rejection_hint(&e, ledger.accounts.get(&tx.sender), tx.amount, &config, now);
+        return Err(rejection(e, hint).into_response());
+    }
+
+    if let Some(receiver) = ledger.accounts.get_mut(&tx.receiver) {
+        receiver.frozen = true;
+    }
+    ledger.record_admin(now, &tx.receiver, "freeze", format!("frozen on receiving {} from {}", tx.amount, tx.sender));
+    state.persist(&mut ledger).map_err(IntoResponse::into_response)?;
+    record_applied(&state, tx, ledger.seq, &config, now);
+    drain_advanced(&state, &mut ledger, &tx.sender, [tx.receiver.as_str()], &config, now);
+
+    Ok(Json(TxResponse {
+        status: "ok".to_string(),
+        message: format!("Processed transaction from {} to {} for {}, {} is frozen", tx.sender, tx.receiver, tx.amount, tx.receiver),
+        applied: None,
+        receipt: None,
+        hint: None,
+    }))
+}
+
+#[derive(Debug, Serialize)]
+struct SignatureCheck {
+    valid: bool,
+    message: String,
+}
+
+// Checks a transaction's signature against the sender's registered key without applying anything,
+// so client developers can debug their signing code
+async fn check_signature(
+    State(state): State<AppState>,
+    Json(tx): Json<Transaction>,
+) -> Result<Json<SignatureCheck>, ApiError> {
+    let ledger = state.lock_ledger();
+    let account = ledger.accounts.get(&tx.sender)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("Account {} not found", tx.sender)))?;
+    let Some(public_key) = &account.public_key else {
+        return Ok(Json(SignatureCheck { valid: false, message: format!("No key registered for {}", tx.sender) }));
+    };
+
+    let check = match verify_signature(&tx, public_key) {
+        Ok(()) => SignatureCheck { valid: true, message: "Signature matches the registered key".to_string() },
+        Err(_) => SignatureCheck {
+            valid: false,
+            message: "Signature is missing, malformed or not made by the registered key".to_string(),
+        },
+    };
+    Ok(Json(check))
+}
+
+#[derive(Debug, Serialize)]
+struct MultiTransferOutcome {
+    status: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    message: Option<String>, // set when this is a retry of a multi transfer already applied
+    tx_id: String, // see multi_tx_id()
+    seq: u64, // shared by every output
+    #[serde(skip_serializing_if = "is_zero")]
+    fee: u64, // total charged to the sender on top of the amounts
+    sender: AccountView, // after the transfer
+    balances: Vec<AccountSummary>, // each receiver's resulting balance, in output order
+}
+
+impl MultiTransferOutcome {
+    // Outcome of the multi transfer applied at seq, with the sender's and receivers' balances as they are now
+    fn new(ledger: &Ledger, multi: &MultiTransfer, dist: &Distribution, seq: u64, message: Option<String>) -> Self {
+        MultiTransferOutcome {
+            status: "ok".to_string(),
+            message,
+            tx_id: multi_tx_id(multi),
+            seq,
+            fee: ledger.history.iter().rev().filter(|entry| entry.seq == seq).map(|entry| entry.fee).sum(),
+            sender: AccountView::from(&ledger.accounts[&multi.sender]),
+            balances: dist.recipients.iter()
+                .map(|(id, _)| AccountSummary { id: id.clone(), balance: ledger.accounts.get(id).map_or(0, |acct| acct.balance) })
+                .collect(),
+        }
+    }
+}
+
+// Pays several receivers from one sender in a single all-or-nothing step that consumes one nonce.
+// A retry of one already applied is recognised by its tx_id while the history retains it, and answered
+// with the original seq instead of failing on the spent nonce.
+async fn submit_multi(
+    State(state): State<AppState>,
+    Json(multi): Json<MultiTransfer>,
+) -> Result<Json<MultiTransferOutcome>, ApiError> {
+    let config = state.config();
+    if multi.outputs.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "outputs must not be empty"));
+    }
+    if multi.outputs.len() > config.max_outputs {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Too many outputs: {} (at most {})", multi.outputs.len(), config.max_outputs),
+        ));
+    }
+    let mut dist = Distribution { from: multi.sender.clone(), recipients: multi.outputs.clone() };
+    resolve_duplicates(&mut dist, &config)?;
+
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    if ledger.accounts.get(&multi.sender).is_some_and(|account| multi.nonce < account.nonce) {
+        let id = multi_tx_id(&multi);
+        if let Some(entry) = ledger.history.iter().rev().find(|entry| entry.sender == multi.sender && entry.tx_id == id) {
+            state.metrics.count_transaction("Duplicate", 0);
+            let message = format!("Duplicate of the multi transfer applied at seq {}, not applied again", entry.seq);
+            return Ok(Json(MultiTransferOutcome::new(&ledger, &multi, &dist, entry.seq, Some(message))));
+        }
+    }
+    if let Err(e) = handle_multi_transfer(&multi, &dist, &mut ledger, &config, now) {
+        state.metrics.count_rejection(&e);
+        let total = dist.recipients.iter().fold(0u64, |total, (_, amount)| total.saturating_add(*amount));
+        let hint = rejection_hint(&e, ledger.accounts.get(&multi.sender), total, &config, now);
+        return Err(rejection(e, hint));
+    }
+    state.persist(&mut ledger)?;
+
+    let seq = ledger.seq;
+    let events = dist.recipients.iter().map(|(receiver, amount)| TxEvent {
+        seq,
+        sender: multi.sender.clone(),
+        receiver: receiver.clone(),
+        amount: *amount,
+        sender_nonce: multi.nonce + 1,
+    });
+    record_transfer(&state, dist.recipients.iter().map(|(_, amount)| amount).sum(), events);
+    let outcome = MultiTransferOutcome::new(&ledger, &multi, &dist, seq, None);
+    let receivers = dist.recipients.iter().map(|(id, _)| id.as_str());
+    drain_advanced(&state, &mut ledger, &multi.sender, receivers, &config, now);
+    Ok(Json(outcome))
+}
+
+// Admin airdrop: pays every recipient from one source in a single all-or-nothing step
+async fn distribute(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(mut dist): Json<Distribution>,
+) -> Result<Json<TxResponse>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    if dist.recipients.is_empty() {
+        return Err(api_error(StatusCode::BAD_REQUEST, "recipients must not be empty"));
+    }
+    let max_outputs = state.config().max_outputs;
+    if dist.recipients.len() > max_outputs {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            format!("Too many recipients: {} (at most {})", dist.recipients.len(), max_outputs),
+        ));
+    }
+    resolve_duplicates(&mut dist, &state.config())?;
+
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    match handle_distribution(&dist, &mut ledger, &state.config(), now, false) {
+        Ok(()) => {
+            let total: u64 = dist.recipients.iter().map(|(_, amount)| amount).sum();
+            ledger.record_admin(now, &dist.from, "distribute", format!("sent {} to {} recipients", total, dist.recipients.len()));
+            for (receiver, amount) in &dist.recipients {
+                ledger.record_admin(now, receiver, "distribute", format!("received {} from {}", amount, dist.from));
+            }
+            state.persist(&mut ledger)?;
+            record_transfer(&state, total, []); // distributions aren't published on /events
+            let receivers = dist.recipients.iter().map(|(id, _)| id.as_str());
+            drain_advanced(&state, &mut ledger, &dist.from, receivers, &state.config(), now);
+            Ok(Json(TxResponse {
+                status: "ok".to_string(),
+                message: format!("Distributed from {} to {} recipients", dist.from, dist.recipients.len()),
+                applied: None,
+                receipt: None,
+                hint: None,
+            }))
+        }
+        Err(e) => {
+            state.metrics.count_rejection(&e);
+            match e {
+                TransactionError::AccountNotFound => Err(api_error(StatusCode::NOT_FOUND, format!("Account {} not found", dist.from))),
+                e => Err(api_error(StatusCode::UNPROCESSABLE_ENTITY, format!("{:?}", e))),
+            }
+        }
+    }
+}
+
+// Test fixture helper: overwrites the given balances in one step, creating missing accounts.
+// Nonces and everything else about existing accounts are left alone.
+async fn set_balances(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(balances): Json<HashMap<String, u64>>,
+) -> Result<Json<HashMap<String, AccountView>>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    let now = state.clock.now_ms();
+
+    let mut ledger = state.lock_ledger();
+    let mut updated = HashMap::with_capacity(balances.len());
+    for (id, balance) in balances {
+        // A missing account is created like POST /accounts would, taking the next seq
+        ledger.create_account(&id, 0, now);
+        let account = ledger.accounts.get_mut(&id).unwrap();
+        let previous = account.balance;
+        account.balance = balance;
+        account.last_active_ms = now;
+        updated.insert(id.clone(), AccountView::from(&*account));
+        if let Some(expected) = &mut ledger.expected_supply {
+            *expected = (*expected + balance as u128).saturating_sub(previous as u128);
+        }
+        ledger.record_admin(now, &id, "set_balance", format!("balance set from {} to {}", previous, balance));
+    }
+    state.persist(&mut ledger)?;
+    Ok(Json(updated))
+}
+
+#[derive(Debug, Serialize)]
+struct ReloadedConfig {
+    config: Config, // the effective config after the reload, secrets redacted
+    ignored: Vec<&'static str>, // changed settings that only take effect on restart
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    overridden_by_env: Vec<String>, // config file settings the environment sets to another value, so the file's is unused
+}
+
+// Settings wired into the router or background tasks at startup. A reload keeps their current
+// values and returns the names of the ones that changed.
+fn keep_startup_settings(new: &mut Config, current: &Config) -> Vec<&'static str> {
+    let mut ignored = Vec::new();
+    macro_rules! keep {
+        ($field:ident, $var:literal) => {
+            if new.$field != current.$field {
+                new.$field = current.$field.clone();
+                ignored.push($var);
+            }
+        };
+    }
+    keep!(max_inflight, "TXH_MAX_INFLIGHT");
+    keep!(shed_overload, "TXH_SHED_OVERLOAD");
+    keep!(log_bodies, "TXH_LOG_BODIES");
+    keep!(inactive_ttl_secs, "TXH_INACTIVE_TTL");
+    keep!(janitor_interval_secs, "TXH_JANITOR_INTERVAL_SECS");
+    keep!(server_key, "TXH_SERVER_KEY");
+    keep!(state_file, "TXH_STATE_FILE");
+    keep!(genesis_file, "TXH_GENESIS_FILE");
+    keep!(genesis, "genesis");
+    keep!(tenants, "TXH_TENANTS");
+    keep!(listen, "TXH_LISTEN");
+    keep!(snapshot_every, "TXH_SNAPSHOT_EVERY");
+    keep!(primary_url, "TXH_PRIMARY_URL");
+    keep!(sync_interval_secs, "TXH_SYNC_INTERVAL_SECS");
+    #[cfg(feature = "chaos")]
+    keep!(chaos_seed, "TXH_CHAOS_SEED");
+    ignored
+}
+
+// Re-reads TXH_CONFIG_FILE and the environment, which wins over the file, and swaps in the new config for
+// subsequent requests
+async fn reload_config(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+) -> Result<Json<ReloadedConfig>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    let vars = ConfigVars::load()
+        .map_err(|e| api_error(StatusCode::UNPROCESSABLE_ENTITY, format!("Config not reloaded: {}", e)))?;
+    let mut config = Config::from_vars(&vars)
+        .and_then(|config| config.check_fee_collector(&state.lock_ledger().accounts).map(|_| config))
+        .map_err(|e| api_error(StatusCode::UNPROCESSABLE_ENTITY, format!("Config not reloaded: {}", e)))?;
+    let overridden_by_env = vars.env_overrides().to_vec();
+
+    let mut current = state.config.write().unwrap();
+    let ignored = keep_startup_settings(&mut config, &current);
+    set_wire_formats(&config);
+    *current = Arc::new(config.clone());
+    drop(current);
+
+    if !overridden_by_env.is_empty() {
+        tracing::warn!(settings = ?overridden_by_env, "config file settings are overridden by the environment");
+    }
+    tracing::info!(?ignored, "config reloaded");
+    Ok(Json(ReloadedConfig { config, ignored, overridden_by_env }))
+}
+
+// Upper bound on an uploaded snapshot
+const MAX_SNAPSHOT_BYTES: usize = 256 * 1024 * 1024;
+
+#[derive(Debug, Deserialize)]
+struct SnapshotQuery {
+    #[serde(default)]
+    gzip: bool,
+}
+
+// io::Write adapter that forwards written chunks to an async response body
+struct ChannelWriter(tokio::sync::mpsc::Sender<Result<axum::body::Bytes, std::io::Error>>);
+
+impl std::io::Write for ChannelWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.blocking_send(Ok(axum::body::Bytes::copy_from_slice(buf)))
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::BrokenPipe, "snapshot download aborted"))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Admin backup of the full ledger as JSON, or gzipped JSON with ?gzip=true.
+// The ledger is copied under the lock, then serialized (and compressed) in chunks straight into the response.
+async fn snapshot(
+    State(state): State<AppState>,
+    Query(query): Query<SnapshotQuery>,
+    headers: HeaderMap,
+) -> Result<axum::response::Response, ApiError> {
+    use axum::response::IntoResponse;
+    use std::io::Write;
+
+    require_admin(&state.config(), &headers)?;
+    let ledger = state.lock_ledger().clone();
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel(16);
+    let gzip = query.gzip;
+    tokio::task::spawn_blocking(move || {
+        let writer = std::io::BufWriter::with_capacity(64 * 1024, ChannelWriter(tx.clone()));
+        let result = if gzip {
+            let mut encoder = flate2::write::GzEncoder::new(writer, flate2::Compression::default());
+            serde_json::to_writer(&mut encoder, &ledger).map_err(std::io::Error::from)
+                .and_then(|_| encoder.finish()?.flush())
+        } else {
+            let mut writer = writer;
+            serde_json::to_writer(&mut writer, &ledger).map_err(std::io::Error::from)
+                .and_then(|_| writer.flush())
+        };
+        if let Err(e) = result {
+            let _ = tx.blocking_send(Err(e));
+        }
+    });
+
+    let stream = futures_util::stream::poll_fn(move |cx| rx.poll_recv(cx));
+    let content_type = if gzip { "application/gzip" } else { "application/json" };
+    Ok(([(axum::http::header::CONTENT_TYPE, content_type)], axum::body::Body::from_stream(stream)).into_response())
+}
+
+#[derive(Debug, Deserialize)]
+struct EventsQuery {
+    account: Option<String>, // only transactions this account sent or received
+}
+
+// One of the TXH_MAX_EVENT_SUBSCRIBERS places, held for as long as its /events stream is open
+struct SubscriberSlot(Arc<AtomicUsize>);
+
+impl SubscriberSlot {
+    fn take(open: &Arc<AtomicUsize>, max: usize) -> Option<SubscriberSlot> {
+        open.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| (max == 0 || n < max).then_some(n + 1)).ok()?;
+        Some(SubscriberSlot(open.clone()))
+    }
+}
+
+impl Drop for SubscriberSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Server-sent events for applied transactions: a `transaction` event per transaction, in sequence order,
+// and a `gap` event with the number of missed transactions when the subscriber fell too far behind.
+// Streams end on shutdown so they don't hold up the graceful stop. Beyond TXH_MAX_EVENT_SUBSCRIBERS open
+// streams, new ones are answered with 503 until one closes.
+async fn events(
+    State(state): State<AppState>,
+    Query(query): Query<EventsQuery>,
+) -> Result<axum::response::Sse<impl futures_util::Stream<Item = Result<axum::response::sse::Event, axum::Error>>>, ApiError> {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+    use tokio::sync::broadcast::error::RecvError;
+
+    let slot = SubscriberSlot::take(&state.event_subscribers, state.config().max_event_subscribers)
+        .ok_or_else(|| api_error(StatusCode::SERVICE_UNAVAILABLE, "Too many /events subscribers, retry later"))?;
+    let subscription = (state.events.subscribe(), state.shutdown.subscribe(), query.account, slot);
+    let stream = futures_util::stream::unfold(subscription, |(mut events, mut shutdown, account, slot)| async move {
+        loop {
+            let received = tokio::select! {
+                received = events.recv() => received,
+                _ = shutdown.wait_for(|stopping| *stopping) => return None,
+            };
+            let event = match received {
+                Ok(event) if account.as_ref().is_some_and(|id| event.sender != *id && event.receiver != *id) => continue,
+                Ok(event) => Event::default().event("transaction").json_data(&event),
+                Err(RecvError::Lagged(missed)) => Event::default().event("gap").json_data(serde_json::json!({ "missed": missed })),
+                Err(RecvError::Closed) => return None,
+            };
+            return Some((event, (events, shutdown, account, slot)));
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::default()))
+}
+
+// Admin view of the alerts nobody has acknowledged yet, oldest first
+async fn list_alerts(State(state): State<AppState>, headers: HeaderMap) -> Result<Json<Vec<Alert>>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    Ok(Json(state.alerts.lock().unwrap().active.clone()))
+}
+
+// Acknowledging an alert clears it; if the condition comes back it is raised again under a new id
+async fn ack_alert(
+    State(state): State<AppState>,
+    Path(id): Path<u64>,
+    headers: HeaderMap,
+) -> Result<Json<Alert>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    let mut alerts = state.alerts.lock().unwrap();
+    let index = alerts.active.iter().position(|alert| alert.id == id)
+        .ok_or_else(|| api_error(StatusCode::NOT_FOUND, format!("No active alert {}", id)))?;
+    Ok(Json(alerts.active.remove(index)))
+}
+
+// A snapshot as written by /admin/snapshot, plain or gzipped (detected from the gzip magic bytes)
+fn parse_snapshot(bytes: &[u8]) -> Result<Ledger, String> {
+    if bytes.starts_with(&[0x1f, 0x8b]) {
+        serde_json::from_reader(flate2::read::GzDecoder::new(bytes))
+    } else {
+        serde_json::from_slice(bytes)
+    }
+    .map_err(|e| format!("Invalid snapshot: {}", e))
+}
+
+// Admin restore from a snapshot, plain or gzipped. Replaces the whole ledger.
+async fn restore(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> Result<Json<Stats>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+
+    let mut restored = parse_snapshot(&body).map_err(|e| api_error(StatusCode::BAD_REQUEST, e))?;
+    let inherited = restored.adopt("snapshot");
+    if !inherited.is_empty() {
+        state.raise_alert("inconsistent_ledger", Severity::Critical, format!("Restored snapshot was flagged: {}", inherited.join("; ")));
+    }
+
+    let mut ledger = state.lock_ledger();
+    *ledger = restored;
+    state.persist(&mut ledger)?;
+    Ok(Json(Stats {
+        accounts: ledger.accounts.len(),
+        latest_seq: ledger.seq,
+        history_size: ledger.history.len(),
+        first_retained_seq: ledger.first_retained_seq(),
+        total_supply: total_supply(&ledger.accounts),
+    }))
+}
+
+// Reverses every transaction after to_seq, newest first, on a copy of the ledger. Needs the history of
+// those transactions, and fails if an admin balance change (set_balances, rename) happened after to_seq,
+// since those aren't in the history. Receiver nonces and fees are unwound with the current
+// TXH_BUMP_RECEIVER_NONCE, TXH_FEE_COLLECTOR and TXH_FEE_BURN_BPS. Locks, freezes and keys are left as they are.
+fn rollback_ledger(ledger: &Ledger, to_seq: u64, config: &Config) -> Result<Ledger, String> {
+    if to_seq > ledger.seq {
+        return Err(format!("seq {} is ahead of the current seq {}", to_seq, ledger.seq));
+    }
+    if ledger.first_retained_seq() > to_seq + 1 {
+        return Err(format!(
+            "history only goes back to seq {}, can't roll back to {}",
+            ledger.first_retained_seq(), to_seq
+        ));
+    }
+    if let Some(admin) = ledger.admin_log.iter()
+        .find(|entry| entry.seq > to_seq && matches!(entry.action.as_str(), "set_balance" | "rename" | "create" | "prune"))
+    {
+        return Err(format!("{} of {} at seq {} isn't reversible", admin.action, admin.account, admin.seq));
+    }
+
+    let mut rolled = ledger.clone();
+    rolled.mark_rewritten(); // history entries come back out, which a log record can't express
+    let broken = |entry: &HistoryEntry| format!("history doesn't reverse cleanly at seq {}", entry.seq);
+    while let Some(seq) = rolled.history.back().map(|entry| entry.seq).filter(|seq| *seq > to_seq) {
+        // A distribution or multi transfer records one entry per recipient under a single seq and nonce
+        let mut legs = Vec::new();
+        while rolled.history.back().is_some_and(|entry| entry.seq == seq) {
+            legs.push(rolled.history.pop_back().unwrap());
+        }
+        let accts = &mut rolled.accounts;
+        for entry in &legs {
+            let receiver = accts.entry(entry.receiver.clone()).or_default();
+            receiver.balance = receiver.balance.checked_sub(entry.amount).ok_or_else(|| broken(entry))?;
+            if config.bump_receiver_nonce && entry.sender != entry.receiver {
+                receiver.nonce = receiver.nonce.checked_sub(1).ok_or_else(|| broken(entry))?;
+            }
+            if entry.fee > 0 {
+                let burned = config.fee_burn(entry.fee);
+                let collector = accts.entry(config.fee_collector.clone()).or_default();
+                collector.balance = collector.balance.checked_sub(entry.fee - burned).ok_or_else(|| broken(entry))?;
+                if let Some(expected) = &mut rolled.expected_supply {
+                    *expected += burned as u128;
+                }
+                if let Some((_, total)) = rolled.fee_buckets.iter_mut().find(|(second, _)| *second == entry.at_ms / 1000) {
+                    *total = total.saturating_sub(entry.fee);
+                }
+            }
+            let sender = accts.get_mut(&entry.sender).ok_or_else(|| broken(entry))?;
+            sender.balance = sender.balance.checked_add(entry.amount + entry.fee).ok_or_else(|| broken(entry))?;
+        }
+        let first = &legs[0];
+        let sender = accts.get_mut(&first.sender).ok_or_else(|| broken(first))?;
+        sender.nonce = sender.nonce.checked_sub(1).ok_or_else(|| broken(first))?;
+        // A submitted transfer added one velocity entry for its whole amount; admin distributions add none
+        let total: u64 = legs.iter().map(|entry| entry.amount).sum();
+        if !first.tx_id.is_empty() && sender.outbound.back() == Some(&(first.at_ms, total)) {
+            sender.outbound.pop_back();
+        }
+    }
+    // Accounts the undone transactions created go away again, as long as nothing else put funds there
+    rolled.accounts.retain(|_, account| account.created_seq <= to_seq || account.balance > 0);
+    rolled.admin_log.retain(|entry| entry.seq <= to_seq || entry.action != "distribute");
+    rolled.seq = to_seq;
+    Ok(rolled)
+}
+
+#[derive(Debug, Deserialize)]
+struct RollbackRequest {
+    to_seq: u64,
+    #[serde(default)]
+    confirm: bool,
+}
+
+// Admin rollback to the state right after transaction to_seq, for dev and test environments.
+// Destructive, so the request has to say confirm: true.
+async fn rollback(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(request): Json<RollbackRequest>,
+) -> Result<Json<Stats>, ApiError> {
+    let config = state.config();
+    require_admin(&config, &headers)?;
+    if !request.confirm {
+        return Err(api_error(
+            StatusCode::BAD_REQUEST,
+            "Rollback discards every transaction after to_seq, resend with \"confirm\": true",
+        ));
+    }
+
+    let mut ledger = state.lock_ledger();
+    let rolled = rollback_ledger(&ledger, request.to_seq, &config)
+        .map_err(|e| api_error(StatusCode::CONFLICT, format!("Can't roll back: {}", e)))?;
+    *ledger = rolled;
+    state.persist(&mut ledger)?;
+    // Undone transactions may be submitted again and applied anew
+    state.recent_txs.lock().unwrap().clear();
+    Ok(Json(Stats {
+        accounts: ledger.accounts.len(),
+        latest_seq: ledger.seq,
+        history_size: ledger.history.len(),
+        first_retained_seq: ledger.first_retained_seq(),
+        total_supply: total_supply(&ledger.accounts),
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameRequest {
+    new_id: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct RenameQuery {
+    #[serde(default)]
+    merge: bool,
+}
+
+#[derive(Debug, Serialize)]
+struct RenamedAccount {
+    id: String,
+    balance: u64,
+    #[serde(with = "nonce_format")]
+    nonce: u32,
+    merged: bool,
+}
+
+// Moves an account to a new id. If the new id is taken the rename fails with 409, unless ?merge=true
+// folds the account into the existing one: balances are summed, the higher nonce wins and recent sends
+// are combined for the velocity limit. Frozen or locked accounts can't be merged. Past history entries keep
+// the old id.
+async fn rename_account(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+    Query(query): Query<RenameQuery>,
+    headers: HeaderMap,
+    Json(body): Json<RenameRequest>,
+) -> Result<Json<RenamedAccount>, ApiError> {
+    require_admin(&state.config(), &headers)?;
+    if body.new_id.is_empty() || body.new_id == id {
+        return Err(api_error(StatusCode::BAD_REQUEST, "new_id must be non-empty and differ from the current id"));
+    }
+
+    let now = state.clock.now_ms();
+    let mut ledger = state.lock_ledger();
+    let accts = &mut ledger.accounts;
+    let Some(source) = accts.get(&id).cloned() else {
+        return Err(api_error(StatusCode::NOT_FOUND, format!("Account {} not found", id)));
+    };
+
+    let merged = match accts.get(&body.new_id) {
+        None => {
+            accts.insert(body.new_id.clone(), source);
+            false
+        }
+        Some(_) if !query.merge => {
+            return Err(api_error(StatusCode::CONFLICT, format!("Account {} already exists, pass ?merge=true to merge into it", body.new_id)));
+        }
+        Some(target) => {
+            // Merging would move funds out from under a freeze or someone else's lock
+            for (side, account) in [(&id, &source), (&body.new_id, target)] {
+                if account.frozen {
+                    return Err(api_error(StatusCode::CONFLICT, format!("Account {} is frozen and can't be merged", side)));
+                }
+                if account.lock.as_ref().is_some_and(|lock| lock.expires_at_ms > now) {
+                    return Err(api_error(StatusCode::CONFLICT, format!("Account {} is locked and can't be merged", side)));
+                }
+            }
+            let balance = target.balance.checked_add(source.balance)
+                .ok_or_else(|| api_error(StatusCode::UNPROCESSABLE_ENTITY, "Merged balance would overflow"))?;
+            let target = accts.get_mut(&body.new_id).unwrap();
+            target.balance = balance;
+            target.nonce = target.nonce.max(source.nonce);
+            target.created_seq = target.created_seq.min(source.created_seq);
+            if target.public_key.is_none() {
+                target.public_key = source.public_key;
+            }
+            // The source's recent sends still count against the merged account's velocity limit
+            let mut outbound: Vec<(u64, u64)> = target.outbound.drain(..).chain(source.outbound).collect();
+            outbound.sort_by_key(|(at_ms, _)| *at_ms);
+            target.outbound = outbound.into();
+            true
+        }
+    };
+    accts.remove(&id);
+
+    let (balance, nonce) = (accts[&body.new_id].balance, accts[&body.new_id].nonce);
+    let detail = format!("{} {} into {}", if merged { "merged" } else { "renamed" }, id, body.new_id);
+    ledger.record_admin(now, &id, "rename", detail.clone());
+    ledger.record_admin(now, &body.new_id, "rename", detail);
+    state.persist(&mut ledger)?;
+    Ok(Json(RenamedAccount { id: body.new_id.clone(), balance, nonce, merged }))
+}
+
+#[derive(Debug, Serialize)]
+struct ServerKeyView {
+    public_key: String,
+}
+
+// The key receipts are signed with, so clients and third parties can verify them
+async fn get_server_key(State(state): State<AppState>) -> Json<ServerKeyView> {
+    Json(ServerKeyView { public_key: hex::encode(state.server_key.verifying_key().to_bytes()) })
+}
+
+// Prometheus text exposition of the service metrics
+async fn metrics(State(state): State<AppState>) -> String {
+    use std::fmt::Write;
+
+    let m = &state.metrics;
+    let accounts = state.lock_ledger().accounts.len();
+    let mut text = format!(
+        "# HELP txh_ledger_lock_waiters Requests currently waiting for the ledger lock.\n\
+         # TYPE txh_ledger_lock_waiters gauge\n\
+         txh_ledger_lock_waiters {}\n\
+         # HELP txh_ledger_lock_last_wait_seconds Time the most recent ledger lock acquisition spent waiting.\n\
+         # TYPE txh_ledger_lock_last_wait_seconds gauge\n\
+         txh_ledger_lock_last_wait_seconds {}\n\
+         # HELP txh_requests_total HTTP requests served.\n\
+         # TYPE txh_requests_total counter\n\
+         txh_requests_total {}\n\
+         # HELP txh_requests_traced_total HTTP requests sampled into a tracing span.\n\
+         # TYPE txh_requests_traced_total counter\n\
+         txh_requests_traced_total {}\n",
+        m.lock_waiters.load(Ordering::Relaxed),
+        m.last_lock_wait_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0,
+        m.requests.load(Ordering::Relaxed),
+        m.traced_requests.load(Ordering::Relaxed),
+    );
+
+    text.push_str(
+        "# HELP txh_transactions_total Transactions submitted through any route, by outcome.\n\
+         # TYPE txh_transactions_total counter\n",
+    );
+    for (outcome, count) in m.transactions.lock().unwrap().iter() {
+        writeln!(text, "txh_transactions_total{{result=\"{}\"}} {}", outcome, count).unwrap();
+    }
+
+    text.push_str(
+        "# HELP txh_transaction_duration_seconds Time to process a submitted transaction, ledger lock wait included.\n\
+         # TYPE txh_transaction_duration_seconds histogram\n",
+    );
+    let mut cumulative = 0;
+    for (bound, bucket) in LATENCY_BUCKETS.iter().zip(&m.latency_buckets) {
+        cumulative += bucket.load(Ordering::Relaxed);
+        writeln!(text, "txh_transaction_duration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative).unwrap();
+    }
+    let count = m.latency_count.load(Ordering::Relaxed);
+    writeln!(text, "txh_transaction_duration_seconds_bucket{{le=\"+Inf\"}} {}", count).unwrap();
+    writeln!(text, "txh_transaction_duration_seconds_sum {}", m.latency_micros_sum.load(Ordering::Relaxed) as f64 / 1_000_000.0).unwrap();
+    writeln!(text, "txh_transaction_duration_seconds_count {}", count).unwrap();
+
+    write!(
+        text,
+        "# HELP txh_accounts Accounts in the ledger.\n\
+         # TYPE txh_accounts gauge\n\
+         txh_accounts {}\n\
+         # HELP txh_value_transferred_total Sum of the amounts of applied submitted transactions.\n\
+         # TYPE txh_value_transferred_total counter\n\
+         txh_value_transferred_total {}\n\
+         # HELP txh_event_subscribers Open /events streams.\n\
+         # TYPE txh_event_subscribers gauge\n\
+         txh_event_subscribers {}\n",
+        accounts,
+        m.value_transferred.load(Ordering::Relaxed),
+        state.event_subscribers.load(Ordering::Relaxed),
+    )
+    .unwrap();
+    text
+}
+
+// Audits the ledger once no more requests can touch it and saves it a last time. A failed audit
+// doesn't stop the save, but the file is flagged so the next start reports it.
+pub fn final_integrity_check(state: &AppState) {
+    let mut ledger = state.lock_ledger();
+    let report = ledger.audit();
+    if report.discrepancies.is_empty() {
+        tracing::info!(total_supply = %report.total_supply, accounts = report.accounts, "integrity ok");
+    } else {
+        tracing::error!(
+            total_supply = %report.total_supply,
+            accounts = report.accounts,
+            discrepancies = ?report.discrepancies,
+            "integrity check failed"
+        );
+    }
+    ledger.integrity_warnings = report.discrepancies;
+    if let Some(storage) = &state.storage
+        && let Err(e) = storage.snapshot(&mut ledger)
+    {
+        tracing::error!(error = %e, "failed to save ledger");
+    }
+}
+
+// Chaos and the TXH_MAX_INFLIGHT limit around the routes that submit transactions. Every call gets the same
+// semaphore, so /submit_transaction and /rpc share one limit.
+fn limit_transactions(app: Router<AppState>, state: &AppState, slots: Option<&Arc<tokio::sync::Semaphore>>) -> Router<AppState> {
+    #[cfg(feature = "chaos")]
+    let app = app.route_layer(axum::middleware::from_fn_with_state(state.clone(), chaos));
+    let Some(slots) = slots else {
+        return app;
+    };
+    // Bound concurrent transaction requests, either queueing the excess or shedding it with a 503
+    let limit = GlobalConcurrencyLimitLayer::with_semaphore(slots.clone());
+    if state.config().shed_overload {
+        app.route_layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: axum::BoxError| async {
+                    api_error(StatusCode::SERVICE_UNAVAILABLE, "Too many transactions in flight, retry later")
+                }))
+                .layer(LoadShedLayer::new())
+                .layer(limit),
+        )
+    } else {
+        app.route_layer(limit)
+    }
+}
+
+// Every route, with the write guards, load limits and logging layers config asks for
+pub fn build_router(state: AppState) -> Router {
+    let max_inflight = state.config().max_inflight;
+    let slots = (max_inflight > 0).then(|| Arc::new(tokio::sync::Semaphore::new(max_inflight)));
+    let app = limit_transactions(Router::new().route("/submit_transaction", post(submit_transaction)), &state, slots.as_ref());
+    // Everything registered so far mutates state; read-only routes are added after the replica guard
+    let app = app
+        .route("/accounts", post(create_account))
+        .route("/account/:id/key", post(set_account_key))
+        .route("/account/:id/rename", post(rename_account))
+        .route("/submit_batch", post(submit_batch))
+        .route("/submit_multi", post(submit_multi))
+        .route("/distribute", post(distribute))
+        .route("/admin/set_balances", post(set_balances))
+        .route("/admin/rollback", post(rollback))
+        .route("/admin/restore", post(restore).layer(axum::extract::DefaultBodyLimit::max(MAX_SNAPSHOT_BYTES)))
+        .route("/account/:id/lock", post(lock_account))
+        .route("/account/:id/unlock", post(unlock_account))
+        .route("/account/:id/freeze", post(freeze_account))
+        .route("/account/:id/unfreeze", post(unfreeze_account))
+        .route("/transfer_and_freeze", post(transfer_and_freeze))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), reject_writes_on_replica))
+        .route_layer(axum::middleware::from_fn_with_state(state.clone(), reject_writes_during_maintenance))
+        // Only swaps this instance's settings, so replicas can pick up config changes too
+        .route("/admin/reload_config", post(reload_config))
+        .route("/account/:id", get(get_account))
+        .route("/accounts", get(list_accounts))
+        .route("/accounts/:id", get(get_account))
+        .route("/accounts/:id/transactions", get(account_transactions))
+        .route("/mempool/:sender", get(sender_mempool))
+        .route("/accounts/commit", post(commit_accounts))
+        .route("/net_settle", post(net_settle))
+        .route("/fees/volume", get(fee_volume))
+        .route("/account/:id/key", get(get_account_key))
+        .route("/key/:pubkey/accounts", get(key_accounts))
+        .route("/account/:id/admin_history", get(admin_history))
+        .route("/account/:id/delta", get(account_delta))
+        .route("/account/:id/statement", get(account_statement))
+        .route("/account/:id/rank", get(account_rank))
+        .route("/account/:id/available", get(account_available))
+        .route("/account/:id/projected", get(account_projected))
+        .route("/accounts/since", get(accounts_since))
+        .route("/sequence", get(sequence))
+        .route("/state_root", get(get_state_root))
+        .route("/stats", get(stats))
+        .route("/status/mode", get(mode))
+        .route("/distribution", get(balance_distribution))
+        .route("/admin/snapshot", get(snapshot))
+        .route("/admin/alerts", get(list_alerts))
+        .route("/admin/alerts/:id/ack", post(ack_alert))
+        .route("/admin/replay", post(replay))
+        .route("/transactions/between", get(transactions_between))
+        .route("/transactions/largest", get(largest_transaction))
+        .route("/transactions/:tx_id", get(get_transaction))
+        .route("/ledger", get(ledger_history))
+        .route("/server_key", get(get_server_key))
+        .route("/preview_batch", post(preview_batch))
+        .route("/verify_signature", post(check_signature))
+        // Checks replica and maintenance per method, so only the transaction layers wrap it
+        .merge(limit_transactions(Router::new().route("/rpc", post(rpc)), &state, slots.as_ref()))
+        .route("/events", get(events))
+        .route("/metrics", get(metrics));
+    let app = if state.config().log_bodies {
+        app.layer(axum::middleware::from_fn(log_bodies))
+    } else {
+        app
+    };
+    let app = app.layer(axum::middleware::from_fn_with_state(state.clone(), trace_requests));
+    app.with_state(state)
+}
+
+// Serves the default ledger and every TXH_TENANTS ledger from one listener. A request picks its tenant
+// with a `/t/<tenant>` path prefix, which is stripped before routing, or else an `X-Tenant` header;
+// requests naming neither go to the default ledger.
+pub fn build_tenant_router(default: AppState, tenants: Vec<AppState>) -> Router {
+    if tenants.is_empty() {
+        return build_router(default);
+    }
+    let default = build_router(default);
+    let tenants: Arc<HashMap<String, Router>> = Arc::new(tenants.into_iter()
+        .map(|state| (state.tenant.clone().unwrap_or_default(), build_router(state)))
+        .collect());
+    Router::new().fallback(move |req: axum::extract::Request| {
+        let default = default.clone();
+        let tenants = tenants.clone();
+        async move {
+            use axum::response::IntoResponse;
+            use tower::ServiceExt;
+
+            let (tenant, req) = split_tenant(req);
+            let app = match tenant {
+                None => default,
+                Some(tenant) => match tenants.get(&tenant) {
+                    Some(app) => app.clone(),
+                    None => return api_error(StatusCode::NOT_FOUND, format!("Unknown tenant {}", tenant)).into_response(),
+                },
+            };
+            match app.oneshot(req).await {
+                Ok(response) => response,
+                Err(never) => match never {},
+            }
+        }
+    })
+}
+
+// The tenant a request names, with a `/t/<tenant>` prefix taken off its path
+fn split_tenant(mut req: axum::extract::Request) -> (Option<String>, axum::extract::Request) {
+    if let Some(rest) = req.uri().path().strip_prefix("/t/") {
+        let (tenant, tail) = rest.split_once('/').unwrap_or((rest, ""));
+        let tenant = tenant.to_string();
+        let path_and_query = match req.uri().query() {
+            Some(query) => format!("/{}?{}", tail, query),
+            None => format!("/{}", tail),
+        };
+        let mut parts = req.uri().clone().into_parts();
+        parts.path_and_query = path_and_query.parse().ok();
+        if let Ok(uri) = axum::http::Uri::from_parts(parts) {
+            *req.uri_mut() = uri;
+        }
+        return (Some(tenant), req);
+    }
+    let tenant = req.headers().get("x-tenant").map(|v| v.to_str().unwrap_or_default().to_string());
+    (tenant, req)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sanitize_body_redacts_lock_tokens() {
+        let logged = sanitize_body(br#"{"token":"abc","nested":{"lock_token":"def"},"amount":1}"#);
+        assert!(!logged.contains("abc") && !logged.contains("def"), "{}", logged);
+        assert!(logged.contains("\"amount\":1"));
+    }
+
+    #[test]
+    fn enqueue_keeps_the_first_transaction_for_a_nonce() {
+        let mut mempool = Mempool::new();
+        let tx = Transaction { sender: "Alice".into(), receiver: "Bob".into(), amount: 1, nonce: 3, ..Default::default() };
+        enqueue(&mut mempool, &tx, 0, 60_000).unwrap();
+        enqueue(&mut mempool, &tx, 1, 60_000).unwrap();
+        let other = Transaction { amount: 2, ..tx.clone() };
+        assert!(matches!(enqueue(&mut mempool, &other, 2, 60_000), Err(TransactionError::NonceAlreadyQueued)));
+        assert_eq!(mempool["Alice"][&3].tx, tx);
+
+        // Once the first one expires the nonce is free again
+        enqueue(&mut mempool, &other, 60_001, 60_000).unwrap();
+        assert_eq!(mempool["Alice"][&3].tx, other);
+    }
+
+    #[test]
+    fn the_dedup_window_expires_oldest_first_and_keeps_reapplied_transactions() {
+        let mut recent = RecentTransactions::default();
+        let tx = Transaction { sender: "Alice".into(), receiver: "Bob".into(), amount: 1, nonce: 0, ..Default::default() };
+        let other = Transaction { nonce: 1, ..tx.clone() };
+        recent.insert(&tx, 0);
+        recent.insert(&other, 500);
+        assert_eq!(recently_applied(&mut recent, &tx, 999, 1000), Some(0));
+        assert_eq!(recently_applied(&mut recent, &tx, 1000, 1000), None);
+        assert_eq!(recently_applied(&mut recent, &other, 1000, 1000), Some(500));
+        assert_eq!(recent.order.len(), 1);
+
+        // Seen again later, the stale first entry must not expire the newer one
+        recent.insert(&tx, 1200);
+        recent.insert(&tx, 1400);
+        assert_eq!(recently_applied(&mut recent, &tx, 2300, 1000), Some(1400));
+        assert_eq!(recent.order.len(), 1);
+    }
+
+    // Which of 2000 transaction requests chaos fails under a fixed seed
+    #[cfg(feature = "chaos")]
+    async fn chaos_failures(error_rate: &str) -> Vec<bool> {
+        use tower::ServiceExt;
+
+        let config = Config::from_vars(&ConfigVars::new([("TXH_CHAOS_ERROR_RATE", error_rate), ("TXH_CHAOS_SEED", "7")])).unwrap();
+        let app = build_router(AppState::new(config, Ledger::default(), SigningKey::from_bytes(&[1; 32])));
+        let mut failures = Vec::new();
+        for nonce in 0..2000 {
+            let tx = serde_json::json!({"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": nonce});
+            let request = axum::http::Request::post("/submit_transaction")
+                .header("content-type", "application/json")
+                .body(axum::body::Body::from(tx.to_string()))
+                .unwrap();
+            let response = app.clone().oneshot(request).await.unwrap();
+            failures.push(response.status() == StatusCode::INTERNAL_SERVER_ERROR);
+        }
+        failures
+    }
+
+    #[cfg(feature = "chaos")]
+    #[tokio::test]
+    async fn chaos_fails_roughly_the_configured_share_of_transactions() {
+        let failures = chaos_failures("0.25").await;
+        let failed = failures.iter().filter(|failed| **failed).count();
+        assert!((400..=600).contains(&failed), "{} of 2000 failed", failed);
+        // The same seed fails the same requests, so a failing run can be replayed
+        assert_eq!(chaos_failures("0.25").await, failures);
+
+        assert!(!chaos_failures("0").await.contains(&true));
+        assert!(!chaos_failures("1").await.contains(&false));
+    }
+}
@@ -0,0 +1,399 @@
+// Durable ledger storage: a snapshot of the whole ledger plus a write-ahead log of the commits made since.
+// Each commit appends one record holding only what it changed and syncs it before returning, so a change is
+// on disk before it's acknowledged. Every TXH_SNAPSHOT_EVERY records the log is folded into a new snapshot.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ledger::{load_ledger, save_ledger, Account, AdminEntry, Changes, HistoryEntry, Ledger};
+
+pub trait Storage: Send + Sync {
+    // Makes everything changed in the ledger since the last commit durable, then clears its change tracking
+    fn commit(&self, ledger: &mut Ledger) -> io::Result<()>;
+    // Saves the whole ledger, e.g. at shutdown or after it was replaced
+    fn snapshot(&self, ledger: &mut Ledger) -> io::Result<()>;
+    // The ledger as of the last successful commit; Ok(None) if nothing was saved yet
+    fn load(&self) -> Result<Option<Ledger>, String>;
+}
+
+// Whether a failed commit may succeed if simply tried again, as after a timeout or a dropped connection.
+// Anything else, such as a full disk or a permission error, fails the same way every time.
+pub fn is_transient(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::Interrupted
+            | io::ErrorKind::WouldBlock
+            | io::ErrorKind::TimedOut
+            | io::ErrorKind::ResourceBusy
+            | io::ErrorKind::ConnectionRefused
+            | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::ConnectionAborted
+            | io::ErrorKind::NotConnected
+            | io::ErrorKind::BrokenPipe
+    )
+}
+
+// Record framing: payload length as u32 little-endian, then the first CHECKSUM_LEN bytes of the
+// payload's SHA-256, then the JSON payload
+const HEADER_LEN: usize = 4 + CHECKSUM_LEN;
+const CHECKSUM_LEN: usize = 8;
+
+// One commit as written to the log
+#[derive(Debug, Serialize, Deserialize)]
+struct LogRecord {
+    commit: u64,
+    seq: u64,
+    sequencer_seq: u64,
+    evicted_through: u64,
+    expected_supply: Option<u128>,
+    accounts: Vec<(String, Option<Account>)>, // touched accounts as they are now; None = removed
+    history: Vec<HistoryEntry>, // appended entries
+    history_len: usize, // history length afterwards, so eviction replays exactly
+    admin: Vec<AdminEntry>, // appended entries
+    fee_bucket: Option<(u64, u64)>, // newest fee bucket, if it changed
+}
+
+impl LogRecord {
+    fn take(ledger: &mut Ledger, commit: u64) -> LogRecord {
+        let changes = std::mem::replace(&mut ledger.changes, Changes::none());
+        let history_from = ledger.history.len().saturating_sub(changes.history);
+        let admin_from = ledger.admin_log.len().saturating_sub(changes.admin);
+        LogRecord {
+            commit,
+            seq: ledger.seq,
+            sequencer_seq: ledger.sequencer_seq,
+            evicted_through: ledger.evicted_through,
+            expected_supply: ledger.expected_supply,
+            accounts: ledger.accounts.take_touched(),
+            history: ledger.history.range(history_from..).cloned().collect(),
+            history_len: ledger.history.len(),
+            admin: ledger.admin_log[admin_from..].to_vec(),
+            fee_bucket: if changes.fees { ledger.fee_buckets.back().copied() } else { None },
+        }
+    }
+
+    fn apply(self, ledger: &mut Ledger) {
+        for (id, account) in self.accounts {
+            match account {
+                Some(account) => ledger.accounts.insert(id, account),
+                None => ledger.accounts.remove(&id),
+            };
+        }
+        ledger.history.extend(self.history);
+        while ledger.history.len() > self.history_len {
+            ledger.history.pop_front();
+        }
+        ledger.admin_log.extend(self.admin);
+        if let Some((second, total)) = self.fee_bucket {
+            ledger.set_fee_bucket(second, total);
+        }
+        ledger.seq = self.seq;
+        ledger.sequencer_seq = self.sequencer_seq;
+        ledger.evicted_through = self.evicted_through;
+        ledger.expected_supply = self.expected_supply;
+        ledger.commits = self.commit;
+    }
+}
+
+fn checksum(payload: &[u8]) -> [u8; CHECKSUM_LEN] {
+    Sha256::digest(payload)[..CHECKSUM_LEN].try_into().unwrap()
+}
+
+// The ledger at path plus its log at path.wal
+pub struct FileStorage {
+    path: String,
+    log_path: String,
+    snapshot_every: usize, // log records after which the next commit writes a snapshot instead
+    log: Mutex<Option<OpenLog>>, // opened on the first commit
+}
+
+struct OpenLog {
+    file: File,
+    len: u64, // end of the last complete record; anything after it is a torn or failed write
+    records: usize,
+    last_commit: u64,
+}
+
+// A snapshot with its log replayed on top
+struct Recovered {
+    ledger: Option<Ledger>,
+    len: u64,
+    records: usize,
+    last_commit: u64,
+}
+
+impl FileStorage {
+    pub fn new(path: &str, snapshot_every: usize) -> FileStorage {
+        FileStorage {
+            path: path.to_string(),
+            log_path: format!("{}.wal", path),
+            snapshot_every: snapshot_every.max(1),
+            log: Mutex::new(None),
+        }
+    }
+
+    // Loads the snapshot and replays the log records it doesn't include yet. A final record cut short by
+    // a crash mid-append, or failing its checksum, was never acknowledged and is left out; damage anywhere
+    // before the final record is an error.
+    fn recover(&self) -> Result<Recovered, String> {
+        let mut ledger = load_ledger(&self.path)?;
+        let mut data = Vec::new();
+        match File::open(&self.log_path) {
+            Ok(mut file) => {
+                file.read_to_end(&mut data).map_err(|e| format!("can't read {}: {}", self.log_path, e))?;
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("can't read {}: {}", self.log_path, e)),
+        }
+        let mut last_commit = ledger.as_ref().map_or(0, |ledger| ledger.commits);
+
+        let mut pos = 0;
+        let mut records = 0;
+        while pos < data.len() {
+            let torn = |reason: &str| {
+                tracing::warn!(path = %self.log_path, offset = pos, reason, "discarding torn final log record");
+            };
+            if data.len() - pos < HEADER_LEN {
+                torn("incomplete header");
+                break;
+            }
+            let len = u32::from_le_bytes(data[pos..pos + 4].try_into().unwrap()) as usize;
+            let end = pos + HEADER_LEN + len;
+            if end > data.len() {
+                torn("incomplete payload");
+                break;
+            }
+            let payload = &data[pos + HEADER_LEN..end];
+            if checksum(payload) != data[pos + 4..pos + HEADER_LEN] {
+                if end == data.len() {
+                    torn("checksum mismatch");
+                    break;
+                }
+                return Err(format!("{} is corrupt at offset {}", self.log_path, pos));
+            }
+            let record: LogRecord = serde_json::from_slice(payload)
+                .map_err(|e| format!("{} has an invalid record at offset {}: {}", self.log_path, pos, e))?;
+            let Some(ledger) = ledger.as_mut() else {
+                return Err(format!("{} has records but {} doesn't exist", self.log_path, self.path));
+            };
+            // Records at or below the snapshot's commit were folded into it before the log was cut
+            if record.commit > ledger.commits {
+                if record.commit != ledger.commits + 1 {
+                    return Err(format!("{} skips from commit {} to {}", self.log_path, ledger.commits, record.commit));
+                }
+                record.apply(ledger);
+            }
+            last_commit = last_commit.max(ledger.commits);
+            pos = end;
+            records += 1;
+        }
+
+        if let Some(ledger) = &mut ledger {
+            ledger.clear_changes();
+        }
+        Ok(Recovered { ledger, len: pos as u64, records, last_commit })
+    }
+
+    fn open_log(&self) -> io::Result<OpenLog> {
+        let recovered = self.recover().map_err(io::Error::other)?;
+        let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&self.log_path)?;
+        file.set_len(recovered.len)?;
+        file.seek(SeekFrom::Start(recovered.len))?;
+        Ok(OpenLog { file, len: recovered.len, records: recovered.records, last_commit: recovered.last_commit })
+    }
+
+    fn write_snapshot(&self, log: &mut OpenLog, ledger: &mut Ledger) -> io::Result<()> {
+        let commit = log.last_commit + 1;
+        ledger.commits = commit;
+        ledger.clear_changes();
+        save_ledger(&self.path, ledger)?;
+        log.last_commit = commit;
+        // The old records are all at or below the snapshot's commit now. If the log can't be cut, they are
+        // skipped on replay and new records simply follow them.
+        let cut = log.file.set_len(0)
+            .and_then(|()| log.file.sync_data())
+            .and_then(|()| log.file.seek(SeekFrom::Start(0)).map(|_| ()));
+        match cut {
+            Ok(()) => {
+                log.len = 0;
+                log.records = 0;
+            }
+            Err(e) => tracing::warn!(path = %self.log_path, error = %e, "can't truncate the log after a snapshot"),
+        }
+        Ok(())
+    }
+
+    fn append(&self, log: &mut OpenLog, ledger: &mut Ledger) -> io::Result<()> {
+        let record = LogRecord::take(ledger, log.last_commit + 1);
+        let payload = serde_json::to_vec(&record)?;
+        let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+        frame.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frame.extend_from_slice(&checksum(&payload));
+        frame.extend_from_slice(&payload);
+
+        let written = log.file.seek(SeekFrom::Start(log.len))
+            .and_then(|_| log.file.write_all(&frame))
+            .and_then(|()| log.file.sync_data());
+        if let Err(e) = written {
+            // Cut off whatever part of the record made it, so the next one starts on a record boundary
+            let _ = log.file.set_len(log.len);
+            return Err(e);
+        }
+        log.len += frame.len() as u64;
+        log.records += 1;
+        log.last_commit = record.commit;
+        ledger.commits = record.commit;
+        Ok(())
+    }
+
+    fn with_log(&self, f: impl FnOnce(&mut OpenLog) -> io::Result<()>) -> io::Result<()> {
+        let mut log = self.log.lock().unwrap();
+        if log.is_none() {
+            *log = Some(self.open_log()?);
+        }
+        f(log.as_mut().unwrap())
+    }
+}
+
+impl Storage for FileStorage {
+    fn commit(&self, ledger: &mut Ledger) -> io::Result<()> {
+        self.with_log(|log| {
+            if ledger.changes.rewritten || log.records >= self.snapshot_every {
+                self.write_snapshot(log, ledger)
+            } else {
+                self.append(log, ledger)
+            }
+        })
+    }
+
+    fn snapshot(&self, ledger: &mut Ledger) -> io::Result<()> {
+        self.with_log(|log| self.write_snapshot(log, ledger))
+    }
+
+    fn load(&self) -> Result<Option<Ledger>, String> {
+        self.recover().map(|recovered| recovered.ledger)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ledger::GenesisAccount;
+
+    fn temp_path(name: &str) -> String {
+        let path = std::env::temp_dir().join(format!("txh-storage-{}-{}", std::process::id(), name));
+        let path = path.to_str().unwrap().to_string();
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(format!("{}.wal", path));
+        path
+    }
+
+    fn genesis() -> Ledger {
+        Ledger::from_genesis(&[
+            GenesisAccount { id: "Alice".to_string(), balance: 1000, nonce: 0 },
+            GenesisAccount { id: "Bob".to_string(), balance: 500, nonce: 0 },
+        ])
+    }
+
+    fn transfer(ledger: &mut Ledger, amount: u64) {
+        ledger.seq += 1;
+        ledger.accounts.get_mut("Alice").unwrap().balance -= amount;
+        ledger.accounts.get_mut("Alice").unwrap().nonce += 1;
+        ledger.accounts.get_mut("Bob").unwrap().balance += amount;
+        let entry = HistoryEntry { seq: ledger.seq, sender: "Alice".into(), receiver: "Bob".into(), amount, ..Default::default() };
+        ledger.record(entry, 0);
+    }
+
+    #[test]
+    fn commits_after_the_first_append_to_the_log_instead_of_rewriting_the_snapshot() {
+        let path = temp_path("append");
+        let storage = FileStorage::new(&path, 1000);
+        let mut ledger = genesis();
+        storage.commit(&mut ledger).unwrap();
+        let snapshot = std::fs::read(&path).unwrap();
+
+        for amount in [10, 20, 30] {
+            transfer(&mut ledger, amount);
+            storage.commit(&mut ledger).unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), snapshot);
+
+        let loaded = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+        assert_eq!(loaded.accounts["Alice"].balance, 940);
+        assert_eq!(loaded.accounts["Alice"].nonce, 3);
+        assert_eq!(loaded.accounts["Bob"].balance, 560);
+        assert_eq!(loaded.seq, 3);
+        assert_eq!(loaded.history.len(), 3);
+    }
+
+    #[test]
+    fn a_torn_final_record_is_skipped_and_cut_off() {
+        let path = temp_path("torn");
+        let storage = FileStorage::new(&path, 1000);
+        let mut ledger = genesis();
+        storage.commit(&mut ledger).unwrap();
+        transfer(&mut ledger, 10);
+        storage.commit(&mut ledger).unwrap();
+        let intact = std::fs::metadata(format!("{}.wal", path)).unwrap().len();
+        transfer(&mut ledger, 20);
+        storage.commit(&mut ledger).unwrap();
+
+        // A crash halfway through the last append
+        let log = OpenOptions::new().write(true).open(format!("{}.wal", path)).unwrap();
+        let full = log.metadata().unwrap().len();
+        log.set_len(intact + (full - intact) / 2).unwrap();
+
+        let reopened = FileStorage::new(&path, 1000);
+        let loaded = reopened.load().unwrap().unwrap();
+        assert_eq!(loaded.seq, 1);
+        assert_eq!(loaded.accounts["Alice"].balance, 990);
+
+        // The next commit continues right after the last intact record
+        let mut ledger = loaded;
+        transfer(&mut ledger, 5);
+        reopened.commit(&mut ledger).unwrap();
+        let loaded = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+        assert_eq!(loaded.seq, 2);
+        assert_eq!(loaded.accounts["Alice"].balance, 985);
+    }
+
+    #[test]
+    fn damage_before_the_final_record_is_an_error() {
+        let path = temp_path("corrupt");
+        let storage = FileStorage::new(&path, 1000);
+        let mut ledger = genesis();
+        storage.commit(&mut ledger).unwrap();
+        transfer(&mut ledger, 10);
+        storage.commit(&mut ledger).unwrap();
+        transfer(&mut ledger, 20);
+        storage.commit(&mut ledger).unwrap();
+
+        let log_path = format!("{}.wal", path);
+        let mut data = std::fs::read(&log_path).unwrap();
+        data[HEADER_LEN + 2] ^= 0xff;
+        std::fs::write(&log_path, data).unwrap();
+        assert!(FileStorage::new(&path, 1000).load().unwrap_err().contains("corrupt"));
+    }
+
+    #[test]
+    fn snapshots_fold_the_log_in_and_empty_it() {
+        let path = temp_path("snapshot");
+        let storage = FileStorage::new(&path, 2);
+        let mut ledger = genesis();
+        storage.commit(&mut ledger).unwrap();
+        for amount in [1, 2, 3, 4, 5, 6] {
+            transfer(&mut ledger, amount);
+            storage.commit(&mut ledger).unwrap();
+        }
+        // Two records, a snapshot, two records, a snapshot
+        assert_eq!(std::fs::metadata(format!("{}.wal", path)).unwrap().len(), 0);
+        let loaded = FileStorage::new(&path, 2).load().unwrap().unwrap();
+        assert_eq!(loaded.accounts["Alice"].balance, 979);
+        assert_eq!(loaded.seq, 6);
+        assert_eq!(loaded.commits, ledger.commits);
+    }
+}
@@ -0,0 +1,1170 @@
+// Transactions and the rules that decide whether one may be applied to the ledger
+
+use std::collections::HashMap;
+use std::sync::MutexGuard;
+
+use axum::http::StatusCode;
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::config::Config;
+use crate::formats::{amount_format, nonce_format};
+use crate::ledger::{state_root, total_supply, Account, AccountStore, HistoryEntry, Ledger};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash, Deserialize)]
+pub struct Transaction {
+    pub sender: String,
+    pub receiver: String,
+    #[serde(with = "amount_format")]
+    pub amount: u64,
+    #[serde(with = "nonce_format")]
+    pub nonce: u32,
+    #[serde(default)]
+    pub signature: Option<String>, // hex ed25519 signature over signing_bytes(), required once the sender registered a key
+    #[serde(default)]
+    pub lock_token: Option<String>, // required while the sender account is locked
+    #[serde(default)]
+    pub timestamp: Option<u64>, // client-assigned time in unix ms, covered by the signature when present
+    #[serde(default)]
+    pub expected_state_root: Option<String>, // apply only if the state root still matches (see state_root)
+    #[serde(default)]
+    pub chain_id: Option<String>, // deployment the transaction is meant for, covered by the signature when present
+    #[serde(default)]
+    pub sequence: Option<u64>, // global order assigned by the trusted sequencer, required when one is configured
+    #[serde(default)]
+    pub sequencer_signature: Option<String>, // hex ed25519 signature by the sequencer over sequencer_bytes()
+}
+
+#[derive(Debug)]
+pub enum TransactionError {
+    AccountNotFound, // Sender account doesn't exist
+    AmountIsZero, // Transcation amount is zero
+    SenderIsReceiver, // Sender and receiver are the same 
+    InsufficientFunds, //  Sender has sufficient funds
+    InvalidNonce, // Transaction's nonce isn't the sender's current nonce
+    UnknownReceiver, // Receiver doesn't exist and isn't allowed to be auto-created
+    InvalidSignature, // Signature missing, malformed or not made by the sender's registered key
+    AmountNotAligned, // Amount isn't a multiple of the configured step
+    AccountLocked, // Sender is locked and the transaction doesn't carry the lock token
+    BalanceOverflow, // Crediting would push a balance past u64::MAX
+    MissingTimestamp, // Timestamp required by the configured checks but not provided
+    TimestampRegression, // Timestamp is older than the sender's previous transaction
+    TimestampInFuture, // Timestamp is further ahead of the server clock than the allowed skew
+    TimestampTooOld, // Timestamp is further behind the server clock than the allowed age
+    AmountTooLarge, // Amount exceeds the configured fraction of the total supply
+    AccountTooNew, // Sender was created more recently than the configured minimum account age
+    AccountFrozen, // Sender is frozen by an admin
+    StateRootMismatch, // The ledger changed since the client read the state root it expected
+    InvalidSequencerSignature, // Sequence or sequencer signature missing, malformed or not made by the sequencer key
+    SequenceOutOfOrder, // Sequence isn't above the last one applied
+    VelocityLimitExceeded, // Sender's outbound total over the velocity window would pass the limit
+    WrongChainId, // Chain id missing or meant for another deployment
+    NonceAlreadyQueued, // A different transaction is already queued for this sender and nonce
+    NonceExhausted, // Sender's nonce, or a receiver's that would be bumped, is already u32::MAX
+    StorageFailed, // Applied but couldn't be saved, so it was rolled back
+    StorageUnavailable, // Applied but storage kept failing transiently through every retry, so it was rolled back
+}
+
+impl TransactionError {
+    // HTTP status a rejected transaction is answered with
+    pub fn status_code(&self) -> StatusCode {
+        use TransactionError::*;
+        match self {
+            AccountNotFound => StatusCode::NOT_FOUND,
+            AmountIsZero | SenderIsReceiver | AmountNotAligned | MissingTimestamp | WrongChainId => StatusCode::BAD_REQUEST,
+            InvalidSignature | InvalidSequencerSignature => StatusCode::UNAUTHORIZED,
+            AccountLocked => StatusCode::LOCKED,
+            AccountFrozen => StatusCode::FORBIDDEN,
+            InvalidNonce | TimestampRegression | StateRootMismatch | SequenceOutOfOrder | NonceAlreadyQueued => {
+                StatusCode::CONFLICT
+            }
+            InsufficientFunds | UnknownReceiver | BalanceOverflow | TimestampInFuture | TimestampTooOld
+            | AmountTooLarge | AccountTooNew | NonceExhausted => StatusCode::UNPROCESSABLE_ENTITY,
+            VelocityLimitExceeded => StatusCode::TOO_MANY_REQUESTS,
+            StorageFailed => StatusCode::INTERNAL_SERVER_ERROR,
+            StorageUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+// Prefix that separates chain-bound signatures from the plain format
+pub const CHAIN_ID_TAG: &[u8] = b"txh-chain-id";
+
+// Bytes a transaction signature covers: if the transaction carries a chain id, CHAIN_ID_TAG and the chain id
+// as u32 big-endian length plus UTF-8 bytes; then sender and receiver the same way,
+// then amount as u64 big-endian and nonce as u32 big-endian, then the timestamp as u64 big-endian if present
+pub fn signing_bytes(tx: &Transaction) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if let Some(chain_id) = &tx.chain_id {
+        bytes.extend_from_slice(CHAIN_ID_TAG);
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+    }
+    for id in [&tx.sender, &tx.receiver] {
+        bytes.extend_from_slice(&(id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(id.as_bytes());
+    }
+    bytes.extend_from_slice(&tx.amount.to_be_bytes());
+    bytes.extend_from_slice(&tx.nonce.to_be_bytes());
+    if let Some(timestamp) = tx.timestamp {
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+    }
+    bytes
+}
+
+pub fn parse_public_key(hex_key: &str) -> Option<VerifyingKey> {
+    let bytes: [u8; 32] = hex::decode(hex_key).ok()?.try_into().ok()?;
+    VerifyingKey::from_bytes(&bytes).ok()
+}
+
+// Checks the transaction's signature against a hex encoded public key
+pub fn verify_signature(tx: &Transaction, public_key: &str) -> Result<(), TransactionError> {
+    verify_signed_bytes(&signing_bytes(tx), tx.signature.as_deref(), public_key)
+}
+
+// Checks a hex ed25519 signature over bytes against a hex public key
+pub fn verify_signed_bytes(bytes: &[u8], signature: Option<&str>, public_key: &str) -> Result<(), TransactionError> {
+    let key = parse_public_key(public_key).ok_or(TransactionError::InvalidSignature)?;
+    let sig_bytes: [u8; 64] = signature
+        .and_then(|sig| hex::decode(sig).ok())
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(TransactionError::InvalidSignature)?;
+    key.verify(bytes, &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| TransactionError::InvalidSignature)
+}
+
+// Bytes a sequencer signature covers: the transaction's signing_bytes followed by the sequence as u64 big-endian
+pub fn sequencer_bytes(tx: &Transaction, sequence: u64) -> Vec<u8> {
+    let mut bytes = signing_bytes(tx);
+    bytes.extend_from_slice(&sequence.to_be_bytes());
+    bytes
+}
+
+// Checks the sequence number is signed by the configured sequencer and returns it
+pub fn verify_sequencer(tx: &Transaction, sequencer_key: &str) -> Result<u64, TransactionError> {
+    let sequence = tx.sequence.ok_or(TransactionError::InvalidSequencerSignature)?;
+    let key = parse_public_key(sequencer_key).ok_or(TransactionError::InvalidSequencerSignature)?;
+    let sig_bytes: [u8; 64] = tx.sequencer_signature.as_deref()
+        .and_then(|sig| hex::decode(sig).ok())
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(TransactionError::InvalidSequencerSignature)?;
+    key.verify(&sequencer_bytes(tx, sequence), &Signature::from_bytes(&sig_bytes))
+        .map_err(|_| TransactionError::InvalidSequencerSignature)?;
+    Ok(sequence)
+}
+
+// Hex SHA-256 of the transaction's signing bytes. Identifies a transaction in history and receipts;
+// the same payload always hashes the same, whether or not it carries a signature.
+pub fn tx_id(tx: &Transaction) -> String {
+    hex::encode(Sha256::digest(signing_bytes(tx)))
+}
+
+// Optimistic concurrency over the whole state: nothing may have changed since the client read expected
+fn check_state_root(expected: Option<&str>, accounts: &AccountStore) -> Result<(), TransactionError> {
+    if let Some(expected) = expected
+        && !expected.eq_ignore_ascii_case(&state_root(accounts))
+    {
+        return Err(TransactionError::StateRootMismatch);
+    }
+    Ok(())
+}
+
+// Timestamps from one sender never go backwards, and stay within the allowed window around the server clock
+fn check_timestamp(timestamp: Option<u64>, sender: &Account, config: &Config, now: u64) -> Result<(), TransactionError> {
+    if config.monotonic_timestamps {
+        let timestamp = timestamp.ok_or(TransactionError::MissingTimestamp)?;
+        if sender.last_tx_timestamp.is_some_and(|last| timestamp < last) {
+            return Err(TransactionError::TimestampRegression);
+        }
+    }
+    if config.max_future_skew_ms > 0 || config.max_timestamp_age_ms > 0 {
+        let timestamp = timestamp.ok_or(TransactionError::MissingTimestamp)?;
+        if config.max_future_skew_ms > 0 && timestamp > now.saturating_add(config.max_future_skew_ms) {
+            return Err(TransactionError::TimestampInFuture);
+        }
+        if config.max_timestamp_age_ms > 0 && timestamp < now.saturating_sub(config.max_timestamp_age_ms) {
+            return Err(TransactionError::TimestampTooOld);
+        }
+    }
+    Ok(())
+}
+
+// Validates a single transaction and, if it passes, applies it: debits the sender the amount plus fees,
+// credits the receiver (auto-creating it unless TXH_REQUIRE_KNOWN_RECEIVER forbids that), bumps the sender's
+// nonce and advances the global seq by one. `now` is the clock time in ms. A rejection changes nothing.
+pub fn handle_transaction(
+    tx: &Transaction,
+    ledger: &mut Ledger,
+    config: &Config,
+    now: u64,
+) -> Result<(), TransactionError> {
+
+    let seq = ledger.seq + 1;
+    let accts = &mut ledger.accounts;
+
+    // 0. Optimistic concurrency over the whole state: nothing may have changed since the client looked
+    check_state_root(tx.expected_state_root.as_deref(), accts)?;
+
+    // 0a. A deployment with a chain id only takes transactions addressed to it, so signatures can't be
+    // replayed from another deployment
+    if let Some(chain_id) = &config.chain_id
+        && tx.chain_id.as_ref() != Some(chain_id)
+    {
+        return Err(TransactionError::WrongChainId);
+    }
+
+    // 0b. Under a trusted sequencer, transactions apply in the sequencer's global order only
+    let sequence = match &config.sequencer_key {
+        Some(key) => {
+            let sequence = verify_sequencer(tx, key)?;
+            if sequence <= ledger.sequencer_seq {
+                return Err(TransactionError::SequenceOutOfOrder);
+            }
+            Some(sequence)
+        }
+        None => None,
+    };
+
+    // 1. Verify sender account exists before cloning it
+    let mut sender_account_clone = accts.get(&tx.sender).ok_or(TransactionError::AccountNotFound)?.clone();
+
+    // 1b. If the sender registered a public key, the transaction must be signed with it.
+    // With require_signatures, senders without a key can't send at all.
+    match &sender_account_clone.public_key {
+        Some(public_key) => verify_signature(tx, public_key)?,
+        None if config.require_signatures => return Err(TransactionError::InvalidSignature),
+        None => {}
+    }
+
+    // 1b2. A frozen sender can't send until an admin unfreezes it
+    if sender_account_clone.frozen {
+        return Err(TransactionError::AccountFrozen);
+    }
+
+    // 1c. A locked sender only accepts transactions carrying its lock token
+    if let Some(lock) = &sender_account_clone.lock
+        && lock.expires_at_ms > now
+        && tx.lock_token.as_deref() != Some(lock.token.as_str())
+    {
+        return Err(TransactionError::AccountLocked);
+    }
+
+    // 1d. Fresh accounts can't send yet, which slows down drain-and-abandon accounts
+    if now.saturating_sub(sender_account_clone.created_ms) < config.min_account_age_secs * 1000 {
+        return Err(TransactionError::AccountTooNew);
+    }
+
+    // 2. Transaction amount is not zero
+    if tx.amount == 0 {
+        return Err(TransactionError::AmountIsZero);
+    }
+
+    // 2b. Amount is a multiple of the configured step
+    if config.amount_step > 1 && !tx.amount.is_multiple_of(config.amount_step) {
+        return Err(TransactionError::AmountNotAligned);
+    }
+
+    // 2b2. Amount is within the configured per-transfer ceiling
+    if config.max_amount > 0 && tx.amount > config.max_amount {
+        return Err(TransactionError::AmountTooLarge);
+    }
+
+    // 2c. Amount is a sane share of everything in circulation, catching fat-finger transfers
+    if config.max_amount_supply_fraction > 0.0
+        && tx.amount as f64 > config.max_amount_supply_fraction * total_supply(accts) as f64
+    {
+        return Err(TransactionError::AmountTooLarge);
+    }
+
+    // 2d. Outbound over the trailing velocity window stays within the limit
+    if config.velocity_limit > 0 {
+        let window_ms = config.velocity_window_secs.saturating_mul(1000);
+        let spent: u64 = sender_account_clone.outbound.iter()
+            .filter(|(at_ms, _)| at_ms.saturating_add(window_ms) > now)
+            .map(|(_, amount)| amount)
+            .sum();
+        if spent.saturating_add(tx.amount) > config.velocity_limit {
+            return Err(TransactionError::VelocityLimitExceeded);
+        }
+    }
+
+    // 3. validate sender isn't receiver (unless self-transfers are allowed as nonce bumps)
+    if tx.sender == tx.receiver && !config.allow_self_transfer {
+        return Err(TransactionError::SenderIsReceiver);
+    }
+
+    // 4. Sender has sufficient funds, including fees: the transfer fee plus the fee for auto-creating the receiver.
+    // A self-transfer moves nothing and the fee collector doesn't pay itself, so neither owes a transfer fee.
+    let creation_fee = if tx.sender != tx.receiver && !accts.contains_key(&tx.receiver) {
+        config.account_creation_fee
+    } else {
+        0
+    };
+    let transfer_fee = if tx.sender != tx.receiver && tx.sender != config.fee_collector {
+        config.transfer_fee(tx.amount)
+    } else {
+        0
+    };
+    let fee = creation_fee.saturating_add(transfer_fee);
+    let burn = config.fee_burn(fee); // destroyed, the collector is credited the rest
+    if tx.amount.checked_add(fee).is_none_or(|total| sender_account_clone.balance < total) {
+        return Err(TransactionError::InsufficientFunds);
+    }
+
+    // 5. Transaction's nonce is the sender's current nonce
+    if sender_account_clone.nonce != tx.nonce {
+        return Err(TransactionError::InvalidNonce);
+    }
+
+    // 5b. Timestamps are monotonic per sender and close to the server clock
+    check_timestamp(tx.timestamp, &sender_account_clone, config, now)?;
+
+    // 6. Receiver exists, or may be auto-created
+    if config.require_known_receiver
+        && !accts.contains_key(&tx.receiver)
+        && !config.known_receivers.contains(&tx.receiver)
+    {
+        return Err(TransactionError::UnknownReceiver);
+    }
+
+    // 6b. Credits can't overflow; a new receiver starts from 0, so this covers auto-creation too.
+    // Checked before anything is mutated so a rejected transaction leaves the sender untouched.
+    if tx.sender != tx.receiver {
+        let receiver_balance = accts.get(&tx.receiver).map_or(0, |acct| acct.balance);
+        receiver_balance.checked_add(tx.amount).ok_or(TransactionError::BalanceOverflow)?;
+    }
+    if fee > burn && config.fee_collector != tx.sender {
+        let collector_balance = accts.get(&config.fee_collector).map_or(0, |acct| acct.balance);
+        let incoming = if config.fee_collector == tx.receiver { tx.amount } else { 0 };
+        collector_balance.checked_add(incoming)
+            .and_then(|balance| balance.checked_add(fee - burn))
+            .ok_or(TransactionError::BalanceOverflow)?;
+    }
+
+    // 6c. Nonces stop at u32::MAX rather than wrapping back to 0, which would make old transactions replayable.
+    // An account whose nonce reached it can't send anymore.
+    let receiver_nonce_bumped = config.bump_receiver_nonce && tx.sender != tx.receiver;
+    if sender_account_clone.nonce == u32::MAX
+        || (receiver_nonce_bumped && accts.get(&tx.receiver).is_some_and(|acct| acct.nonce == u32::MAX))
+    {
+        return Err(TransactionError::NonceExhausted);
+    }
+
+    // It's Valid. 
+    // // Increment Sender Nonce
+    sender_account_clone.nonce += 1;
+    sender_account_clone.last_active_ms = now;
+    if config.velocity_limit > 0 {
+        let window_ms = config.velocity_window_secs.saturating_mul(1000);
+        let outbound = &mut sender_account_clone.outbound;
+        while outbound.front().is_some_and(|(at_ms, _)| at_ms.saturating_add(window_ms) <= now) {
+            outbound.pop_front();
+        }
+        outbound.push_back((now, tx.amount));
+    }
+    if tx.timestamp.is_some() {
+        sender_account_clone.last_tx_timestamp = tx.timestamp;
+    }
+
+    // An allowed self-transfer is a pure nonce bump, balances stay as they are
+    if tx.sender != tx.receiver {
+        // // Update Sender bal
+        sender_account_clone.balance -= tx.amount + fee;
+
+        // // Update Receiver Bal. If receiver account, doesn't exist, create it.
+        let receiver_account = accts.entry(tx.receiver.clone()).or_insert(Account { created_seq: seq, created_ms: now, ..Default::default() });
+        receiver_account.balance += tx.amount;
+        receiver_account.last_active_ms = now;
+        if config.bump_receiver_nonce {
+            receiver_account.nonce += 1;
+        }
+    }
+
+    // put the modified sender back into the AccountStore
+    let sender_balance = sender_account_clone.balance;
+    accts.insert(tx.sender.clone(), sender_account_clone);
+
+    if fee > burn {
+        let collector = accts.entry(config.fee_collector.clone()).or_insert(Account { created_seq: seq, created_ms: now, ..Default::default() });
+        collector.balance += fee - burn;
+    }
+    
+    ledger.seq = seq;
+    if let Some(sequence) = sequence {
+        ledger.sequencer_seq = sequence;
+    }
+    ledger.record(HistoryEntry {
+        seq,
+        sender: tx.sender.clone(),
+        receiver: tx.receiver.clone(),
+        amount: tx.amount,
+        fee,
+        at_ms: now,
+        tx_id: tx_id(tx),
+        sender_balance: Some(sender_balance),
+    }, config.max_history);
+    if fee > 0 {
+        ledger.record_fee(now, fee);
+    }
+    if burn > 0 {
+        ledger.burn(burn);
+    }
+
+    tracing::debug!(seq, sender = %tx.sender, receiver = %tx.receiver, amount = tx.amount, fee, "applied transaction");
+
+    Ok(())
+}
+
+// Whether the checks on tx read more than its sender, receiver and fee collector, so it can't be
+// judged on a copy of just those
+pub fn reads_whole_ledger(tx: &Transaction, config: &Config) -> bool {
+    tx.expected_state_root.is_some() || config.max_amount_supply_fraction > 0.0 || config.sequencer_key.is_some()
+}
+
+// handle_transaction without holding the ledger lock while the checks and the signature verification run.
+// The transaction is applied to a copy of its sender, receiver and fee collector taken under the lock, then
+// written back under the lock again; if one of them changed in between, it's applied to the ledger itself.
+// A rejection stands if nothing it looked at changed. Transactions whose checks read more than those
+// accounts (an expected state root, the supply fraction, the sequencer order) skip the copy.
+// Takes the locked ledger and returns it locked again, reacquired with relock.
+pub fn handle_transaction_staged<'a>(
+    tx: &Transaction,
+    ledger: MutexGuard<'a, Ledger>,
+    relock: impl FnOnce() -> MutexGuard<'a, Ledger>,
+    config: &Config,
+    now: u64,
+) -> (MutexGuard<'a, Ledger>, Result<(), TransactionError>) {
+    let mut ledger = ledger;
+    if reads_whole_ledger(tx, config) {
+        let result = handle_transaction(tx, &mut ledger, config, now);
+        return (ledger, result);
+    }
+
+    let mut stage = ledger.stage(&tx.sender, &tx.receiver, &config.fee_collector);
+    drop(ledger);
+    let staged = handle_transaction(tx, &mut stage.ledger, config, now);
+    let mut ledger = relock();
+    let result = match staged {
+        Ok(()) if ledger.merge(&stage, config.max_history, now) => Ok(()),
+        Err(e) if ledger.unchanged_since(&stage) => Err(e),
+        _ => handle_transaction(tx, &mut ledger, config, now),
+    };
+    (ledger, result)
+}
+
+// The largest amount handle_transaction would accept from sender right now, and the fees it would cost,
+// going by the checks that depend on the amount: fees, the per-transfer ceiling, the supply fraction, the
+// velocity window and the amount step. Without a receiver, assumes one that still has to be auto-created,
+// so the amount can be sent anywhere. Frozen, too new or nonce exhausted senders can't send anything.
+pub fn max_spendable(ledger: &Ledger, sender: &str, receiver: Option<&str>, config: &Config, now: u64) -> (u64, u64) {
+    let accts = &ledger.accounts;
+    let Some(account) = accts.get(sender) else {
+        return (0, 0);
+    };
+    if account.frozen
+        || account.nonce == u32::MAX
+        || now.saturating_sub(account.created_ms) < config.min_account_age_secs * 1000
+    {
+        return (0, 0);
+    }
+    if receiver == Some(sender) {
+        // A self-transfer moves nothing, so only the ceilings below apply and no fee is owed
+        if !config.allow_self_transfer {
+            return (0, 0);
+        }
+    } else if let Some(receiver) = receiver
+        && config.require_known_receiver
+        && !accts.contains_key(receiver)
+        && !config.known_receivers.iter().any(|known| known == receiver)
+    {
+        return (0, 0);
+    }
+
+    let is_self = receiver == Some(sender);
+    let creation_fee = match receiver {
+        _ if is_self => 0,
+        Some(receiver) if accts.contains_key(receiver) => 0,
+        _ => config.account_creation_fee,
+    };
+    let fee_for = |amount: u64| {
+        let transfer_fee = if is_self || sender == config.fee_collector { 0 } else { config.transfer_fee(amount) };
+        creation_fee.saturating_add(transfer_fee)
+    };
+
+    let mut ceiling = account.balance;
+    if config.max_amount > 0 {
+        ceiling = ceiling.min(config.max_amount);
+    }
+    if config.velocity_limit > 0 {
+        let window_ms = config.velocity_window_secs.saturating_mul(1000);
+        let spent: u64 = account.outbound.iter()
+            .filter(|(at_ms, _)| at_ms.saturating_add(window_ms) > now)
+            .map(|(_, amount)| amount)
+            .sum();
+        ceiling = ceiling.min(config.velocity_limit.saturating_sub(spent));
+    }
+    if let Some(receiver) = receiver.filter(|_| !is_self) {
+        ceiling = ceiling.min(u64::MAX - accts.get(receiver).map_or(0, |acct| acct.balance));
+    }
+    let supply = total_supply(accts) as f64;
+    let fits = |amount: u64| {
+        let within_supply = config.max_amount_supply_fraction <= 0.0
+            || amount as f64 <= config.max_amount_supply_fraction * supply;
+        let funded = is_self || amount.checked_add(fee_for(amount)).is_some_and(|total| total <= account.balance);
+        within_supply && funded
+    };
+
+    // Every check only gets harder as the amount grows, so the largest accepted amount can be bisected
+    let (mut low, mut high) = (0, ceiling);
+    while low < high {
+        let mid = low + (high - low).div_ceil(2);
+        if fits(mid) {
+            low = mid;
+        } else {
+            high = mid - 1;
+        }
+    }
+    let amount = if config.amount_step > 1 { low - low % config.amount_step } else { low };
+    if amount == 0 {
+        return (0, 0);
+    }
+    (amount, if is_self { 0 } else { fee_for(amount) })
+}
+
+// Admin fan-out from one funded source to many recipients
+#[derive(Debug, Clone, Deserialize)]
+pub struct Distribution {
+    pub from: String,
+    pub recipients: Vec<(String, u64)>, // [[id, amount], ...]
+}
+
+impl Distribution {
+    // Folds repeated recipients into a single credit each, keeping the order of first appearance.
+    // Returns the first id that was listed more than once.
+    pub fn merge_duplicates(&mut self) -> Result<Option<String>, TransactionError> {
+        let mut first_duplicate = None;
+        let mut merged: Vec<(String, u64)> = Vec::with_capacity(self.recipients.len());
+        let mut positions: HashMap<String, usize> = HashMap::new();
+        for (receiver, amount) in self.recipients.drain(..) {
+            match positions.get(&receiver) {
+                Some(&i) => {
+                    merged[i].1 = merged[i].1.checked_add(amount).ok_or(TransactionError::BalanceOverflow)?;
+                    first_duplicate.get_or_insert(receiver);
+                }
+                None => {
+                    positions.insert(receiver.clone(), merged.len());
+                    merged.push((receiver, amount));
+                }
+            }
+        }
+        self.recipients = merged;
+        Ok(first_duplicate)
+    }
+}
+
+// A sender paying several receivers at once, all or nothing, for one nonce
+#[derive(Debug, Clone, Deserialize)]
+pub struct MultiTransfer {
+    pub sender: String,
+    pub outputs: Vec<(String, u64)>, // [[receiver, amount], ...]
+    #[serde(with = "nonce_format")]
+    pub nonce: u32,
+    #[serde(default)]
+    pub signature: Option<String>, // hex ed25519 signature over multi_signing_bytes()
+    #[serde(default)]
+    pub lock_token: Option<String>,
+    #[serde(default)]
+    pub chain_id: Option<String>,
+    #[serde(default)]
+    pub timestamp: Option<u64>, // client-assigned time in unix ms, checked and signed like a transaction's
+    #[serde(default)]
+    pub expected_state_root: Option<String>, // apply only if the state root still matches (see state_root)
+}
+
+pub const MULTI_TAG: &[u8] = b"txh-multi";
+
+// Bytes a multi transfer signature covers: the chain id prefix as in signing_bytes, the ASCII bytes
+// `txh-multi`, the sender as u32 big-endian length plus UTF-8 bytes, the nonce as u32 big-endian,
+// the output count as u32 big-endian, then each receiver (length plus bytes) and amount as u64 big-endian,
+// then the timestamp as u64 big-endian if present
+pub fn multi_signing_bytes(multi: &MultiTransfer) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if let Some(chain_id) = &multi.chain_id {
+        bytes.extend_from_slice(CHAIN_ID_TAG);
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+    }
+    bytes.extend_from_slice(MULTI_TAG);
+    bytes.extend_from_slice(&(multi.sender.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(multi.sender.as_bytes());
+    bytes.extend_from_slice(&multi.nonce.to_be_bytes());
+    bytes.extend_from_slice(&(multi.outputs.len() as u32).to_be_bytes());
+    for (receiver, amount) in &multi.outputs {
+        bytes.extend_from_slice(&(receiver.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(receiver.as_bytes());
+        bytes.extend_from_slice(&amount.to_be_bytes());
+    }
+    if let Some(timestamp) = multi.timestamp {
+        bytes.extend_from_slice(&timestamp.to_be_bytes());
+    }
+    bytes
+}
+
+// Hex SHA-256 of the multi transfer's signing bytes, recorded on each of its history entries
+pub fn multi_tx_id(multi: &MultiTransfer) -> String {
+    hex::encode(Sha256::digest(multi_signing_bytes(multi)))
+}
+
+pub const LOCK_TAG: &[u8] = b"txh-lock";
+
+// Bytes a lock request signature covers: CHAIN_ID_TAG and the configured chain id as in signing_bytes if
+// one is set, the ASCII bytes `txh-lock`, the account as u32 big-endian length plus UTF-8 bytes, then
+// ttl_secs (0 when omitted) and the timestamp as u64 big-endian
+pub fn lock_signing_bytes(chain_id: Option<&str>, account: &str, ttl_secs: u64, timestamp: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    if let Some(chain_id) = chain_id {
+        bytes.extend_from_slice(CHAIN_ID_TAG);
+        bytes.extend_from_slice(&(chain_id.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(chain_id.as_bytes());
+    }
+    bytes.extend_from_slice(LOCK_TAG);
+    bytes.extend_from_slice(&(account.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(account.as_bytes());
+    bytes.extend_from_slice(&ttl_secs.to_be_bytes());
+    bytes.extend_from_slice(&timestamp.to_be_bytes());
+    bytes
+}
+
+// Runs the sender-side checks of handle_transaction against a multi transfer, then applies its outputs
+// (already merged into dist) as one fee-charging distribution
+pub fn handle_multi_transfer(
+    multi: &MultiTransfer,
+    dist: &Distribution,
+    ledger: &mut Ledger,
+    config: &Config,
+    now: u64,
+) -> Result<(), TransactionError> {
+    check_state_root(multi.expected_state_root.as_deref(), &ledger.accounts)?;
+    if let Some(chain_id) = &config.chain_id
+        && multi.chain_id.as_ref() != Some(chain_id)
+    {
+        return Err(TransactionError::WrongChainId);
+    }
+    // The sequencer only orders single transactions
+    if config.sequencer_key.is_some() {
+        return Err(TransactionError::InvalidSequencerSignature);
+    }
+
+    let sender = ledger.accounts.get(&multi.sender).ok_or(TransactionError::AccountNotFound)?;
+    match &sender.public_key {
+        Some(public_key) => verify_signed_bytes(&multi_signing_bytes(multi), multi.signature.as_deref(), public_key)?,
+        None if config.require_signatures => return Err(TransactionError::InvalidSignature),
+        None => {}
+    }
+    if sender.frozen {
+        return Err(TransactionError::AccountFrozen);
+    }
+    if let Some(lock) = &sender.lock
+        && lock.expires_at_ms > now
+        && multi.lock_token.as_deref() != Some(lock.token.as_str())
+    {
+        return Err(TransactionError::AccountLocked);
+    }
+    if now.saturating_sub(sender.created_ms) < config.min_account_age_secs * 1000 {
+        return Err(TransactionError::AccountTooNew);
+    }
+    if dist.recipients.iter().any(|(_, amount)| config.max_amount > 0 && *amount > config.max_amount) {
+        return Err(TransactionError::AmountTooLarge);
+    }
+    let total = dist.recipients.iter().try_fold(0u64, |sum, (_, amount)| sum.checked_add(*amount))
+        .ok_or(TransactionError::BalanceOverflow)?;
+    if config.max_amount_supply_fraction > 0.0
+        && total as f64 > config.max_amount_supply_fraction * total_supply(&ledger.accounts) as f64
+    {
+        return Err(TransactionError::AmountTooLarge);
+    }
+    let window_ms = config.velocity_window_secs.saturating_mul(1000);
+    if config.velocity_limit > 0 {
+        let spent: u64 = sender.outbound.iter()
+            .filter(|(at_ms, _)| at_ms.saturating_add(window_ms) > now)
+            .map(|(_, amount)| amount)
+            .sum();
+        if spent.saturating_add(total) > config.velocity_limit {
+            return Err(TransactionError::VelocityLimitExceeded);
+        }
+    }
+    if sender.nonce != multi.nonce {
+        return Err(TransactionError::InvalidNonce);
+    }
+    check_timestamp(multi.timestamp, sender, config, now)?;
+
+    handle_distribution(dist, ledger, config, now, true)?;
+
+    let (seq, id) = (ledger.seq, multi_tx_id(multi));
+    for entry in ledger.history.iter_mut().rev().take_while(|entry| entry.seq == seq) {
+        entry.tx_id = id.clone();
+    }
+    let sender = ledger.accounts.get_mut(&multi.sender).unwrap();
+    if multi.timestamp.is_some() {
+        sender.last_tx_timestamp = multi.timestamp;
+    }
+    if config.velocity_limit > 0 {
+        while sender.outbound.front().is_some_and(|(at_ms, _)| at_ms.saturating_add(window_ms) <= now) {
+            sender.outbound.pop_front();
+        }
+        sender.outbound.push_back((now, total));
+    }
+    Ok(())
+}
+
+// Applies a distribution atomically: every credit lands or none does. The source pays the summed amount
+// and consumes a single nonce; the whole distribution takes one global sequence number.
+// Missing recipients are auto-created like transaction receivers. With charge_fees, each credit costs
+// the source the same fees a single transfer of that amount would, on top of the amount.
+pub fn handle_distribution(
+    dist: &Distribution,
+    ledger: &mut Ledger,
+    config: &Config,
+    now: u64,
+    charge_fees: bool,
+) -> Result<(), TransactionError> {
+    let seq = ledger.seq + 1;
+    let accts = &mut ledger.accounts;
+
+    // 1. Validate everything up front so a failure leaves the ledger untouched
+    let source = accts.get(&dist.from).ok_or(TransactionError::AccountNotFound)?;
+    let mut total: u64 = 0;
+    let mut credits: HashMap<&String, u64> = HashMap::new();
+    let mut fees: Vec<u64> = Vec::with_capacity(dist.recipients.len()); // per recipient, in order
+    for (receiver, amount) in &dist.recipients {
+        if *amount == 0 {
+            return Err(TransactionError::AmountIsZero);
+        }
+        if config.amount_step > 1 && !amount.is_multiple_of(config.amount_step) {
+            return Err(TransactionError::AmountNotAligned);
+        }
+        if *receiver == dist.from {
+            return Err(TransactionError::SenderIsReceiver);
+        }
+        if config.require_known_receiver && !accts.contains_key(receiver) && !config.known_receivers.contains(receiver) {
+            return Err(TransactionError::UnknownReceiver);
+        }
+        total = total.checked_add(*amount).ok_or(TransactionError::BalanceOverflow)?;
+        let credit = credits.entry(receiver).or_insert(0);
+        *credit = credit.checked_add(*amount).ok_or(TransactionError::BalanceOverflow)?;
+        let creation_fee = if accts.contains_key(receiver) { 0 } else { config.account_creation_fee };
+        let transfer_fee = if dist.from != config.fee_collector { config.transfer_fee(*amount) } else { 0 };
+        fees.push(if charge_fees { creation_fee.saturating_add(transfer_fee) } else { 0 });
+    }
+    let total_fees = fees.iter().try_fold(0u64, |sum, fee| sum.checked_add(*fee)).ok_or(TransactionError::InsufficientFunds)?;
+    // Burned per output, as a rollback unwinds it, so it never exceeds total_fees
+    let burned: u64 = fees.iter().map(|fee| config.fee_burn(*fee)).sum();
+    if total.checked_add(total_fees).is_none_or(|debit| source.balance < debit) {
+        return Err(TransactionError::InsufficientFunds);
+    }
+    for (receiver, credit) in &credits {
+        let balance = accts.get(*receiver).map_or(0, |acct| acct.balance);
+        balance.checked_add(*credit).ok_or(TransactionError::BalanceOverflow)?;
+    }
+    if total_fees > burned && config.fee_collector != dist.from {
+        let collector_balance = accts.get(&config.fee_collector).map_or(0, |acct| acct.balance);
+        collector_balance.checked_add(credits.get(&config.fee_collector).copied().unwrap_or(0))
+            .and_then(|balance| balance.checked_add(total_fees - burned))
+            .ok_or(TransactionError::BalanceOverflow)?;
+    }
+    if source.nonce == u32::MAX {
+        return Err(TransactionError::NonceExhausted);
+    }
+    if config.bump_receiver_nonce {
+        let mut bumps: HashMap<&String, u32> = HashMap::new();
+        for (receiver, _) in &dist.recipients {
+            *bumps.entry(receiver).or_insert(0) += 1;
+        }
+        for (receiver, count) in bumps {
+            let nonce = accts.get(receiver).map_or(0, |acct| acct.nonce);
+            nonce.checked_add(count).ok_or(TransactionError::NonceExhausted)?;
+        }
+    }
+
+    // 2. Apply
+    let source = accts.get_mut(&dist.from).unwrap();
+    source.balance -= total + total_fees;
+    source.nonce += 1;
+    source.last_active_ms = now;
+    for (receiver, amount) in &dist.recipients {
+        let account = accts.entry(receiver.clone()).or_insert(Account { created_seq: seq, created_ms: now, ..Default::default() });
+        account.balance += amount;
+        account.last_active_ms = now;
+        if config.bump_receiver_nonce {
+            account.nonce += 1;
+        }
+    }
+    if total_fees > burned {
+        let collector = accts.entry(config.fee_collector.clone()).or_insert(Account { created_seq: seq, created_ms: now, ..Default::default() });
+        collector.balance += total_fees - burned;
+    }
+
+    ledger.seq = seq;
+    for ((receiver, amount), fee) in dist.recipients.iter().zip(fees) {
+        ledger.record(HistoryEntry {
+            seq,
+            sender: dist.from.clone(),
+            receiver: receiver.clone(),
+            amount: *amount,
+            fee,
+            at_ms: now,
+            tx_id: String::new(),
+            sender_balance: None,
+        }, config.max_history);
+    }
+    if total_fees > 0 {
+        ledger.record_fee(now, total_fees);
+    }
+    if burned > 0 {
+        ledger.burn(burned);
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::ConfigVars;
+    use crate::ledger::{AccountLock, GenesisAccount};
+    use ed25519_dalek::{Signer, SigningKey};
+
+    const NOW: u64 = 1_700_000_000_000;
+
+    fn config() -> Config {
+        Config::from_vars(&ConfigVars::default()).unwrap()
+    }
+
+    // Alice 1000 and Bob 500, both created long before NOW
+    fn ledger() -> Ledger {
+        Ledger::from_genesis(&[
+            GenesisAccount { id: "Alice".into(), balance: 1000, nonce: 0 },
+            GenesisAccount { id: "Bob".into(), balance: 500, nonce: 0 },
+        ])
+    }
+
+    fn transfer(amount: u64, nonce: u32) -> Transaction {
+        Transaction { sender: "Alice".into(), receiver: "Bob".into(), amount, nonce, ..Default::default() }
+    }
+
+    fn key() -> SigningKey {
+        SigningKey::from_bytes(&[7; 32])
+    }
+
+    fn sign(tx: &mut Transaction, key: &SigningKey) {
+        tx.signature = Some(hex::encode(key.sign(&signing_bytes(tx)).to_bytes()));
+    }
+
+    // Runs tx and checks that a rejection leaves the ledger exactly as it was
+    fn reject(tx: &Transaction, ledger: &mut Ledger, config: &Config) -> TransactionError {
+        let before = (state_root(&ledger.accounts), ledger.seq, ledger.history.len());
+        let err = handle_transaction(tx, ledger, config, NOW).unwrap_err();
+        assert_eq!(before, (state_root(&ledger.accounts), ledger.seq, ledger.history.len()), "{:?} mutated the ledger", err);
+        err
+    }
+
+    #[test]
+    fn applies_a_valid_transfer() {
+        let mut ledger = ledger();
+        handle_transaction(&transfer(100, 0), &mut ledger, &config(), NOW).unwrap();
+        assert_eq!(ledger.accounts["Alice"].balance, 900);
+        assert_eq!(ledger.accounts["Alice"].nonce, 1);
+        assert_eq!(ledger.accounts["Bob"].balance, 600);
+        assert_eq!(ledger.seq, 1);
+        let entry = ledger.history.back().unwrap();
+        assert_eq!((entry.seq, entry.amount, entry.sender_balance), (1, 100, Some(900)));
+        assert_eq!(entry.tx_id, tx_id(&transfer(100, 0)));
+    }
+
+    #[test]
+    fn auto_creates_the_receiver_and_charges_fees() {
+        let mut config = config();
+        config.fee_flat = 2;
+        config.account_creation_fee = 5;
+        let mut ledger = ledger();
+        let tx = Transaction { receiver: "Carol".into(), ..transfer(100, 0) };
+        handle_transaction(&tx, &mut ledger, &config, NOW).unwrap();
+        assert_eq!(ledger.accounts["Alice"].balance, 893);
+        assert_eq!(ledger.accounts["Carol"].balance, 100);
+        assert_eq!(ledger.accounts["Carol"].created_seq, 1);
+        assert_eq!(ledger.accounts["fees"].balance, 7);
+        assert_eq!(total_supply(&ledger.accounts), 1500);
+    }
+
+    #[test]
+    fn rejects_unknown_sender() {
+        let tx = Transaction { sender: "Mallory".into(), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config()), TransactionError::AccountNotFound));
+    }
+
+    #[test]
+    fn rejects_zero_amount() {
+        assert!(matches!(reject(&transfer(0, 0), &mut ledger(), &config()), TransactionError::AmountIsZero));
+    }
+
+    #[test]
+    fn rejects_self_transfer_unless_allowed() {
+        let tx = Transaction { receiver: "Alice".into(), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config()), TransactionError::SenderIsReceiver));
+
+        let mut config = config();
+        config.allow_self_transfer = true;
+        let mut ledger = ledger();
+        handle_transaction(&tx, &mut ledger, &config, NOW).unwrap();
+        assert_eq!((ledger.accounts["Alice"].balance, ledger.accounts["Alice"].nonce), (1000, 1));
+    }
+
+    #[test]
+    fn rejects_insufficient_funds_including_fees() {
+        assert!(matches!(reject(&transfer(1001, 0), &mut ledger(), &config()), TransactionError::InsufficientFunds));
+
+        let mut config = config();
+        config.fee_flat = 1;
+        assert!(matches!(reject(&transfer(1000, 0), &mut ledger(), &config), TransactionError::InsufficientFunds));
+    }
+
+    #[test]
+    fn rejects_wrong_nonce() {
+        assert!(matches!(reject(&transfer(1, 1), &mut ledger(), &config()), TransactionError::InvalidNonce));
+    }
+
+    #[test]
+    fn rejects_unknown_receiver_when_required() {
+        let mut config = config();
+        config.require_known_receiver = true;
+        config.known_receivers = vec!["Dave".into()];
+        let tx = Transaction { receiver: "Carol".into(), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config), TransactionError::UnknownReceiver));
+
+        let tx = Transaction { receiver: "Dave".into(), ..transfer(1, 0) };
+        handle_transaction(&tx, &mut ledger(), &config, NOW).unwrap();
+    }
+
+    #[test]
+    fn rejects_missing_or_foreign_signatures() {
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Alice").unwrap().public_key = Some(hex::encode(key().verifying_key().to_bytes()));
+
+        let mut tx = transfer(1, 0);
+        assert!(matches!(reject(&tx, &mut ledger, &config()), TransactionError::InvalidSignature));
+        sign(&mut tx, &SigningKey::from_bytes(&[8; 32]));
+        assert!(matches!(reject(&tx, &mut ledger, &config()), TransactionError::InvalidSignature));
+        sign(&mut tx, &key());
+        handle_transaction(&tx, &mut ledger, &config(), NOW).unwrap();
+
+        let mut config = config();
+        config.require_signatures = true;
+        let tx = Transaction { sender: "Bob".into(), receiver: "Alice".into(), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger, &config), TransactionError::InvalidSignature));
+    }
+
+    #[test]
+    fn rejects_misaligned_amounts() {
+        let mut config = config();
+        config.amount_step = 10;
+        assert!(matches!(reject(&transfer(15, 0), &mut ledger(), &config), TransactionError::AmountNotAligned));
+    }
+
+    #[test]
+    fn rejects_locked_sender_without_token() {
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Alice").unwrap().lock = Some(AccountLock { token: "t".into(), expires_at_ms: NOW + 1000 });
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config()), TransactionError::AccountLocked));
+
+        let tx = Transaction { lock_token: Some("t".into()), ..transfer(1, 0) };
+        handle_transaction(&tx, &mut ledger, &config(), NOW).unwrap();
+    }
+
+    #[test]
+    fn rejects_credits_that_overflow() {
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Bob").unwrap().balance = u64::MAX;
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config()), TransactionError::BalanceOverflow));
+    }
+
+    #[test]
+    fn rejects_missing_and_regressing_timestamps() {
+        let mut config = config();
+        config.monotonic_timestamps = true;
+        let mut ledger = ledger();
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config), TransactionError::MissingTimestamp));
+
+        let tx = Transaction { timestamp: Some(NOW), ..transfer(1, 0) };
+        handle_transaction(&tx, &mut ledger, &config, NOW).unwrap();
+        let tx = Transaction { timestamp: Some(NOW - 1), ..transfer(1, 1) };
+        assert!(matches!(reject(&tx, &mut ledger, &config), TransactionError::TimestampRegression));
+    }
+
+    #[test]
+    fn rejects_timestamps_outside_the_window() {
+        let mut config = config();
+        config.max_future_skew_ms = 1000;
+        config.max_timestamp_age_ms = 5000;
+        let tx = Transaction { timestamp: Some(NOW + 1001), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config), TransactionError::TimestampInFuture));
+        let tx = Transaction { timestamp: Some(NOW - 5001), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config), TransactionError::TimestampTooOld));
+    }
+
+    #[test]
+    fn rejects_amounts_over_the_caps() {
+        let mut config = config();
+        config.max_amount = 100;
+        assert!(matches!(reject(&transfer(101, 0), &mut ledger(), &config), TransactionError::AmountTooLarge));
+
+        let mut config = self::config();
+        config.max_amount_supply_fraction = 0.1;
+        assert!(matches!(reject(&transfer(151, 0), &mut ledger(), &config), TransactionError::AmountTooLarge));
+        handle_transaction(&transfer(150, 0), &mut ledger(), &config, NOW).unwrap();
+    }
+
+    #[test]
+    fn rejects_senders_younger_than_the_minimum_age() {
+        let mut config = config();
+        config.min_account_age_secs = 60;
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Alice").unwrap().created_ms = NOW - 59_000;
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config), TransactionError::AccountTooNew));
+    }
+
+    #[test]
+    fn rejects_frozen_senders() {
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Alice").unwrap().frozen = true;
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config()), TransactionError::AccountFrozen));
+    }
+
+    #[test]
+    fn rejects_a_stale_state_root() {
+        let mut ledger = ledger();
+        let tx = Transaction { expected_state_root: Some("00".repeat(32)), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger, &config()), TransactionError::StateRootMismatch));
+
+        let tx = Transaction { expected_state_root: Some(state_root(&ledger.accounts)), ..transfer(1, 0) };
+        handle_transaction(&tx, &mut ledger, &config(), NOW).unwrap();
+    }
+
+    #[test]
+    fn rejects_unsequenced_and_out_of_order_transactions() {
+        let sequencer = SigningKey::from_bytes(&[9; 32]);
+        let mut config = config();
+        config.sequencer_key = Some(hex::encode(sequencer.verifying_key().to_bytes()));
+        let mut ledger = ledger();
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger, &config), TransactionError::InvalidSequencerSignature));
+
+        let sequenced = |tx: Transaction, sequence: u64| Transaction {
+            sequence: Some(sequence),
+            sequencer_signature: Some(hex::encode(sequencer.sign(&sequencer_bytes(&tx, sequence)).to_bytes())),
+            ..tx
+        };
+        handle_transaction(&sequenced(transfer(1, 0), 5), &mut ledger, &config, NOW).unwrap();
+        assert_eq!(ledger.sequencer_seq, 5);
+        assert!(matches!(reject(&sequenced(transfer(1, 1), 5), &mut ledger, &config), TransactionError::SequenceOutOfOrder));
+    }
+
+    #[test]
+    fn rejects_velocity_over_the_limit() {
+        let mut config = config();
+        config.velocity_limit = 150;
+        let mut ledger = ledger();
+        handle_transaction(&transfer(100, 0), &mut ledger, &config, NOW).unwrap();
+        assert!(matches!(reject(&transfer(51, 1), &mut ledger, &config), TransactionError::VelocityLimitExceeded));
+        // Once the first transfer leaves the window there is room again
+        let later = NOW + config.velocity_window_secs * 1000;
+        handle_transaction(&transfer(51, 1), &mut ledger, &config, later).unwrap();
+    }
+
+    #[test]
+    fn rejects_the_wrong_chain_id() {
+        let mut config = config();
+        config.chain_id = Some("main".into());
+        assert!(matches!(reject(&transfer(1, 0), &mut ledger(), &config), TransactionError::WrongChainId));
+        let tx = Transaction { chain_id: Some("test".into()), ..transfer(1, 0) };
+        assert!(matches!(reject(&tx, &mut ledger(), &config), TransactionError::WrongChainId));
+    }
+
+    #[test]
+    fn rejects_exhausted_nonces() {
+        let mut ledger = ledger();
+        ledger.accounts.get_mut("Alice").unwrap().nonce = u32::MAX;
+        assert!(matches!(reject(&transfer(1, u32::MAX), &mut ledger, &config()), TransactionError::NonceExhausted));
+    }
+
+    #[test]
+    fn maps_every_error_to_a_client_error_status() {
+        use TransactionError::*;
+        for err in [
+            AccountNotFound, AmountIsZero, SenderIsReceiver, InsufficientFunds, InvalidNonce, UnknownReceiver,
+            InvalidSignature, AmountNotAligned, AccountLocked, BalanceOverflow, MissingTimestamp, TimestampRegression,
+            TimestampInFuture, TimestampTooOld, AmountTooLarge, AccountTooNew, AccountFrozen, StateRootMismatch,
+            InvalidSequencerSignature, SequenceOutOfOrder, VelocityLimitExceeded, WrongChainId, NonceAlreadyQueued,
+            NonceExhausted,
+        ] {
+            assert!(err.status_code().is_client_error(), "{:?}", err);
+        }
+        assert_eq!(InvalidNonce.status_code(), StatusCode::CONFLICT);
+        assert_eq!(InsufficientFunds.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[test]
+    fn distribution_is_all_or_nothing() {
+        let mut ledger = ledger();
+        let dist = Distribution { from: "Alice".into(), recipients: vec![("Bob".into(), 10), ("Carol".into(), 0)] };
+        assert!(matches!(handle_distribution(&dist, &mut ledger, &config(), NOW, false), Err(TransactionError::AmountIsZero)));
+        assert_eq!((ledger.accounts["Alice"].balance, ledger.accounts["Bob"].balance, ledger.seq), (1000, 500, 0));
+
+        let dist = Distribution { from: "Alice".into(), recipients: vec![("Bob".into(), 10), ("Carol".into(), 20)] };
+        handle_distribution(&dist, &mut ledger, &config(), NOW, false).unwrap();
+        assert_eq!((ledger.accounts["Alice"].balance, ledger.accounts["Carol"].balance, ledger.seq), (970, 20, 1));
+    }
+
+    #[test]
+    fn merges_duplicate_recipients() {
+        let mut dist = Distribution { from: "Alice".into(), recipients: vec![("Bob".into(), 1), ("Carol".into(), 2), ("Bob".into(), 3)] };
+        assert_eq!(dist.merge_duplicates().unwrap(), Some("Bob".to_string()));
+        assert_eq!(dist.recipients, vec![("Bob".to_string(), 4), ("Carol".to_string(), 2)]);
+    }
+
+    #[test]
+    fn spendable_is_the_largest_amount_a_transfer_accepts() {
+        let base = config();
+        let configs = [
+            Config { fee_bps: 250, fee_min: 3, ..base.clone() },
+            Config { fee_flat: 7, account_creation_fee: 5, ..base.clone() },
+            Config { amount_step: 7, fee_flat: 1, ..base.clone() },
+            Config { max_amount: 300, fee_flat: 1, ..base.clone() },
+            Config { velocity_limit: 400, ..base.clone() },
+            Config { max_amount_supply_fraction: 0.2, ..base.clone() },
+        ];
+        for config in configs {
+            for receiver in ["Bob", "Carol"] {
+                let known = (receiver == "Bob").then_some(receiver);
+                let (amount, fee) = max_spendable(&ledger(), "Alice", known, &config, NOW);
+                let tx = Transaction { receiver: receiver.into(), ..transfer(amount, 0) };
+                let mut applied = ledger();
+                handle_transaction(&tx, &mut applied, &config, NOW).unwrap_or_else(|e| panic!("{:?} with {:?}", e, config));
+                assert_eq!(applied.accounts["Alice"].balance, 1000 - amount - fee);
+
+                let tx = Transaction { amount: amount + config.amount_step.max(1), ..tx };
+                assert!(handle_transaction(&tx, &mut ledger(), &config, NOW).is_err(), "{} more fits with {:?}", tx.amount - amount, config);
+            }
+        }
+    }
+
+    #[test]
+    fn frozen_or_new_accounts_have_nothing_spendable() {
+        let mut frozen = ledger();
+        frozen.accounts.get_mut("Alice").unwrap().frozen = true;
+        assert_eq!(max_spendable(&frozen, "Alice", None, &config(), NOW), (0, 0));
+
+        let config = Config { min_account_age_secs: 60, ..config() };
+        assert_eq!(max_spendable(&ledger(), "Alice", None, &config, 30_000), (0, 0));
+        assert_eq!(max_spendable(&ledger(), "Alice", None, &config, 60_000), (1000, 0));
+    }
+}
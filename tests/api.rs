@@ -0,0 +1,1119 @@
+mod common;
+
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use ed25519_dalek::{Signer, SigningKey};
+use serde_json::json;
+use tower::ServiceExt;
+
+use common::{
+    admin_get, admin_post, app, app_with_clock, balance, config, get, hold_request, post, send, state, state_file, transfer,
+    FlakyStorage, ManualClock, ADMIN_TOKEN,
+};
+use transaction_handler_microservice::ledger::{total_supply, GenesisAccount, Ledger};
+use transaction_handler_microservice::server::{build_router, build_tenant_router, sync_from_primary, AppState};
+use transaction_handler_microservice::storage::{FileStorage, Storage};
+use transaction_handler_microservice::validation::{lock_signing_bytes, multi_signing_bytes, MultiTransfer};
+
+const NOW: u64 = 1_700_000_000_000;
+
+#[tokio::test]
+async fn applies_a_transfer() {
+    let (app, _) = app(&[]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["status"], "ok");
+    assert_eq!(body["seq"], 1);
+    assert_eq!(body["tx_id"].as_str().unwrap().len(), 64);
+    assert_eq!(balance(&app, "Alice").await, 900);
+    assert_eq!(balance(&app, "Bob").await, 600);
+}
+
+#[tokio::test]
+async fn unknown_sender_is_not_found_and_leaves_the_service_usable() {
+    let (app, _) = app(&[]);
+    let (status, body) = transfer(&app, "Mallory", "Bob", 10, 0).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["message"], "AccountNotFound");
+
+    let (status, _) = transfer(&app, "Alice", "Bob", 10, 0).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn zero_amount_is_a_bad_request() {
+    let (app, _) = app(&[]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 0, 0).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["message"], "AmountIsZero");
+    assert_eq!(balance(&app, "Alice").await, 1000);
+}
+
+#[tokio::test]
+async fn bad_nonce_is_a_conflict_with_a_hint() {
+    let (app, _) = app(&[]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 10, 5).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["status"], "error");
+    assert_eq!(body["message"], "InvalidNonce");
+    assert_eq!(body["expected_nonce"], 0);
+    assert!(body.get("current_nonce").is_none());
+    assert_eq!(balance(&app, "Alice").await, 1000);
+}
+
+#[tokio::test]
+async fn malformed_body_is_rejected_before_validation() {
+    let (app, _) = app(&[]);
+    let (status, _) = post(&app, "/submit_transaction", json!({"receiver": "Bob", "amount": 1, "nonce": 0})).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn unknown_account_reads_are_not_found() {
+    let (app, _) = app(&[]);
+    let (status, _) = get(&app, "/account/Mallory").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn accounts_created_after_a_since_cursor_are_listed() {
+    let (app, _) = app(&[]);
+    transfer(&app, "Alice", "Bob", 1, 0).await;
+    let (_, since) = get(&app, "/accounts/since?seq=0").await;
+    let cursor = since["latest"].as_u64().unwrap();
+
+    let (status, _) = post(&app, "/accounts", json!({"id": "Carol"})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (_, since) = get(&app, &format!("/accounts/since?seq={}", cursor)).await;
+    assert_eq!(since["accounts"], json!(["Carol"]));
+    assert!(since["latest"].as_u64().unwrap() > cursor);
+}
+
+#[tokio::test]
+async fn accounts_created_by_set_balances_are_listed_after_the_cursor() {
+    let (app, _) = app(&[]);
+    transfer(&app, "Alice", "Bob", 1, 0).await;
+    let (_, since) = get(&app, "/accounts/since?seq=0").await;
+    let cursor = since["latest"].as_u64().unwrap();
+
+    let (status, body) = admin_post(&app, "/admin/set_balances", json!({"Erin": 5, "Alice": 10})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, since) = get(&app, &format!("/accounts/since?seq={}", cursor)).await;
+    assert_eq!(since["accounts"], json!(["Erin"]));
+}
+
+#[tokio::test]
+async fn delta_at_the_end_of_the_seq_range_does_not_overflow() {
+    let (app, _) = app(&[]);
+    let max = u64::MAX;
+    let (status, body) = get(&app, &format!("/account/Alice/delta?from_seq={}&to_seq={}", max, max)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["delta"], json!(0));
+}
+
+#[tokio::test]
+async fn locking_an_account_without_a_key_needs_the_admin_token() {
+    let (app, _) = app(&[]);
+    let (status, _) = post(&app, "/account/Alice/lock", json!({})).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, grant) = admin_post(&app, "/account/Alice/lock", json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", grant);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::LOCKED);
+    let tx = json!({"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 0, "lock_token": grant["token"]});
+    let (status, body) = post(&app, "/submit_transaction", tx).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn a_signed_lock_request_is_accepted_once() {
+    let (app, _) = app_with_clock(&[], ManualClock::at(NOW));
+    let key = SigningKey::from_bytes(&[9; 32]);
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    let (status, _) = post(&app, "/accounts", json!({"id": "Carol", "public_key": public_key})).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    let signature = hex::encode(key.sign(&lock_signing_bytes(None, "Carol", 10, NOW)).to_bytes());
+    let request = json!({"ttl_secs": 10, "timestamp": NOW, "signature": signature});
+    let (status, grant) = post(&app, "/account/Carol/lock", request.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", grant);
+    assert_eq!(grant["expires_at_ms"], NOW + 10_000);
+
+    let (status, _) = post(&app, "/account/Carol/unlock", json!({"token": grant["token"]})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = post(&app, "/account/Carol/lock", request).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let forged = json!({"ttl_secs": 3600, "timestamp": NOW + 1, "signature": signature});
+    let (status, _) = post(&app, "/account/Carol/lock", forged).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn a_signed_lock_request_that_finds_the_account_locked_can_be_sent_again() {
+    let (app, _) = app_with_clock(&[], ManualClock::at(NOW));
+    let key = SigningKey::from_bytes(&[9; 32]);
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    let (status, _) = post(&app, "/accounts", json!({"id": "Carol", "public_key": public_key})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, held) = admin_post(&app, "/account/Carol/lock", json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", held);
+
+    let signature = hex::encode(key.sign(&lock_signing_bytes(None, "Carol", 10, NOW)).to_bytes());
+    let request = json!({"ttl_secs": 10, "timestamp": NOW, "signature": signature});
+    let (status, _) = post(&app, "/account/Carol/lock", request.clone()).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    let (status, _) = post(&app, "/account/Carol/unlock", json!({"token": held["token"]})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, grant) = post(&app, "/account/Carol/lock", request).await;
+    assert_eq!(status, StatusCode::OK, "{}", grant);
+}
+
+#[tokio::test]
+async fn lock_ttl_is_capped_and_overflow_is_a_bad_request() {
+    let (app, _) = app_with_clock(&[("TXH_MAX_LOCK_TTL_SECS", "60")], ManualClock::at(NOW));
+    let (status, grant) = admin_post(&app, "/account/Alice/lock", json!({"ttl_secs": u64::MAX})).await;
+    assert_eq!(status, StatusCode::OK, "{}", grant);
+    assert_eq!(grant["expires_at_ms"], NOW + 60_000);
+
+    let max = u64::MAX.to_string();
+    let (app, _) = app_with_clock(&[("TXH_MAX_LOCK_TTL_SECS", &max)], ManualClock::at(NOW));
+    let (status, _) = admin_post(&app, "/account/Bob/lock", json!({"ttl_secs": u64::MAX})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, body) = admin_post(&app, "/account/Bob/lock", json!({"ttl_secs": 0})).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+}
+
+#[tokio::test]
+async fn a_lock_expires_on_the_clock() {
+    let clock = ManualClock::at(NOW);
+    let (app, _) = app_with_clock(&[], clock.clone());
+    let (status, _) = admin_post(&app, "/account/Alice/lock", json!({"ttl_secs": 5})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::LOCKED);
+
+    clock.advance(5_000);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn a_maintenance_window_holds_back_admin_writes_too() {
+    let clock = ManualClock::at(NOW);
+    let (start, end) = (NOW.to_string(), (NOW + 60_000).to_string());
+    let (app, _) = app_with_clock(&[("TXH_MAINTENANCE_START", &start), ("TXH_MAINTENANCE_END", &end)], clock.clone());
+    let (status, body) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    let (status, body) = admin_post(&app, "/admin/set_balances", json!({"Alice": 10})).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    let (status, body) = admin_post(&app, "/account/Bob/freeze", json!({})).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 1000);
+
+    clock.advance(60_000);
+    let (status, body) = admin_post(&app, "/admin/set_balances", json!({"Alice": 10})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn the_maintenance_window_can_still_be_cleared_by_a_reload() {
+    let (start, end) = (NOW.to_string(), (NOW + 60_000).to_string());
+    let (app, _) = app_with_clock(&[("TXH_MAINTENANCE_START", &start), ("TXH_MAINTENANCE_END", &end)], ManualClock::at(NOW));
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    // The environment schedules no window, so the reload clears it
+    let (status, body) = admin_post(&app, "/admin/reload_config", json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn locks_are_written_to_the_state_file() {
+    let path = state_file("locks");
+    let (app, _) = app(&[("TXH_STATE_FILE", &path)]);
+    let (status, grant) = admin_post(&app, "/account/Alice/lock", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let saved = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+    assert_eq!(saved.accounts["Alice"].lock.as_ref().unwrap().token, grant["token"]);
+    let (status, _) = post(&app, "/account/Alice/unlock", json!({"token": grant["token"]})).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(FileStorage::new(&path, 1000).load().unwrap().unwrap().accounts["Alice"].lock.is_none());
+}
+
+#[tokio::test]
+async fn a_restarted_server_picks_up_every_acknowledged_change() {
+    let path = state_file("restart");
+    let vars = [("TXH_STATE_FILE", path.as_str()), ("TXH_SNAPSHOT_EVERY", "3")];
+    let (app, _) = app(&vars);
+    for nonce in 0..5 {
+        let (status, body) = transfer(&app, "Alice", "Carol", 10, nonce).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+    }
+    let (status, _) = admin_post(&app, "/account/Bob/freeze", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    drop(app);
+
+    let saved = FileStorage::new(&path, 3).load().unwrap().unwrap();
+    let state = AppState::new(config(&vars), saved, SigningKey::from_bytes(&[1; 32]));
+    let app = build_router(state);
+    assert_eq!(balance(&app, "Alice").await, 950);
+    assert_eq!(balance(&app, "Carol").await, 50);
+    let (_, account) = get(&app, "/account/Alice").await;
+    assert_eq!(account["nonce"], 5);
+    let (status, _) = transfer(&app, "Bob", "Alice", 1, 0).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = transfer(&app, "Alice", "Carol", 10, 5).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["seq"], 6);
+}
+
+#[tokio::test]
+async fn a_retry_after_a_restart_gets_the_original_outcome_from_saved_history() {
+    let path = state_file("restart-retry");
+    let vars = [("TXH_STATE_FILE", path.as_str()), ("TXH_DEDUP_WINDOW_MS", "60000")];
+    let (app, _) = app(&vars);
+    let (status, applied) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", applied);
+    drop(app);
+
+    // The dedup window didn't survive, the saved history did
+    let saved = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+    let app = build_router(AppState::new(config(&vars), saved, SigningKey::from_bytes(&[1; 32])));
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!((&body["tx_id"], &body["seq"]), (&applied["tx_id"], &applied["seq"]), "{}", body);
+    assert!(body["message"].as_str().unwrap().contains("applied at seq 1"), "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 900);
+}
+
+#[tokio::test]
+async fn a_failed_save_answers_500_and_rolls_the_change_back() {
+    let path = state_file("failed-save");
+    let storage = FlakyStorage::new(&path);
+    let state = state(&[]).with_storage(storage.clone());
+    let app = build_router(state);
+    let (status, _) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK);
+
+    storage.failing.store(true, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 1).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR, "{}", body);
+    let (status, _) = admin_post(&app, "/account/Bob/freeze", json!({})).await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(balance(&app, "Alice").await, 900);
+    let (_, alerts) = admin_get(&app, "/admin/alerts").await;
+    assert_eq!(alerts[0]["kind"], "save_failed");
+
+    // Nothing of the failed attempts survived, so the same nonce goes through once saving works again
+    storage.failing.store(false, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["seq"], 2);
+    let (status, _) = transfer(&app, "Bob", "Alice", 1, 0).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn transient_save_errors_are_retried_until_the_retries_run_out() {
+    let path = state_file("transient-save");
+    let storage = FlakyStorage::new(&path);
+    let state = state(&[("TXH_STORAGE_RETRIES", "2"), ("TXH_STORAGE_RETRY_BACKOFF_MS", "1")]).with_storage(storage.clone());
+    let app = build_router(state);
+
+    storage.transient_failures.store(2, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(storage.transient_failures.load(Ordering::SeqCst), 0);
+    let saved = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+    assert_eq!((saved.seq, saved.accounts["Alice"].balance), (1, 900));
+
+    // A third timeout in a row is one too many: 503, and the change is rolled back
+    storage.transient_failures.store(3, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 1).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 900);
+}
+
+#[tokio::test]
+async fn spendable_leaves_room_for_fees() {
+    let (app, _) = app(&[("TXH_FEE_FLAT", "10"), ("TXH_ACCOUNT_CREATION_FEE", "5")]);
+    let (status, body) = get(&app, "/account/Alice/available").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!((body["spendable"].as_u64(), body["fee"].as_u64()), (Some(985), Some(15)));
+
+    let (_, body) = get(&app, "/account/Alice/available?receiver=Bob").await;
+    assert_eq!((body["spendable"].as_u64(), body["fee"].as_u64()), (Some(990), Some(10)));
+    let (status, body) = transfer(&app, "Alice", "Bob", 990, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 0);
+}
+
+#[tokio::test]
+async fn body_logging_passes_event_streams_through() {
+    let (app, _) = app(&[("TXH_LOG_BODIES", "true")]);
+    let request = Request::get("/events").body(Body::empty()).unwrap();
+    let response = tokio::time::timeout(Duration::from_secs(5), app.oneshot(request)).await
+        .expect("the event stream was buffered by the body logger")
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn body_logging_keeps_large_snapshots_restorable() {
+    let (app, _) = app(&[("TXH_LOG_BODIES", "true")]);
+    transfer(&app, "Alice", "Bob", 10, 0).await;
+    let (status, snapshot) = admin_get(&app, "/admin/snapshot").await;
+    assert_eq!(status, StatusCode::OK, "{}", snapshot);
+
+    // Trailing whitespace takes the upload past the logging cap without changing the snapshot
+    let body = format!("{}{}", snapshot, " ".repeat(2 * 1024 * 1024));
+    let request = Request::post("/admin/restore")
+        .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+        .body(Body::from(body))
+        .unwrap();
+    let (status, stats) = send(&app, request).await;
+    assert_eq!(status, StatusCode::OK, "{}", stats);
+    assert_eq!(stats["latest_seq"], 1);
+    assert_eq!(balance(&app, "Bob").await, 510);
+}
+
+#[tokio::test]
+async fn replicas_accept_config_reloads_but_not_writes() {
+    let (app, _) = app(&[("TXH_ROLE", "replica")]);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    let (status, body) = admin_post(&app, "/admin/reload_config", json!({})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn a_replica_syncs_the_ledger_from_its_primary() {
+    let (primary, _) = app(&[]);
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let served = primary.clone();
+    tokio::spawn(async move { axum::serve(listener, served.into_make_service()).await.unwrap() });
+    let (status, _) = transfer(&primary, "Alice", "Carol", 40, 0).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (replica, replica_state) = app(&[("TXH_ROLE", "replica"), ("TXH_PRIMARY_URL", &url), ("TXH_PRIMARY_TOKEN", ADMIN_TOKEN)]);
+    assert_eq!(sync_from_primary(&replica_state).await, Ok(1));
+    assert_eq!(balance(&replica, "Alice").await, 960);
+    assert_eq!(balance(&replica, "Carol").await, 40);
+    let (status, _) = transfer(&replica, "Alice", "Bob", 1, 1).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    let (_, no_token) = app(&[("TXH_ROLE", "replica"), ("TXH_PRIMARY_URL", &url)]);
+    let err = sync_from_primary(&no_token).await.unwrap_err();
+    assert!(err.contains("401"), "{}", err);
+}
+
+#[tokio::test]
+async fn a_retry_gets_the_original_receipt_even_if_the_sender_can_no_longer_afford_it() {
+    let (app, _) = app(&[]);
+    let (status, first) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", first);
+    let (status, _) = transfer(&app, "Alice", "Bob", 900, 1).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = admin_post(&app, "/account/Alice/freeze", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, retry) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!((&retry["tx_id"], &retry["seq"]), (&first["tx_id"], &first["seq"]));
+    assert_eq!(balance(&app, "Bob").await, 1500);
+
+    // A different payload on a used nonce is still rejected
+    let (status, _) = transfer(&app, "Alice", "Bob", 101, 0).await;
+    assert_ne!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn every_nonce_rejection_carries_the_expected_nonce() {
+    let (app, _) = app(&[("TXH_NONCE_QUEUE_GAP", "5")]);
+    transfer(&app, "Alice", "Bob", 1, 0).await;
+
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 3).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, body) = transfer(&app, "Alice", "Bob", 2, 3).await;
+    assert_eq!((status, &body["message"]), (StatusCode::CONFLICT, &json!("NonceAlreadyQueued")));
+    assert_eq!(body["expected_nonce"], 1);
+
+    let batch = json!([
+        {"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 1},
+        {"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 9},
+    ]);
+    let (status, body) = post(&app, "/submit_batch", batch).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["results"][1]["expected_nonce"], 2, "{}", body);
+
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 1]], "nonce": 9});
+    let (status, body) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["expected_nonce"], 1, "{}", body);
+
+    let call = json!({"jsonrpc": "2.0", "id": 1, "method": "submit_transaction",
+        "params": {"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 9}});
+    let (_, body) = post(&app, "/rpc", call).await;
+    assert_eq!(body["error"]["data"]["expected_nonce"], 1, "{}", body);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn metrics_show_how_long_the_ledger_lock_was_waited_for() {
+    let (app, state) = app(&[]);
+    let (held, holding) = std::sync::mpsc::channel();
+    let holder = std::thread::spawn(move || {
+        let _ledger = state.lock_ledger();
+        held.send(()).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+    });
+    holding.recv().unwrap();
+
+    // /metrics takes the ledger lock too, so it reports its own wait behind the holder
+    let (_, metrics) = get(&app, "/metrics").await;
+    holder.join().unwrap();
+    let metrics = metrics.as_str().unwrap();
+    assert!(metrics.contains("# TYPE txh_ledger_lock_waiters gauge\ntxh_ledger_lock_waiters 0\n"), "{}", metrics);
+    let waited: f64 = metrics.lines()
+        .find_map(|line| line.strip_prefix("txh_ledger_lock_last_wait_seconds "))
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(waited >= 0.05, "{}", metrics);
+}
+
+#[tokio::test]
+async fn transactions_beyond_the_inflight_limit_are_shed_or_wait_their_turn() {
+    let held = json!({"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 0});
+
+    // Shedding: the excess is answered 503 right away, and gets through again once the slot is free
+    let (shedding, _) = app(&[("TXH_MAX_INFLIGHT", "1"), ("TXH_SHED_OVERLOAD", "true")]);
+    let (pending, release) = hold_request(&shedding, "/submit_transaction", held.clone()).await;
+    let (status, body) = transfer(&shedding, "Bob", "Carol", 1, 0).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert!(body["message"].as_str().unwrap().contains("in flight"), "{}", body);
+    release.send(()).unwrap();
+    assert_eq!(pending.await.0, StatusCode::OK);
+    let (status, _) = transfer(&shedding, "Bob", "Carol", 1, 0).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Queueing: the excess waits for the slot instead
+    let (app, _) = app(&[("TXH_MAX_INFLIGHT", "1")]);
+    let (pending, release) = hold_request(&app, "/submit_transaction", held).await;
+    let mut queued = Box::pin(transfer(&app, "Bob", "Carol", 1, 0));
+    assert!(futures_util::poll!(&mut queued).is_pending());
+    // Reads aren't limited
+    assert_eq!(balance(&app, "Bob").await, 500);
+    release.send(()).unwrap();
+    assert_eq!(pending.await.0, StatusCode::OK);
+    let (status, body) = queued.await;
+    assert_eq!((status, &body["seq"]), (StatusCode::OK, &json!(2)), "{}", body);
+}
+
+#[tokio::test]
+async fn json_rpc_submissions_count_against_the_inflight_limit() {
+    let (app, _) = app(&[("TXH_MAX_INFLIGHT", "1"), ("TXH_SHED_OVERLOAD", "true")]);
+    let call = json!({"jsonrpc": "2.0", "id": 1, "method": "submit_transaction",
+        "params": {"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 0}});
+    let (pending, release) = hold_request(&app, "/rpc", call).await;
+
+    let (status, body) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    let (status, _) = post(&app, "/rpc", json!({"jsonrpc": "2.0", "id": 2, "method": "get_sequence"})).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+
+    release.send(()).unwrap();
+    let (status, body) = pending.await;
+    assert_eq!((status, &body["result"]["seq"]), (StatusCode::OK, &json!(1)), "{}", body);
+    let (status, body) = transfer(&app, "Alice", "Bob", 1, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn a_batch_goes_through_the_same_bookkeeping_as_single_submissions() {
+    let (app, _) = app(&[("TXH_NONCE_QUEUE_GAP", "5")]);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 2).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    let batch = json!([
+        {"sender": "Alice", "receiver": "Bob", "amount": 10, "nonce": 0},
+        {"sender": "Alice", "receiver": "Carol", "amount": 20, "nonce": 1},
+    ]);
+    let (status, body) = post(&app, "/submit_batch", batch.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    // The queued nonce 2 drained once the batch caught up to it
+    let (_, account) = get(&app, "/account/Alice").await;
+    assert_eq!((account["balance"].as_u64(), account["nonce"].as_u64()), (Some(969), Some(3)));
+
+    // A retry is recognised instead of failing on the spent nonces
+    let (status, body) = post(&app, "/submit_batch", batch).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!((body["applied"].as_u64(), &body["results"][1]["status"]), (Some(0), &json!("duplicate")));
+
+    // A failing atomic batch leaves no trace, history included
+    let batch = json!([
+        {"sender": "Bob", "receiver": "Dave", "amount": 5, "nonce": 0},
+        {"sender": "Bob", "receiver": "Dave", "amount": 5000, "nonce": 1},
+    ]);
+    let (status, _) = post(&app, "/submit_batch", batch).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    let (status, _) = get(&app, "/account/Dave").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = get(&app, "/ledger").await;
+    assert_eq!(body["total"], 3, "{}", body);
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    let metrics = metrics.as_str().unwrap();
+    assert!(metrics.contains("txh_transactions_total{result=\"Processed\"} 3"), "{}", metrics);
+    assert!(metrics.contains("txh_transactions_total{result=\"Duplicate\"} 2"), "{}", metrics);
+    assert!(metrics.contains("txh_value_transferred_total 31"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn queued_nonces_drain_whichever_route_advances_the_nonce() {
+    let (app, _) = app(&[("TXH_NONCE_QUEUE_GAP", "5"), ("TXH_BUMP_RECEIVER_NONCE", "true")]);
+    let nonce = |app: &axum::Router, id: &'static str| {
+        let app = app.clone();
+        async move { get(&app, &format!("/account/{}", id)).await.1["nonce"].as_u64().unwrap() }
+    };
+
+    let (status, _) = transfer(&app, "Alice", "Carol", 1, 1).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 10]], "nonce": 0});
+    let (status, body) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(nonce(&app, "Alice").await, 2);
+
+    let (status, _) = transfer(&app, "Alice", "Carol", 1, 3).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let quarantine = json!({"transaction": {"sender": "Alice", "receiver": "Dave", "amount": 5, "nonce": 2}});
+    let (status, body) = admin_post(&app, "/transfer_and_freeze", quarantine).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(nonce(&app, "Alice").await, 4);
+
+    let (status, _) = transfer(&app, "Alice", "Carol", 1, 5).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, body) = admin_post(&app, "/distribute", json!({"from": "Alice", "recipients": [["Bob", 1]]})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(nonce(&app, "Alice").await, 6);
+
+    // Receiving bumps Bob's nonce from 2 to 3, which is what his queued transaction waits for
+    let (status, _) = transfer(&app, "Bob", "Carol", 1, 3).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let (status, body) = admin_post(&app, "/distribute", json!({"from": "Alice", "recipients": [["Bob", 1]]})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(nonce(&app, "Bob").await, 4);
+    assert_eq!(balance(&app, "Carol").await, 4);
+}
+
+#[tokio::test]
+async fn multi_transfers_distributions_and_quarantines_are_counted() {
+    let (app, _) = app(&[]);
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 10], ["Carol", 5]], "nonce": 0});
+    let (status, _) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::OK);
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 11]], "nonce": 0});
+    let (status, _) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = admin_post(&app, "/distribute", json!({"from": "Alice", "recipients": [["Bob", 1], ["Dave", 2]]})).await;
+    assert_eq!(status, StatusCode::OK);
+    let quarantine = json!({"transaction": {"sender": "Bob", "receiver": "Erin", "amount": 7, "nonce": 0}});
+    let (status, _) = admin_post(&app, "/transfer_and_freeze", quarantine).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (_, metrics) = get(&app, "/metrics").await;
+    let metrics = metrics.as_str().unwrap();
+    assert!(metrics.contains("txh_transactions_total{result=\"Processed\"} 3"), "{}", metrics);
+    assert!(metrics.contains("txh_transactions_total{result=\"InvalidNonce\"} 1"), "{}", metrics);
+    assert!(metrics.contains("txh_value_transferred_total 25"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn an_unknown_receiver_fails_a_non_atomic_batch_that_requires_existing_receivers() {
+    let (app, _) = app(&[]);
+    let batch = json!({
+        "transactions": [
+            {"sender": "Alice", "receiver": "Bob", "amount": 10, "nonce": 0},
+            {"sender": "Alice", "receiver": "Zed", "amount": 10, "nonce": 1},
+            {"sender": "Alice", "receiver": "Bob", "amount": 10, "nonce": 2},
+        ],
+        "atomic": false,
+        "require_existing_receivers": true,
+    });
+    let (status, body) = post(&app, "/submit_batch", batch).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+    assert_eq!((&body["committed"], &body["failed_index"]), (&json!(false), &json!(1)));
+    assert_eq!(body["results"][2]["status"], "skipped");
+    assert_eq!(balance(&app, "Alice").await, 1000);
+    let (_, account) = get(&app, "/account/Alice").await;
+    assert_eq!(account["nonce"], 0);
+}
+
+#[tokio::test]
+async fn a_zero_amount_at_position_3_leaves_every_balance_and_nonce_untouched() {
+    let (app, _) = app(&[]);
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 10], ["Carol", 5], ["Dave", 0], ["Erin", 1]], "nonce": 0});
+    let (status, body) = post(&app, "/submit_multi", multi).await;
+    assert_eq!((status, &body["message"]), (StatusCode::BAD_REQUEST, &json!("AmountIsZero")));
+
+    let (_, alice) = get(&app, "/account/Alice").await;
+    assert_eq!((alice["balance"].as_u64(), alice["nonce"].as_u64()), (Some(1000), Some(0)));
+    assert_eq!(balance(&app, "Bob").await, 500);
+    for id in ["Carol", "Dave", "Erin"] {
+        let (status, _) = get(&app, &format!("/account/{}", id)).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{} was created", id);
+    }
+    let (_, body) = get(&app, "/ledger").await;
+    assert_eq!(body["total"], 0, "{}", body);
+}
+
+#[tokio::test]
+async fn a_multi_transfer_is_checked_like_a_transaction_and_retried_idempotently() {
+    let (app, _) = app_with_clock(&[("TXH_MAX_TIMESTAMP_AGE", "60000")], ManualClock::at(NOW));
+    let key = SigningKey::from_bytes(&[9; 32]);
+    let public_key = hex::encode(key.verifying_key().to_bytes());
+    let (status, _) = post(&app, "/accounts", json!({"id": "Carol", "public_key": public_key})).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let fund = json!({"sender": "Alice", "receiver": "Carol", "amount": 100, "nonce": 0, "timestamp": NOW});
+    let (status, _) = post(&app, "/submit_transaction", fund).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let signed = |timestamp: u64| {
+        let mut multi = json!({"sender": "Carol", "outputs": [["Bob", 10], ["Dave", 5]], "nonce": 0, "timestamp": timestamp});
+        let parsed: MultiTransfer = serde_json::from_value(multi.clone()).unwrap();
+        multi["signature"] = json!(hex::encode(key.sign(&multi_signing_bytes(&parsed)).to_bytes()));
+        multi
+    };
+
+    // The timestamp is covered by the signature and goes through the same window as a transaction's
+    let mut forged = signed(NOW);
+    forged["timestamp"] = json!(NOW + 1);
+    let (status, _) = post(&app, "/submit_multi", forged).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let (status, body) = post(&app, "/submit_multi", signed(NOW - 120_000)).await;
+    assert_eq!((status, &body["message"]), (StatusCode::UNPROCESSABLE_ENTITY, &json!("TimestampTooOld")));
+
+    let mut stale = signed(NOW);
+    stale["expected_state_root"] = json!("00".repeat(32));
+    let (status, body) = post(&app, "/submit_multi", stale).await;
+    assert_eq!((status, &body["message"]), (StatusCode::CONFLICT, &json!("StateRootMismatch")));
+
+    let mut multi = signed(NOW);
+    multi["expected_state_root"] = get(&app, "/state_root").await.1["state_root"].clone();
+    let (status, applied) = post(&app, "/submit_multi", multi.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", applied);
+    assert_eq!(applied["tx_id"].as_str().unwrap().len(), 64);
+    let (status, entry) = get(&app, &format!("/transactions/{}", applied["tx_id"].as_str().unwrap())).await;
+    assert_eq!((status, &entry["seq"]), (StatusCode::OK, &applied["seq"]));
+
+    // A retry gets the original seq back instead of InvalidNonce, and moves nothing
+    let (status, retry) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!((&retry["seq"], &retry["tx_id"]), (&applied["seq"], &applied["tx_id"]));
+    assert!(retry["message"].as_str().unwrap().starts_with("Duplicate"), "{}", retry);
+    assert_eq!(balance(&app, "Bob").await, 510);
+    assert_eq!(get(&app, "/account/Carol").await.1["nonce"], 1);
+}
+
+// Submits a 20 transaction batch from Alice and, once Alice's nonce shows it has started, a transfer from Bob.
+// Returns the seq Bob's transfer got.
+async fn transfer_during_batch(isolation: &str) -> u64 {
+    let (app, _) = app(&[]);
+    let transactions: Vec<_> = (0..20).map(|nonce| json!({"sender": "Alice", "receiver": "Carol", "amount": 1, "nonce": nonce})).collect();
+    let batch = json!({"transactions": transactions, "isolation": isolation});
+    let submitted = tokio::spawn({
+        let app = app.clone();
+        async move { post(&app, "/submit_batch", batch).await }
+    });
+    loop {
+        tokio::task::yield_now().await;
+        if get(&app, "/account/Alice").await.1["nonce"] != 0 {
+            break;
+        }
+    }
+    let (status, body) = transfer(&app, "Bob", "Dave", 1, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, outcome) = submitted.await.unwrap();
+    assert_eq!((status, outcome["applied"].as_u64()), (StatusCode::OK, Some(20)), "{}", outcome);
+    assert_eq!(balance(&app, "Carol").await, 20);
+    body["seq"].as_u64().unwrap()
+}
+
+#[tokio::test]
+async fn only_a_read_committed_batch_lets_other_requests_interleave() {
+    // Serializable: the batch is over by the time anyone else gets the lock
+    assert_eq!(transfer_during_batch("serializable").await, 21);
+    // Read-committed: Bob's transfer lands between two of the batch's transactions
+    assert!(transfer_during_batch("read_committed").await < 21);
+
+    // The price: a failure only rolls back its own transaction, the ones before it stay committed
+    let (app, _) = app(&[]);
+    let batch = json!({"transactions": [
+        {"sender": "Alice", "receiver": "Bob", "amount": 10, "nonce": 0},
+        {"sender": "Alice", "receiver": "Bob", "amount": 5000, "nonce": 1},
+        {"sender": "Alice", "receiver": "Bob", "amount": 10, "nonce": 1},
+    ], "isolation": "read_committed"});
+    let (status, body) = post(&app, "/submit_batch", batch).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!((&body["committed"], &body["applied"], &body["failed_index"]), (&json!(false), &json!(1), &json!(1)));
+    assert_eq!(body["results"][2]["status"], "skipped");
+    assert_eq!(balance(&app, "Bob").await, 510);
+}
+
+#[tokio::test]
+async fn single_submissions_are_not_starved_by_a_chunked_batch() {
+    let (app, _) = app(&[("TXH_BATCH_CHUNK_SIZE", "5")]);
+    let transactions: Vec<_> = (0..40).map(|nonce| json!({"sender": "Alice", "receiver": "Carol", "amount": 1, "nonce": nonce})).collect();
+    let batch = json!({"transactions": transactions, "isolation": "read_committed"});
+    let submitted = tokio::spawn({
+        let app = app.clone();
+        async move { post(&app, "/submit_batch", batch).await }
+    });
+
+    // Bob keeps submitting for as long as the batch runs
+    let mut seen = Vec::new();
+    while !submitted.is_finished() {
+        tokio::task::yield_now().await;
+        let batch_nonce = get(&app, "/account/Alice").await.1["nonce"].as_u64().unwrap();
+        let (status, body) = transfer(&app, "Bob", "Dave", 1, seen.len() as u32).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        seen.push(batch_nonce);
+    }
+    let (status, outcome) = submitted.await.unwrap();
+    assert_eq!((status, outcome["applied"].as_u64()), (StatusCode::OK, Some(40)), "{}", outcome);
+
+    // Bob got in between the chunks, never inside one
+    let during: Vec<_> = seen.iter().filter(|nonce| (1..40).contains(*nonce)).collect();
+    assert!(during.len() >= 3, "{:?}", seen);
+    assert!(during.iter().all(|nonce| *nonce % 5 == 0), "{:?}", seen);
+    assert_eq!(balance(&app, "Dave").await, seen.len() as u64);
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn concurrent_transfers_between_overlapping_accounts_keep_supply_and_nonces_exact() {
+    const ACCOUNTS: usize = 8;
+    const NONCES: u32 = 150;
+    let genesis: Vec<_> = (0..ACCOUNTS).map(|i| GenesisAccount { id: format!("acct-{}", i), balance: 10_000, nonce: 0 }).collect();
+    let state = AppState::new(config(&[("TXH_FEE_FLAT", "1")]), Ledger::from_genesis(&genesis), SigningKey::from_bytes(&[1; 32]));
+    let app = build_router(state.clone());
+    let supply = total_supply(&state.lock_ledger().accounts);
+
+    // Two racers per sender go through the same nonces, to overlapping receivers in both directions;
+    // each nonce must be taken by exactly one of them
+    let racers: Vec<_> = (0..ACCOUNTS * 2).map(|racer| {
+        let app = app.clone();
+        let sender = format!("acct-{}", racer / 2);
+        let receiver = format!("acct-{}", (racer / 2 + 1 + racer % 2 * 2) % ACCOUNTS);
+        tokio::spawn(async move {
+            let mut taken = Vec::new();
+            for nonce in 0..NONCES {
+                loop {
+                    let (status, body) = transfer(&app, &sender, &receiver, 1 + nonce as u64 % 7, nonce).await;
+                    if status == StatusCode::OK {
+                        taken.push(nonce);
+                        break;
+                    }
+                    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+                    if body["expected_nonce"].as_u64().unwrap() > nonce as u64 {
+                        break;
+                    }
+                    tokio::task::yield_now().await;
+                }
+            }
+            (sender, taken)
+        })
+    }).collect();
+
+    // Meanwhile nobody may see a debit without its credit
+    let reader = tokio::spawn({
+        let state = state.clone();
+        let racing = racers.iter().map(|racer| racer.abort_handle()).collect::<Vec<_>>();
+        async move {
+            while racing.iter().any(|racer| !racer.is_finished()) {
+                assert_eq!(total_supply(&state.lock_ledger().accounts), supply);
+                tokio::task::yield_now().await;
+            }
+        }
+    });
+
+    let mut taken: HashMap<String, Vec<u32>> = HashMap::new();
+    for racer in racers {
+        let (sender, nonces) = racer.await.unwrap();
+        taken.entry(sender).or_default().extend(nonces);
+    }
+    reader.await.unwrap();
+
+    let ledger = state.lock_ledger();
+    for (sender, mut nonces) in taken {
+        nonces.sort_unstable();
+        assert_eq!(nonces, (0..NONCES).collect::<Vec<_>>(), "{}", sender);
+        assert_eq!(ledger.accounts[&sender].nonce, NONCES, "{}", sender);
+    }
+    assert_eq!(total_supply(&ledger.accounts), supply);
+    assert_eq!(ledger.seq, ACCOUNTS as u64 * NONCES as u64);
+    assert!(ledger.history.iter().map(|entry| entry.seq).eq(1..=ledger.seq));
+    assert_eq!(ledger.audit().discrepancies, Vec::<String>::new());
+}
+
+#[tokio::test]
+async fn tenants_with_the_same_account_ids_are_fully_isolated() {
+    let path = state_file("tenants");
+    let tenant_files = ["acme", "globex"].map(|tenant| format!("{}.tenant-{}", path, tenant));
+    for file in &tenant_files {
+        let _ = std::fs::remove_file(file);
+        let _ = std::fs::remove_file(format!("{}.wal", file));
+    }
+    let default = state(&[("TXH_TENANTS", "acme,globex"), ("TXH_STATE_FILE", &path)]);
+    let acme = default.for_tenant("acme", common::ledger());
+    let globex = default.for_tenant("globex", common::ledger());
+    let app = build_tenant_router(default.clone(), vec![acme.clone(), globex.clone()]);
+    let as_acme = |request: axum::http::request::Builder| request.header("x-tenant", "acme");
+
+    // acme's Alice pays Bob; the other Alices keep their balance and nonce
+    let tx = json!({"sender": "Alice", "receiver": "Bob", "amount": 300, "nonce": 0});
+    let request = as_acme(Request::post("/submit_transaction")).header("content-type", "application/json");
+    let (status, body) = send(&app, request.body(Body::from(tx.to_string())).unwrap()).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, body) = send(&app, as_acme(Request::get("/account/Alice")).body(Body::empty()).unwrap()).await;
+    assert_eq!((body["balance"].as_u64(), body["nonce"].as_u64()), (Some(700), Some(1)));
+    assert_eq!(balance(&app, "Alice").await, 1000);
+    let (_, body) = get(&app, "/t/globex/account/Alice").await;
+    assert_eq!((body["balance"].as_u64(), body["nonce"].as_u64()), (Some(1000), Some(0)));
+
+    // Nonces are per tenant, so nonce 0 is still fresh for globex's Alice
+    let (status, body) = post(&app, "/t/globex/submit_transaction", tx.clone()).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, _) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = post(&app, "/t/acme/submit_transaction", json!({"sender": "Alice", "receiver": "Bob", "amount": 1, "nonce": 0})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // An admin-minted account only exists, and only adds to the supply, in its tenant
+    let (status, body) = admin_post(&app, "/t/globex/accounts", json!({"id": "Carol", "balance": 250})).await;
+    assert_eq!(status, StatusCode::CREATED, "{}", body);
+    let (status, _) = get(&app, "/t/acme/account/Carol").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = get(&app, "/account/Carol").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = get(&app, "/t/globex/accounts?prefix=C").await;
+    assert_eq!(body, json!([{"id": "Carol", "balance": 250}]));
+    let (_, body) = get(&app, "/t/globex/stats").await;
+    assert_eq!((body["accounts"].as_u64(), body["total_supply"].as_u64()), (Some(3), Some(1750)));
+    let (_, body) = get(&app, "/t/acme/stats").await;
+    assert_eq!((body["accounts"].as_u64(), body["total_supply"].as_u64()), (Some(2), Some(1500)));
+    let (_, body) = get(&app, "/stats").await;
+    assert_eq!((body["accounts"].as_u64(), body["latest_seq"].as_u64(), body["total_supply"].as_u64()), (Some(2), Some(1), Some(1500)));
+
+    let (status, body) = get(&app, "/t/initech/stats").await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+
+    // Each tenant saves to its own file, so a restart brings back the same split
+    assert_eq!(FileStorage::new(&tenant_files[0], 1000).load().unwrap().unwrap().accounts["Alice"].balance, 700);
+    assert!(FileStorage::new(&tenant_files[1], 1000).load().unwrap().unwrap().accounts.contains_key("Carol"));
+    assert_eq!(FileStorage::new(&path, 1000).load().unwrap().unwrap().accounts["Alice"].balance, 999);
+    for file in tenant_files.iter().chain([&path]) {
+        let _ = std::fs::remove_file(file);
+        let _ = std::fs::remove_file(format!("{}.wal", file));
+    }
+}
+
+#[tokio::test]
+async fn identical_transactions_apply_once_inside_the_dedup_window() {
+    let clock = ManualClock::at(1_000_000);
+    let (app, _) = app_with_clock(&[("TXH_DEDUP_WINDOW_MS", "1000")], clock.clone());
+
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    clock.advance(999);
+    let (status, retry) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!(retry["message"], "Duplicate of a transaction applied 999ms ago, not applied again");
+    assert_eq!((balance(&app, "Alice").await, balance(&app, "Bob").await), (900, 600));
+
+    // Outside the window both are judged normally: the retry is found in the history instead, and a
+    // new transaction with the same fields but the next nonce applies
+    clock.advance(1);
+    let (status, retry) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", retry);
+    assert_eq!(retry["message"], "Duplicate of the transaction applied at seq 1, not applied again");
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!((balance(&app, "Alice").await, balance(&app, "Bob").await), (800, 700));
+}
+
+#[tokio::test]
+async fn merging_keeps_freezes_and_the_velocity_window() {
+    let (app, _) = app(&[("TXH_VELOCITY_LIMIT", "300")]);
+    let (status, _) = admin_post(&app, "/account/Alice/freeze", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, body) = admin_post(&app, "/account/Alice/rename?merge=true", json!({"new_id": "Bob"})).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["message"], "Account Alice is frozen and can't be merged");
+    let (status, _) = admin_post(&app, "/account/Bob/rename?merge=true", json!({"new_id": "Alice"})).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!((balance(&app, "Alice").await, balance(&app, "Bob").await), (1000, 500));
+    let (status, _) = admin_post(&app, "/account/Alice/unfreeze", json!({})).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Alice's 250 sent just before the merge still counts against Bob's limit of 300
+    let (status, body) = transfer(&app, "Alice", "Carol", 250, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = admin_post(&app, "/account/Alice/rename?merge=true", json!({"new_id": "Bob"})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["balance"], 1250);
+    let (status, body) = transfer(&app, "Bob", "Carol", 100, 1).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+    let (status, body) = transfer(&app, "Bob", "Carol", 50, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+async fn roll_back_to(app: &axum::Router, to_seq: u64) {
+    let (status, body) = admin_post(app, "/admin/rollback", json!({"to_seq": to_seq, "confirm": true})).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn a_rollback_takes_undone_fees_out_of_the_fee_volume() {
+    let (app, _) = app(&[("TXH_FEE_FLAT", "2")]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 40, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (status, body) = transfer(&app, "Alice", "Bob", 40, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, volume) = get(&app, "/fees/volume?window_secs=60").await;
+    assert_eq!(volume["total"], 4);
+
+    roll_back_to(&app, 1).await;
+    let (_, volume) = get(&app, "/fees/volume?window_secs=60").await;
+    assert_eq!(volume["total"], 2);
+}
+
+#[tokio::test]
+async fn a_rolled_back_transaction_can_be_applied_again_inside_the_dedup_window() {
+    let (app, _) = app(&[("TXH_DEDUP_WINDOW_MS", "60000")]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 40, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    roll_back_to(&app, 0).await;
+
+    let (status, body) = transfer(&app, "Alice", "Bob", 40, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["seq"], 1, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 960);
+}
+
+#[tokio::test]
+async fn a_rolled_back_multi_transfer_leaves_the_velocity_window() {
+    let (app, _) = app(&[("TXH_VELOCITY_LIMIT", "300")]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 40, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let multi = json!({"sender": "Alice", "outputs": [["Bob", 100], ["Carol", 150]], "nonce": 1});
+    let (status, body) = post(&app, "/submit_multi", multi).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    roll_back_to(&app, 1).await;
+
+    // Only the 40 still applied counts against the limit
+    let (status, body) = transfer(&app, "Alice", "Bob", 260, 1).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn queued_transactions_are_kept_when_their_drain_cant_be_saved() {
+    let path = state_file("drain-save");
+    let storage = FlakyStorage::new(&path);
+    let app = build_router(state(&[("TXH_NONCE_QUEUE_GAP", "5")]).with_storage(storage.clone()));
+    let (status, _) = transfer(&app, "Alice", "Bob", 10, 1).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    // Nonce 0 saves, the drained nonce 1 doesn't and goes back in the queue
+    storage.fail_after.store(1, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 5, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let (_, queued) = get(&app, "/mempool/Alice").await;
+    assert_eq!((queued["next_nonce"].as_u64(), queued["transactions"][0]["nonce"].as_u64()), (Some(1), Some(1)), "{}", queued);
+    assert_eq!(balance(&app, "Alice").await, 995);
+
+    // Once saves work again, the queued nonce 1 goes first, ahead of a different nonce 1 submitted now
+    storage.fail_after.store(usize::MAX, Ordering::SeqCst);
+    storage.failing.store(false, Ordering::SeqCst);
+    let (status, body) = transfer(&app, "Alice", "Bob", 7, 1).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 985);
+    let (_, queued) = get(&app, "/mempool/Alice").await;
+    assert_eq!(queued["transactions"], json!([]));
+    let saved = FileStorage::new(&path, 1000).load().unwrap().unwrap();
+    assert_eq!(saved.accounts["Alice"].nonce, 2);
+}
+
+#[tokio::test]
+async fn the_projected_balance_counts_queued_transactions_the_actual_one_doesnt() {
+    let (app, _) = app(&[("TXH_NONCE_QUEUE_GAP", "5"), ("TXH_FEE_FLAT", "1"), ("TXH_REQUIRE_KNOWN_RECEIVER", "true")]);
+    for (receiver, amount, nonce) in [("Bob", 100, 2), ("Bob", 50, 1), ("Dave", 10, 3)] {
+        let (status, body) = transfer(&app, "Alice", receiver, amount, nonce).await;
+        assert_eq!(status, StatusCode::ACCEPTED, "{}", body);
+    }
+
+    let (status, projected) = get(&app, "/account/Alice/projected").await;
+    assert_eq!(status, StatusCode::OK, "{}", projected);
+    // 100 and 50 plus a fee each; the transfer to the unknown Dave would be dropped
+    assert_eq!(projected, json!({
+        "account": "Alice", "balance": 1000, "projected_balance": 848, "pending": 2, "failing_nonces": [3],
+    }));
+    assert_eq!(balance(&app, "Alice").await, 1000);
+
+    let (status, body) = transfer(&app, "Alice", "Bob", 1, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(balance(&app, "Alice").await, 846);
+    let (_, projected) = get(&app, "/account/Alice/projected").await;
+    assert_eq!((projected["projected_balance"].as_u64(), projected["pending"].as_u64()), (Some(846), Some(0)));
+}
+
+#[tokio::test]
+async fn event_subscribers_beyond_the_cap_get_503_until_one_disconnects() {
+    let (app, _) = app(&[("TXH_MAX_EVENT_SUBSCRIBERS", "2")]);
+    let subscribe = || app.clone().oneshot(Request::get("/events").body(Body::empty()).unwrap());
+    let first = subscribe().await.unwrap();
+    let second = subscribe().await.unwrap();
+    assert_eq!((first.status(), second.status()), (StatusCode::OK, StatusCode::OK));
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert!(metrics.as_str().unwrap().contains("txh_event_subscribers 2"), "{}", metrics);
+
+    let third = subscribe().await.unwrap();
+    assert_eq!(third.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    drop(first);
+    let fourth = subscribe().await.unwrap();
+    assert_eq!(fourth.status(), StatusCode::OK);
+    drop((second, fourth));
+    let (_, metrics) = get(&app, "/metrics").await;
+    assert!(metrics.as_str().unwrap().contains("txh_event_subscribers 0"), "{}", metrics);
+}
+
+#[tokio::test]
+async fn a_fee_split_credits_the_collector_its_share_and_burns_the_rest() {
+    let (app, state) = app(&[("TXH_FEE_FLAT", "10"), ("TXH_FEE_BURN_BPS", "5000")]);
+    let (status, body) = transfer(&app, "Alice", "Bob", 100, 0).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["fee"], 10, "{}", body);
+
+    assert_eq!((balance(&app, "Alice").await, balance(&app, "fees").await), (890, 5));
+    let (_, stats) = get(&app, "/stats").await;
+    assert_eq!(stats["total_supply"], 1495);
+    assert_eq!(state.lock_ledger().audit().discrepancies, Vec::<String>::new());
+
+    // A rollback gives the burned half back to the supply along with the rest of the fee
+    roll_back_to(&app, 0).await;
+    let (_, stats) = get(&app, "/stats").await;
+    assert_eq!(stats["total_supply"], 1500);
+    assert_eq!(state.lock_ledger().audit().discrepancies, Vec::<String>::new());
+}
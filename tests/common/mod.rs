@@ -0,0 +1,192 @@
+// Helpers shared by the HTTP tests: an app over a fresh ledger, and one-shot requests against it
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use axum::body::Body;
+use axum::http::{Request, StatusCode};
+use axum::Router;
+use ed25519_dalek::SigningKey;
+use serde_json::Value;
+use tower::ServiceExt;
+
+use transaction_handler_microservice::config::{Config, ConfigVars};
+use transaction_handler_microservice::ledger::{GenesisAccount, Ledger};
+use transaction_handler_microservice::server::{build_router, AppState, Clock};
+use transaction_handler_microservice::storage::{FileStorage, Storage};
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+// Config from TXH_* pairs, everything else at its default. The admin token is always set.
+pub fn config(vars: &[(&str, &str)]) -> Config {
+    let admin = ("TXH_ADMIN_TOKEN", ADMIN_TOKEN);
+    Config::from_vars(&ConfigVars::new(std::iter::once(admin).chain(vars.iter().copied()))).unwrap()
+}
+
+// Alice 1000 and Bob 500, like a fresh server without a genesis file
+pub fn ledger() -> Ledger {
+    Ledger::from_genesis(&[
+        GenesisAccount { id: "Alice".into(), balance: 1000, nonce: 0 },
+        GenesisAccount { id: "Bob".into(), balance: 500, nonce: 0 },
+    ])
+}
+
+pub fn state(vars: &[(&str, &str)]) -> AppState {
+    AppState::new(config(vars), ledger(), SigningKey::from_bytes(&[1; 32]))
+}
+
+pub fn app(vars: &[(&str, &str)]) -> (Router, AppState) {
+    let state = state(vars);
+    (build_router(state.clone()), state)
+}
+
+// A clock that only moves when the test advances it
+pub struct ManualClock(AtomicU64);
+
+impl ManualClock {
+    pub fn at(now_ms: u64) -> Arc<ManualClock> {
+        Arc::new(ManualClock(AtomicU64::new(now_ms)))
+    }
+
+    pub fn advance(&self, ms: u64) {
+        self.0.fetch_add(ms, Ordering::SeqCst);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now_ms(&self) -> u64 {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+// Like app, on a clock the test controls
+pub fn app_with_clock(vars: &[(&str, &str)], clock: Arc<ManualClock>) -> (Router, AppState) {
+    let state = state(vars).with_clock(clock);
+    (build_router(state.clone()), state)
+}
+
+// A state file path under the temp dir, with anything an earlier run left there removed
+pub fn state_file(name: &str) -> String {
+    let path = std::env::temp_dir().join(format!("txh-api-{}-{}.json", std::process::id(), name));
+    let path = path.to_str().unwrap().to_string();
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(format!("{}.wal", path));
+    path
+}
+
+// File storage whose commits fail while `failing` is set, as on a full disk. `fail_after` sets it by
+// itself once that many more commits went through.
+pub struct FlakyStorage {
+    pub inner: FileStorage,
+    pub failing: AtomicBool,
+    pub fail_after: AtomicUsize,
+    pub transient_failures: AtomicUsize, // commits that time out before the next one gets through
+}
+
+impl FlakyStorage {
+    pub fn new(path: &str) -> Arc<FlakyStorage> {
+        Arc::new(FlakyStorage {
+            inner: FileStorage::new(path, 1000),
+            failing: AtomicBool::new(false),
+            fail_after: AtomicUsize::new(usize::MAX),
+            transient_failures: AtomicUsize::new(0),
+        })
+    }
+}
+
+impl Storage for FlakyStorage {
+    fn commit(&self, ledger: &mut Ledger) -> std::io::Result<()> {
+        match self.fail_after.load(Ordering::SeqCst) {
+            0 => self.failing.store(true, Ordering::SeqCst),
+            usize::MAX => {}
+            left => self.fail_after.store(left - 1, Ordering::SeqCst),
+        }
+        if self.failing.load(Ordering::SeqCst) {
+            return Err(std::io::Error::other("disk full"));
+        }
+        if self.transient_failures.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| left.checked_sub(1)).is_ok() {
+            return Err(std::io::Error::from(std::io::ErrorKind::TimedOut));
+        }
+        self.inner.commit(ledger)
+    }
+
+    fn snapshot(&self, ledger: &mut Ledger) -> std::io::Result<()> {
+        self.inner.snapshot(ledger)
+    }
+
+    fn load(&self) -> Result<Option<Ledger>, String> {
+        self.inner.load()
+    }
+}
+
+pub async fn send(app: &Router, request: Request<Body>) -> (StatusCode, Value) {
+    let response = app.clone().oneshot(request).await.unwrap();
+    let status = response.status();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body = serde_json::from_slice(&bytes)
+        .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&bytes).into_owned()));
+    (status, body)
+}
+
+pub async fn post(app: &Router, path: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::post(path)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+pub async fn admin_post(app: &Router, path: &str, body: Value) -> (StatusCode, Value) {
+    let request = Request::post(path)
+        .header("content-type", "application/json")
+        .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+        .body(Body::from(body.to_string()))
+        .unwrap();
+    send(app, request).await
+}
+
+pub async fn get(app: &Router, path: &str) -> (StatusCode, Value) {
+    send(app, Request::get(path).body(Body::empty()).unwrap()).await
+}
+
+pub async fn admin_get(app: &Router, path: &str) -> (StatusCode, Value) {
+    let request = Request::get(path)
+        .header("authorization", format!("Bearer {}", ADMIN_TOKEN))
+        .body(Body::empty())
+        .unwrap();
+    send(app, request).await
+}
+
+pub async fn balance(app: &Router, id: &str) -> u64 {
+    let (status, body) = get(app, &format!("/account/{}", id)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["balance"].as_u64().unwrap()
+}
+
+// A request still waiting for its body, and what sends the body
+pub type Held = (Pin<Box<dyn Future<Output = (StatusCode, Value)> + Send>>, tokio::sync::oneshot::Sender<()>);
+
+// Starts posting body to path but holds the body back until the returned sender fires, so the request
+// sits inside the handler, in its TXH_MAX_INFLIGHT slot, until then
+pub async fn hold_request(app: &Router, path: &str, body: Value) -> Held {
+    let (release, released) = tokio::sync::oneshot::channel::<()>();
+    let stream = futures_util::stream::once(async move {
+        released.await.map(|()| axum::body::Bytes::from(body.to_string()))
+    });
+    let request = Request::post(path)
+        .header("content-type", "application/json")
+        .body(Body::from_stream(stream))
+        .unwrap();
+    let app = app.clone();
+    let mut pending: Pin<Box<dyn Future<Output = (StatusCode, Value)> + Send>> = Box::pin(async move { send(&app, request).await });
+    assert!(futures_util::poll!(&mut pending).is_pending());
+    (pending, release)
+}
+
+pub async fn transfer(app: &Router, sender: &str, receiver: &str, amount: u64, nonce: u32) -> (StatusCode, Value) {
+    let tx = serde_json::json!({"sender": sender, "receiver": receiver, "amount": amount, "nonce": nonce});
+    post(app, "/submit_transaction", tx).await
+}